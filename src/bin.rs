@@ -7,6 +7,7 @@ use std::io::Write;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 enum OutputMode {
     Quiet,
@@ -19,8 +20,16 @@ fn main() {
     let mut opts = Options::new();
     opts.optflag("d", "dry-run", "Do not change anything on disk. Only print dupes found");
     opts.optflag("s", "small", "Also dedupe small files (smaller than a disk block)");
+    opts.optopt("", "min-size", "Skip files smaller than this many bytes", "<bytes>");
     opts.optflag("q", "quiet", "Hide regular progress output");
+    opts.optflag("", "keep-permissions", "Don't hardlink duplicates that have different permissions, only report them");
     opts.optmulti("e", "exclude", "Don't scan directories or files with that filename (wildcards are not supported)", "<exact filename>");
+    opts.optopt("", "hash", "Hash function used to compare file content: blake3 (default), xxh3 (faster, only for trusted files) or sha256", "<name>");
+    opts.optflag("", "keyed-hash", "Hash with a random key, so that files crafted to collide can't be mistaken for duplicates. Can't be used with --cache");
+    opts.optopt("", "cache", "Remember file hashes in this file to speed up later runs", "<file>");
+    opts.optopt("", "journal", "Record hardlinked files in this file, so that it can be undone with --undo", "<file>");
+    opts.optopt("", "undo", "Turn the files recorded in this journal back into separate copies, and exit", "<journal>");
+    opts.optflag("", "apparent-size", "Count the space saved by file lengths, rather than by space on disk");
     opts.optflag("", "json", "Display results as JSON");
     opts.optflag("h", "help", "This help text");
 
@@ -37,6 +46,21 @@ fn main() {
         OutputMode::Text
     };
 
+    if let Some(journal) = matches.opt_str("undo") {
+        match undo(&journal) {
+            Ok(summary) => {
+                if !matches!(output_mode, OutputMode::Quiet) {
+                    println!("Copied {} files. Left {} files that were no longer linked.", summary.copied, summary.skipped.len());
+                }
+            },
+            Err(err) => {
+                writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
     if matches.opt_present("h") || matches.free.is_empty() {
         println!(
             "Hardlink files with duplicate content (v{}).\n{}\n\n{}",
@@ -56,6 +80,51 @@ fn main() {
     s.settings.break_on = Some(&CTRL_C_BREAKS);
     s.settings.run_mode = if matches.opt_present("dry-run") { RunMode::DryRun } else { RunMode::Hardlink };
     s.settings.ignore_small = !matches.opt_present("small");
+    if let Some(min_size) = matches.opt_str("min-size") {
+        s.settings.min_size = match min_size.parse() {
+            Ok(size) => size,
+            Err(_) => {
+                writeln!(&mut std::io::stderr(), "Invalid --min-size: {}", min_size).unwrap();
+                std::process::exit(2)
+            },
+        };
+    }
+    s.settings.ignore_permissions = !matches.opt_present("keep-permissions");
+    // There's only one scan, so the hashes aren't needed after it
+    s.settings.free_hashes = true;
+    let mut hashing = HashSettings::default();
+    if let Some(name) = matches.opt_str("hash") {
+        hashing.algorithm = match name.as_str() {
+            "blake3" => HashAlgorithm::Blake3,
+            "xxh3" => HashAlgorithm::Xxh3,
+            "sha256" => HashAlgorithm::Sha256,
+            _ => {
+                writeln!(&mut std::io::stderr(), "Unknown hash function: {}", name).unwrap();
+                std::process::exit(2)
+            },
+        };
+    }
+    if matches.opt_present("keyed-hash") {
+        // Hashes with a new key every run don't match the cached ones, which would all be thrown away
+        if matches.opt_present("cache") {
+            writeln!(&mut std::io::stderr(), "--keyed-hash can't be used with --cache").unwrap();
+            std::process::exit(2)
+        }
+        hashing.key = Some(HashSettings::random_key());
+    }
+    if matches!(output_mode, OutputMode::Json) {
+        hashing.error_log = Some(Arc::default());
+    }
+    s.settings.hashing = Arc::new(hashing);
+    if let Some(cache) = matches.opt_str("cache") {
+        s.set_hash_cache(cache);
+    }
+    if let Some(journal) = matches.opt_str("journal") {
+        if let Err(err) = s.set_journal(&journal) {
+            writeln!(&mut std::io::stderr(), "Journal {}: {}", journal, err).unwrap();
+            std::process::exit(2)
+        }
+    }
     match output_mode {
         OutputMode::Quiet => {
             // Noop-output is already set by default.
@@ -83,7 +152,12 @@ fn main() {
 
     s.exclude(matches.opt_strs("exclude"));
 
-    match inner_main(s, matches.free) {
+    let report_savings = match output_mode {
+        OutputMode::Text => Some(if matches.opt_present("apparent-size") { SizeMode::Logical } else { SizeMode::Physical }),
+        _ => None,
+    };
+
+    match inner_main(s, matches.free, report_savings) {
         Ok(()) => {},
         Err(err) => {
             writeln!(&mut std::io::stderr(), "Error: {}", err).unwrap();
@@ -92,10 +166,21 @@ fn main() {
     };
 }
 
-fn inner_main(mut s: Scanner, args: Vec<String>) -> io::Result<()> {
+fn inner_main(mut s: Scanner, args: Vec<String>, report_savings: Option<SizeMode>) -> io::Result<()> {
     for arg in args {
         let path: PathBuf = arg.into();
         s.enqueue(path)?;
     }
-    s.flush()
+    s.flush()?;
+    if let Some(size_mode) = report_savings {
+        if s.settings.run_mode == RunMode::DryRun {
+            let (_, total) = s.bytes_saved(size_mode)?;
+            println!("Hardlinking would save {}", human_size(total as usize));
+        } else {
+            let stats = s.stats();
+            let total = if size_mode == SizeMode::Logical { stats.bytes_freed } else { stats.allocated_bytes_freed };
+            println!("Hardlinking saved {}", human_size(total));
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,307 @@
+use crate::hasher::{HashAlgorithm, HashSettings, RangeDigest};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 8] = b"dkcache1";
+/// Anything larger is assumed to be garbage
+const MAX_PATH_LEN: usize = 1 << 16;
+const MAX_RANGES: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct FileVersion {
+    mtime: (u64, u32),
+    size: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    version: FileVersion,
+    ranges: Vec<RangeDigest>,
+    /// Number of ranges that are already in the cache file
+    saved: usize,
+}
+
+/// Hashes of files remembered between runs, so that unchanged files don't have to be read again.
+///
+/// The cache is an append-only log, in which later entries override earlier ones.
+/// Entries are valid only for the same path, modification time and size.
+/// A damaged or incompatible cache file is ignored.
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    /// Ranges depend on all of the hash settings
+    settings_key: Vec<u8>,
+    entries: HashMap<Box<Path>, CacheEntry>,
+    /// Cache file is unusable and has to be rewritten from scratch
+    reset: bool,
+}
+
+impl HashCache {
+    /// Loads cached hashes. Never fails: if the cache can't be read, it starts empty.
+    pub fn open(path: impl Into<PathBuf>, settings: &HashSettings) -> Self {
+        let mut cache = HashCache {
+            path: path.into(),
+            settings_key: settings_key(settings),
+            entries: HashMap::new(),
+            reset: true,
+        };
+        match fs::File::open(&cache.path) {
+            Ok(file) => {
+                // Whatever was read before the error is still usable,
+                // but appending after garbage would make the new entries unreadable.
+                if let Err(err) = cache.load(BufReader::new(file)) {
+                    if err.kind() != io::ErrorKind::UnexpectedEof {
+                        eprintln!("Hash cache {}: {}", cache.path.display(), err);
+                    }
+                    cache.reset = true;
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => eprintln!("Hash cache {}: {}", cache.path.display(), err),
+        }
+        cache
+    }
+
+    fn load(&mut self, mut file: impl Read) -> io::Result<()> {
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        let key = read_bytes(&mut file, MAX_PATH_LEN)?;
+        if &header != MAGIC || key != self.settings_key {
+            return Err(corrupt());
+        }
+        self.reset = false;
+
+        // A partially written last record is expected after a crash, and it's simply dropped
+        loop {
+            let mut separator = [0; 1];
+            match file.read_exact(&mut separator) {
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                res => res?,
+            }
+            if separator[0] != b'\n' {
+                return Err(corrupt());
+            }
+            let path = read_bytes(&mut file, MAX_PATH_LEN)?;
+            let path = String::from_utf8(path).map_err(|_| corrupt())?;
+            let secs = read_u64(&mut file)?;
+            let nanos = read_u64(&mut file)? as u32;
+            let size = read_u64(&mut file)?;
+            let count = read_u64(&mut file)? as usize;
+            if count > MAX_RANGES {
+                return Err(corrupt());
+            }
+            let mut ranges = Vec::with_capacity(count);
+            for _ in 0..count {
+                let range_size = read_u64(&mut file)?;
+                let mut hash = [0; 32];
+                file.read_exact(&mut hash)?;
+                ranges.push((range_size, hash));
+            }
+            let saved = ranges.len();
+            self.entries.insert(PathBuf::from(path).into_boxed_path(), CacheEntry {
+                version: FileVersion { mtime: (secs, nanos), size },
+                ranges,
+                saved,
+            });
+        }
+    }
+
+    /// Cached hashes of the file, if it hasn't changed since it was cached.
+    /// The file is remembered, so that its hashes can be saved later with `update`.
+    pub fn lookup(&mut self, path: &Path, metadata: &fs::Metadata) -> Option<&[RangeDigest]> {
+        let version = file_version(metadata)?;
+        // Non-UTF-8 paths are not cached
+        path.to_str()?;
+        let entry = self.entries.entry(path.into()).or_insert_with(|| CacheEntry {
+            version,
+            ranges: Vec::new(),
+            saved: 0,
+        });
+        if entry.version != version {
+            *entry = CacheEntry {
+                version,
+                ranges: Vec::new(),
+                saved: 0,
+            };
+        }
+        Some(&entry.ranges)
+    }
+
+    /// Remember hashes of a file previously passed to `lookup`
+    pub fn update(&mut self, path: &Path, ranges: impl Iterator<Item = RangeDigest>) {
+        if let Some(entry) = self.entries.get_mut(path) {
+            let ranges: Vec<_> = ranges.collect();
+            if ranges.len() > entry.ranges.len() {
+                entry.ranges = ranges;
+            }
+        }
+    }
+
+    /// Appends new and changed entries to the cache file
+    pub fn save(&mut self) -> io::Result<()> {
+        let file = if self.reset {
+            fs::File::create(&self.path)?
+        } else {
+            fs::OpenOptions::new().append(true).open(&self.path)?
+        };
+        let mut file = BufWriter::new(file);
+        if self.reset {
+            file.write_all(MAGIC)?;
+            write_bytes(&mut file, &self.settings_key)?;
+            for entry in self.entries.values_mut() {
+                entry.saved = 0;
+            }
+            self.reset = false;
+        }
+        for (path, entry) in &mut self.entries {
+            if entry.ranges.len() <= entry.saved {
+                continue;
+            }
+            let path = match path.to_str() {
+                Some(p) => p,
+                None => continue,
+            };
+            file.write_all(b"\n")?;
+            write_bytes(&mut file, path.as_bytes())?;
+            file.write_all(&entry.version.mtime.0.to_le_bytes())?;
+            file.write_all(&u64::from(entry.version.mtime.1).to_le_bytes())?;
+            file.write_all(&entry.version.size.to_le_bytes())?;
+            file.write_all(&(entry.ranges.len() as u64).to_le_bytes())?;
+            for (size, hash) in &entry.ranges {
+                file.write_all(&size.to_le_bytes())?;
+                file.write_all(hash)?;
+            }
+            entry.saved = entry.ranges.len();
+        }
+        file.flush()
+    }
+}
+
+fn file_version(metadata: &fs::Metadata) -> Option<FileVersion> {
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(FileVersion {
+        mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+        size: metadata.len(),
+    })
+}
+
+fn settings_key(settings: &HashSettings) -> Vec<u8> {
+    let mut key = Vec::with_capacity(26);
+    key.push(match settings.algorithm {
+        HashAlgorithm::Blake3 => 1,
+        HashAlgorithm::Xxh3 => 2,
+        HashAlgorithm::Sha256 => 3,
+    });
+    key.push(settings.digest_len() as u8);
+    key.extend_from_slice(&settings.initial_buffer_size.max(1).to_le_bytes());
+    key.extend_from_slice(&settings.growth_factor.max(2).to_le_bytes());
+    key.extend_from_slice(&settings.max_buffer_size.to_le_bytes());
+    if let Some(hash_key) = &settings.key {
+        key.extend_from_slice(hash_key);
+    }
+    key
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt hash cache")
+}
+
+fn read_u64(file: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_bytes(file: &mut impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let len = read_u64(file)? as usize;
+    if len > max_len {
+        return Err(corrupt());
+    }
+    let mut bytes = vec![0; len];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_bytes(file: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    file.write_all(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let tmp = tempdir::TempDir::new("cachetest").expect("tmp");
+        let cache_path = tmp.path().join("cache");
+        let file_path = tmp.path().join("a");
+        fs::write(&file_path, "hello").expect("write");
+        let metadata = fs::metadata(&file_path).expect("stat");
+        let settings = HashSettings::default();
+
+        let mut cache = HashCache::open(&cache_path, &settings);
+        assert_eq!(Some(&[][..]), cache.lookup(&file_path, &metadata));
+        cache.update(&file_path, vec![(5, [1; 32])].into_iter());
+        cache.save().expect("save");
+
+        let mut cache = HashCache::open(&cache_path, &settings);
+        assert_eq!(Some(&[(5, [1; 32])][..]), cache.lookup(&file_path, &metadata));
+
+        // Different settings produce different ranges
+        let other = HashSettings {
+            initial_buffer_size: 1,
+            ..HashSettings::default()
+        };
+        let mut cache = HashCache::open(&cache_path, &other);
+        assert_eq!(Some(&[][..]), cache.lookup(&file_path, &metadata));
+    }
+
+    #[test]
+    fn changed_file() {
+        let tmp = tempdir::TempDir::new("cachetest").expect("tmp");
+        let cache_path = tmp.path().join("cache");
+        let file_path = tmp.path().join("a");
+        fs::write(&file_path, "hello").expect("write");
+        let settings = HashSettings::default();
+
+        let mut cache = HashCache::open(&cache_path, &settings);
+        cache.lookup(&file_path, &fs::metadata(&file_path).expect("stat"));
+        cache.update(&file_path, vec![(5, [1; 32])].into_iter());
+        cache.save().expect("save");
+
+        fs::write(&file_path, "hello world").expect("write");
+        let mut cache = HashCache::open(&cache_path, &settings);
+        assert_eq!(Some(&[][..]), cache.lookup(&file_path, &fs::metadata(&file_path).expect("stat")));
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let tmp = tempdir::TempDir::new("cachetest").expect("tmp");
+        let cache_path = tmp.path().join("cache");
+        let file_path = tmp.path().join("a");
+        fs::write(&file_path, "hello").expect("write");
+        let metadata = fs::metadata(&file_path).expect("stat");
+        let settings = HashSettings::default();
+
+        fs::write(&cache_path, "garbage").expect("write");
+        let mut cache = HashCache::open(&cache_path, &settings);
+        assert_eq!(Some(&[][..]), cache.lookup(&file_path, &metadata));
+        cache.update(&file_path, vec![(5, [1; 32])].into_iter());
+        cache.save().expect("save");
+
+        // Partially written record is dropped
+        let mut data = fs::read(&cache_path).expect("read");
+        data.extend_from_slice(b"\n\x05\x00");
+        fs::write(&cache_path, data).expect("write");
+        let mut cache = HashCache::open(&cache_path, &settings);
+        assert_eq!(Some(&[(5, [1; 32])][..]), cache.lookup(&file_path, &metadata));
+        cache.save().expect("save");
+        let mut cache = HashCache::open(&cache_path, &settings);
+        assert_eq!(Some(&[(5, [1; 32])][..]), cache.lookup(&file_path, &metadata));
+    }
+}
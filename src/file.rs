@@ -1,11 +1,20 @@
-use crate::hasher::Hasher;
-use crate::metadata::Metadata;
+use crate::hasher::{fingerprint_file, group_equal, to_hex, HashSettings, Hasher, RangeDigest, StreamDigest};
+use crate::lazyfile::{symlink_target, FileError};
+use crate::metadata::{FileIdentity, FileIndex, Metadata};
 use smallvec::SmallVec;
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::cmp::max;
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
 use std::io;
 use std::path::Path;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Clone)]
 pub struct FileSet {
@@ -32,29 +41,291 @@ impl FileSet {
     pub fn links(&self) -> u64 {
         max(self.max_hardlinks, self.paths.len() as u64)
     }
+
+    /// The exact path is already in the set. Differently cased paths may be different files, even on Windows and macOS,
+    /// since directories there can be case-sensitive.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| **p == *path)
+    }
+
+    /// Combines sets of the same file found separately, e.g. by scans of different roots.
+    /// Paths that are already in this set aren't added again.
+    pub fn merge(mut self, other: FileSet) -> FileSet {
+        self.max_hardlinks = max(self.max_hardlinks, other.max_hardlinks);
+        for path in other.paths {
+            if !self.contains(&path) {
+                self.push(path);
+            }
+        }
+        self
+    }
+}
+
+/// Windows and macOS filesystems are usually case-insensitive, so `C:\Data` and `c:\data` are likely the same path.
+/// Some directories are case-sensitive though, so it's only good enough for matching path prefixes, never for
+/// deciding that two paths are the same file.
+fn same_path(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    if !cfg!(any(windows, target_os = "macos")) {
+        return false;
+    }
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase)),
+        _ => false,
+    }
+}
+
+/// `Path::starts_with`, but case-insensitive where `same_path` is
+pub(crate) fn path_starts_with(path: &Path, prefix: &Path) -> bool {
+    if path.starts_with(prefix) {
+        return true;
+    }
+    if !cfg!(any(windows, target_os = "macos")) {
+        return false;
+    }
+    let mut components = path.components();
+    prefix.components().all(|prefix| components.next().map_or(false, |c| same_path(c.as_ref(), prefix.as_ref())))
+}
+
+/// Hashes computed for a `FileContent`, which can be saved and loaded to resume hashing in another run
+#[cfg(feature = "json")]
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct HashState {
+    metadata: Metadata,
+    /// Missing in states saved by older versions, which are then never restored
+    #[serde(default)]
+    version: Option<FileIdentity>,
+    hashes: Hasher,
+}
+
+/// What else has to be the same for files to be equal, other than their size and content.
+/// Files compared with each other should use the same policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GroupPolicy {
+    /// Files on different devices can be equal. They can't be hardlinked, so it's only useful for actions like deleting
+    /// or copying duplicates. Otherwise only files on the same device are compared.
+    pub across_devices: bool,
+    /// Files are equal only if their NTFS alternate data streams (e.g. `Zone.Identifier`) are equal too,
+    /// so that hardlinking doesn't lose any streams. Other systems don't have them.
+    pub compare_streams: bool,
+    /// Files owned by different users or groups are never equal, so that hardlinks don't mix up their ownership.
+    /// On Windows the owner and group SIDs are read for it, which takes a call per file.
+    pub compare_owners: bool,
+    /// Files with different read-only, hidden or system attributes are never equal,
+    /// since hardlinks share the attributes and linking would change them
+    pub compare_attributes: bool,
+}
+
+/// Whatever has to be the same for files to be equal, other than their content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct GroupKey {
+    size: u64,
+    dev: u64,
+    owner: (u32, u32),
+    /// Read-only, hidden, system
+    attributes: (bool, bool, bool),
 }
 
 #[derive(Debug)]
 /// File content is efficiently compared using this struct's `PartialOrd` implementation
+///
+/// Files are open only while they're being compared, so a group of duplicates doesn't hold any file handles
+/// once it's been found. Only the hashes are kept, which can be freed with `clear_hashes`.
 pub struct FileContent {
-    path: Box<Path>,
+    /// Unique, since files that can't be read can't be ordered by their content
+    id: u64,
+    pub(crate) path: Box<Path>,
     metadata: Metadata,
-    /// Hashes of content, calculated incrementally
-    hashes: RefCell<Hasher>,
+    /// Hashes of content, calculated incrementally. Shared by all `FileContent`s of the same file created via `HashMemo`.
+    hashes: Rc<RefCell<Hasher>>,
+    settings: Arc<HashSettings>,
+    grouping: GroupPolicy,
+    /// See `take_failed_comparison`
+    failed_comparison: Cell<bool>,
+    /// See `take_differing_streams`
+    differing_streams: RefCell<Vec<Box<Path>>>,
 }
 
 impl FileContent {
     pub fn from_path(path: Box<Path>) -> Result<Self, io::Error> {
-        let m = Metadata::from_path(&path)?;
+        let m = Metadata::from_path(&path).map_err(|err| FileError::metadata(&path, &err).to_io_error())?;
         Ok(Self::new(path, m))
     }
 
     pub fn new(path: Box<Path>, metadata: Metadata) -> Self {
+        Self::with_settings(path, metadata, Arc::default())
+    }
+
+    /// Files compared with each other should share the same settings.
+    /// With `compare_symlink_targets`, the size of a symlink is the length of its target.
+    pub fn with_settings(path: Box<Path>, mut metadata: Metadata, settings: Arc<HashSettings>) -> Self {
+        if settings.compare_symlink_targets && metadata.reparse_point {
+            // If it can't be read, comparisons will fail the same way
+            if let Ok(Some(target)) = symlink_target(&path) {
+                metadata.size = target.len() as u64;
+            }
+        }
         FileContent {
+            id: NEXT_ID.fetch_add(1, AtomicOrdering::Relaxed),
             path,
             metadata,
-            hashes: RefCell::new(Hasher::new()),
+            hashes: Rc::new(RefCell::new(Hasher::new())),
+            settings,
+            grouping: GroupPolicy::default(),
+            failed_comparison: Cell::new(false),
+            differing_streams: RefCell::default(),
+        }
+    }
+
+    /// Like `with_settings`, but if the same file (even via another path) has been seen by the `memo`,
+    /// hashes already computed for it are shared instead of reading the file again.
+    pub fn with_memo(path: Box<Path>, metadata: &fs::Metadata, settings: Arc<HashSettings>, memo: &mut HashMemo) -> Self {
+        // Without the index it's just not shared
+        let index = FileIndex::new(&path, metadata).unwrap_or_else(|_| FileIndex::from_metadata(metadata));
+        FileContent {
+            hashes: memo.hashes(&index, metadata),
+            ..Self::with_settings(path, Metadata::new(metadata).with_index(index), settings)
+        }
+    }
+
+    /// Compare also the metadata the policy asks for, not only size and content
+    pub fn with_grouping(mut self, grouping: GroupPolicy) -> Self {
+        #[cfg(windows)]
+        if grouping.compare_owners {
+            (self.metadata.uid, self.metadata.gid) = crate::metadata::owner_sids(&self.path);
+        }
+        self.grouping = grouping;
+        self
+    }
+}
+
+/// Hashes of files shared between `FileContent`s created with `FileContent::with_memo`,
+/// so that a file reachable through multiple paths is read only once.
+/// Files that have been modified since are treated as different files.
+#[derive(Debug, Default)]
+pub struct HashMemo {
+    /// Hashes are kept only as long as some `FileContent` uses them
+    hashes: HashMap<FileIdentity, Weak<RefCell<Hasher>>>,
+}
+
+impl HashMemo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn hashes(&mut self, index: &FileIndex, m: &fs::Metadata) -> Rc<RefCell<Hasher>> {
+        let hashes = Rc::new(RefCell::new(Hasher::new()));
+        // Without an ID, different files could look like the same one
+        let id = match FileIdentity::new(index, m) {
+            Some(id) => id,
+            None => return hashes,
+        };
+        if let Some(hashes) = self.hashes.get(&id).and_then(Weak::upgrade) {
+            return hashes;
+        }
+        if self.hashes.len() >= 1024 && self.hashes.len().is_power_of_two() {
+            self.hashes.retain(|_, hashes| hashes.strong_count() > 0);
+        }
+        self.hashes.insert(id, Rc::downgrade(&hashes));
+        hashes
+    }
+}
+
+impl FileContent {
+    /// Partitions the files into groups of equal content. Returns groups of indices into `files`, including single-file groups.
+    /// It's equivalent to comparing all pairs, but every part of every file is read at most once.
+    /// Groups are sorted, and ordered by their first index, like those of `group_equal`.
+    pub fn group_by_content(files: &[FileContent]) -> Vec<Vec<usize>> {
+        let mut by_metadata = BTreeMap::<GroupKey, Vec<usize>>::new();
+        for (i, file) in files.iter().enumerate() {
+            by_metadata.entry(file.group_key()).or_default().push(i);
+        }
+
+        let mut groups = Vec::new();
+        for (key, indices) in by_metadata {
+            // Files sharing hashes are the same file, so only one of them is compared
+            let mut shared = HashMap::<*const RefCell<Hasher>, usize>::new();
+            let mut same_file = Vec::<Vec<usize>>::new();
+            for &i in &indices {
+                let n = *shared.entry(Rc::as_ptr(&files[i].hashes)).or_insert_with(|| {
+                    same_file.push(Vec::new());
+                    same_file.len() - 1
+                });
+                same_file[n].push(i);
+            }
+            let mut hashes: Vec<_> = same_file.iter().map(|same| files[same[0]].hashes.borrow_mut()).collect();
+            let mut hashers: Vec<_> = hashes.iter_mut().zip(&same_file).map(|(h, same)| (&mut **h, &*files[same[0]].path)).collect();
+            let settings = &files[indices[0]].settings;
+            let equal = group_equal(&mut hashers, key.size, settings);
+            drop(hashers);
+            for group in equal {
+                if !files[indices[0]].grouping.compare_streams || group.len() < 2 {
+                    groups.push(group.into_iter().flat_map(|n| same_file[n].iter().copied()).collect());
+                    continue;
+                }
+                // Equal content is split further by streams. Files with unreadable streams end up alone.
+                let mut by_streams = BTreeMap::<Vec<StreamDigest>, Vec<usize>>::new();
+                for n in group {
+                    match hashes[n].streams(&files[same_file[n][0]].path, settings) {
+                        Ok(streams) => by_streams.entry(streams.to_vec()).or_default().extend(&same_file[n]),
+                        Err(_) => groups.push(same_file[n].clone()),
+                    }
+                }
+                let firsts: Vec<_> = by_streams.values().map(|group| &files[group[0]]).collect();
+                for (i, a) in firsts.iter().enumerate() {
+                    for b in &firsts[i + 1..] {
+                        a.record_differing_streams(b);
+                    }
+                }
+                groups.extend(by_streams.into_values());
+            }
+        }
+        for group in &mut groups {
+            group.sort_unstable();
         }
+        groups.sort_unstable();
+        groups
+    }
+}
+
+/// Buckets of files by size and hash of their first chunk. Only files in the same bucket can be equal,
+/// so files that differ early don't have to be compared with each other at all.
+/// The first chunk is the same as used for comparisons, so it's computed only once per file.
+#[derive(Debug, Default)]
+pub struct FirstChunkIndex {
+    buckets: HashMap<(GroupKey, [u8; 32]), Vec<usize>>,
+    /// Can't be equal to anything
+    unreadable: Vec<usize>,
+}
+
+impl FirstChunkIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index of all the files, by their position in the slice
+    pub fn build(files: &[FileContent]) -> Self {
+        let mut index = Self::new();
+        for (i, file) in files.iter().enumerate() {
+            index.insert(i, file);
+        }
+        index
+    }
+
+    /// Adds the file under an arbitrary `id`
+    pub fn insert(&mut self, id: usize, file: &FileContent) {
+        let hash = file.hashes.borrow_mut().first_chunk_hash(file.metadata.size, &file.path, &file.settings);
+        match hash {
+            Ok(hash) => self.buckets.entry((file.group_key(), hash)).or_default().push(id),
+            Err(_) => self.unreadable.push(id),
+        }
+    }
+
+    /// Groups of ids of files that may be equal. Unreadable files are in buckets of their own.
+    pub fn buckets(&self) -> impl Iterator<Item = &[usize]> {
+        self.buckets.values().map(|b| &b[..]).chain(self.unreadable.chunks(1))
     }
 }
 
@@ -62,42 +333,231 @@ impl Eq for FileContent {}
 
 impl PartialEq for FileContent {
     fn eq(&self, other: &Self) -> bool {
-        self.partial_cmp(other)
-            .map_or(false, |o| o == Ordering::Equal)
+        self.try_cmp(other) == Some(Ordering::Equal)
     }
 }
 
+/// Files that couldn't be read are ordered after all readable files with the same group key, by their paths.
+/// Once hashing of a file has failed, it's no longer compared by content, even if the ranges hashed before
+/// the error would have been enough, so that its order stays the same from then on. It's not where it was before
+/// it failed, though (see `take_failed_comparison`).
 impl Ord for FileContent {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.compare(other).unwrap_or(Ordering::Greater)
+        self.try_cmp(other).unwrap_or_else(|| {
+            // Ord can't fail, so this is the last chance for the error to be seen
+            for content in [self, other] {
+                if let Some(err) = content.hashes.borrow_mut().take_unreported_error() {
+                    eprintln!("Can't compare files: {}", err);
+                }
+            }
+            self.failed_comparison.set(true);
+            other.failed_comparison.set(true);
+            // An unreadable file isn't equal to anything, but it still needs a consistent place in the order
+            let failed = |content: &Self| content.error().is_some();
+            failed(self).cmp(&failed(other)).then_with(|| self.path.cmp(&other.path)).then_with(|| self.id.cmp(&other.id))
+        })
     }
 }
 
-/// That does the bulk of hasing and comparisons
 impl PartialOrd for FileContent {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.compare(other).ok()
+        Some(self.cmp(other))
     }
 }
 
 impl FileContent {
+    /// That does the bulk of hasing and comparisons. It's `None` if either file can't be read.
+    pub fn try_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.group_key().cmp(&other.group_key()) {
+            Ordering::Equal if !std::ptr::eq(self, other) && (self.error().is_some() || other.error().is_some()) => None,
+            _ => self.compare(other).ok(),
+        }
+    }
+
     fn compare(&self, other: &Self) -> io::Result<Ordering> {
+        match self.compare_without_reading(other) {
+            Some(ord) => Ok(ord),
+            None => self.compare_hashes(other, |a, b| a.compare(b, self.metadata.size, &self.path, &other.path, &self.settings)),
+        }
+    }
+
+    /// Compares content of the files, calling `progress(bytes_compared, total)` as the comparison advances
+    /// (see `Hasher::compare_with_progress`). It's called at least once, even if nothing has to be read.
+    pub fn compare_with_progress(&self, other: &Self, progress: &mut dyn FnMut(u64, u64)) -> io::Result<Ordering> {
+        let size = self.metadata.size;
+        match self.compare_without_reading(other) {
+            Some(ord) => {
+                progress(if ord == Ordering::Equal { size } else { 0 }, size);
+                Ok(ord)
+            },
+            None => self.compare_hashes(other, |a, b| a.compare_with_progress(b, size, &self.path, &other.path, &self.settings, progress)),
+        }
+    }
+
+    /// Files that can be told apart, or known to be the same, by their metadata
+    fn compare_without_reading(&self, other: &Self) -> Option<Ordering> {
         // Fast pointer comparison
         if std::ptr::eq(self, other) {
-            return Ok(Ordering::Equal);
+            return Some(Ordering::Equal);
         }
 
         // Different file sizes mean they're obviously different.
         // Also different devices mean they're not the same as far as we're concerned
-        // (since search is intended for hardlinking and hardlinking only works within the same device).
-        let cmp = self.metadata.cmp(&other.metadata);
+        // (since search is intended for hardlinking and hardlinking only works within the same device),
+        // unless the settings allow comparing across devices.
+        let cmp = self.group_key().cmp(&other.group_key());
         if cmp != Ordering::Equal {
-            return Ok(cmp);
+            return Some(cmp);
         }
 
+        // The same file seen via different paths, or hardlinks of it
+        if Rc::ptr_eq(&self.hashes, &other.hashes) || self.metadata.same_file(&other.metadata) {
+            return Some(Ordering::Equal);
+        }
+        None
+    }
+
+    /// Compares the content with `compare`, and then the streams if they're compared too
+    fn compare_hashes(&self, other: &Self, compare: impl FnOnce(&mut Hasher, &mut Hasher) -> io::Result<Ordering>) -> io::Result<Ordering> {
         let mut hashes1 = self.hashes.borrow_mut();
         let mut hashes2 = other.hashes.borrow_mut();
 
-        hashes1.compare(&mut hashes2, self.metadata.size, &self.path, &other.path)
+        let ord = compare(&mut hashes1, &mut hashes2)?;
+        if ord != Ordering::Equal || !self.grouping.compare_streams {
+            return Ok(ord);
+        }
+        let ord = hashes1.streams(&self.path, &self.settings)?.cmp(hashes2.streams(&other.path, &self.settings)?);
+        if ord != Ordering::Equal {
+            self.record_differing_streams(other);
+        }
+        Ok(ord)
+    }
+
+    /// On the newer file, which is the one that's looked up when a `Scanner` adds it
+    fn record_differing_streams(&self, other: &Self) {
+        let (newer, older) = if self.id > other.id { (self, other) } else { (other, self) };
+        let mut paths = newer.differing_streams.borrow_mut();
+        if !paths.contains(&older.path) {
+            paths.push(older.path.clone());
+        }
+    }
+
+    /// Compares content of the file with a stream, e.g. an entry of an archive. See `Hasher::compare_with_reader`.
+    pub fn compare_with_reader(&self, reader: &mut impl io::Read) -> io::Result<Ordering> {
+        self.hashes.borrow_mut().compare_with_reader(self.metadata.size, &self.path, reader, &self.settings)
+    }
+
+    /// Bytes read from the file so far to compare it. See `Hasher::bytes_read`.
+    pub fn bytes_read(&self) -> u64 {
+        self.hashes.borrow().bytes_read()
+    }
+
+    /// Device and size, and the owner and attributes if they're compared. Only files with the same key can be equal.
+    fn group_key(&self) -> GroupKey {
+        let m = &self.metadata;
+        let grouping = &self.grouping;
+        GroupKey {
+            size: m.size,
+            dev: if grouping.across_devices { 0 } else { m.dev },
+            owner: if grouping.compare_owners { (m.uid, m.gid) } else { (0, 0) },
+            attributes: if grouping.compare_attributes { (m.readonly, m.hidden, m.system) } else { (false, false, false) },
+        }
+    }
+
+    /// Size, device and creation time of the file, as it was when it was found
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// File name without the directory, for reporting. It's taken from the path, rather than stored again.
+    pub fn name(&self) -> Cow<'_, str> {
+        self.path.file_name().unwrap_or(self.path.as_os_str()).to_string_lossy()
+    }
+
+    /// Failed reads of the file so far. See `Hasher::read_errors`.
+    pub fn read_errors(&self) -> u32 {
+        self.hashes.borrow().read_errors()
+    }
+
+    /// Error that prevented the file from being compared. Such file can't be a duplicate of anything.
+    pub fn error(&self) -> Option<io::Error> {
+        self.hashes.borrow().error().map(|err| err.to_io_error())
+    }
+
+    /// Whether it's been compared with a file that couldn't be read (or couldn't be read itself) since the last call.
+    /// A file that fails is ordered differently than before (see `Ord`), so if it's a key of a sorted map or set,
+    /// the map is out of order. Such keys have to be removed before the map is searched again.
+    pub fn take_failed_comparison(&self) -> bool {
+        self.failed_comparison.replace(false)
+    }
+
+    /// Paths of older files (created before this one) found since the last call to have the same content, but different
+    /// alternate data streams, so they aren't equal. Only with `GroupPolicy::compare_streams`.
+    pub fn take_differing_streams(&self) -> Vec<Box<Path>> {
+        self.differing_streams.take()
+    }
+
+    /// Free memory of hashes, e.g. after the file's duplicates have been found.
+    /// Comparisons still work, but they'll have to read the file again.
+    pub fn clear_hashes(&self) {
+        self.hashes.borrow_mut().clear();
+    }
+
+    /// Reuse hashes computed earlier, e.g. in a previous run
+    pub(crate) fn preload(&self, ranges: &[RangeDigest]) {
+        self.hashes.borrow_mut().preload(ranges);
+    }
+
+    pub(crate) fn known_ranges(&self) -> Vec<RangeDigest> {
+        self.hashes.borrow().known_ranges().collect()
+    }
+
+    /// Snapshot of hashes computed so far
+    #[cfg(feature = "json")]
+    pub fn hash_state(&self) -> HashState {
+        HashState {
+            metadata: self.metadata,
+            version: FileIdentity::from_metadata(&self.metadata),
+            hashes: self.hashes.borrow().clone(),
+        }
+    }
+
+    /// Reuse hashes from `hash_state()`, which must have been computed with the same settings.
+    /// Returns `false` and ignores the state if the file may have changed since, i.e. it has a different
+    /// size, modification time or file ID. Files on filesystems without file IDs never have their state restored.
+    #[cfg(feature = "json")]
+    pub fn restore_hash_state(&self, state: HashState) -> bool {
+        if state.metadata != self.metadata || state.version.is_none() || state.version != FileIdentity::from_metadata(&self.metadata) {
+            return false;
+        }
+        *self.hashes.borrow_mut() = state.hashes;
+        true
+    }
+
+    /// Digest of the whole file content. Reuses hashes already computed for comparisons.
+    pub fn digest(&self) -> io::Result<[u8; 20]> {
+        self.hashes.borrow_mut().digest(self.metadata.size, &self.path, &self.settings)
+    }
+
+    /// Hash of the first `prefix_len` bytes, with this file's settings. See `Hasher::quick_hash`.
+    pub fn quick_hash(&self, prefix_len: u64) -> io::Result<[u8; 20]> {
+        Hasher::quick_hash(&self.path, prefix_len, &self.settings)
+    }
+
+    /// Stable fingerprint of the content, the same as `fingerprint_file`.
+    /// It doesn't depend on this file's hash settings, but with default settings it reuses hashes computed for comparisons.
+    pub fn fingerprint(&self) -> io::Result<String> {
+        if self.settings.has_default_digests() {
+            Ok(to_hex(&self.digest()?))
+        } else {
+            fingerprint_file(&self.path)
+        }
+    }
+
+    /// Whether the file's digest is `expected`, e.g. from a catalog of known files.
+    /// The computed digest is returned too, so that it can be remembered.
+    pub fn matches_digest(&self, expected: &[u8; 20]) -> io::Result<(bool, [u8; 20])> {
+        let digest = self.digest()?;
+        Ok((&digest == expected, digest))
     }
 }
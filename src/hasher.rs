@@ -1,175 +1,2418 @@
-use crate::lazyfile::LazyFile;
+use crate::lazyfile::{FileError, LazyFile};
+use crate::sparse;
+use crate::streams;
 use smallvec::SmallVec;
+use std::cell::RefCell;
 use std::cmp::{min, Ordering};
-use std::convert::TryInto;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
-/// A hashed chunk of data of arbitrary size. Files are compared a bit by bit.
-#[derive(Debug, PartialOrd, Eq, PartialEq, Ord)]
-struct HashedRange {
-    size: u64,
-    hash: [u8; 20],
+/// Hash function used to digest file content. Digests shorter than 32 bytes are zero-padded.
+pub trait ContentHasher {
+    fn new() -> Self;
+    /// Hasher whose output depends on the key
+    fn new_keyed(key: &[u8; 32]) -> Self;
+    fn update(&mut self, data: &[u8]);
+    /// Same result as `update`, but may be faster for large amounts of data
+    #[inline]
+    fn update_large(&mut self, data: &[u8]) {
+        self.update(data);
+    }
+    fn finalize(self) -> [u8; 32];
 }
 
-impl HashedRange {
-    pub fn from_file(file: &mut LazyFile<'_>, start: u64, size: u64) -> Result<Self, io::Error> {
-        let fd = file.fd()?;
-        fd.seek(SeekFrom::Start(start))?;
-        let mut hasher = blake3::Hasher::new();
-        let mut to_read = size as usize;
-        let mut data = vec![0; to_read];
-        loop {
-            match fd.read(&mut data[0..to_read]) {
-                Ok(0) => break,
-                Ok(n) => {
-                    debug_assert!(n <= to_read);
-                    hasher.update(&data[0..n]);
-
-                    to_read -= n;
-                    if to_read == 0 {
-                        break;
-                    }
-                },
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
-                Err(e) => return Err(e),
-            }
+impl ContentHasher for blake3::Hasher {
+    #[inline]
+    fn new() -> Self {
+        blake3::Hasher::new()
+    }
+
+    #[inline]
+    fn new_keyed(key: &[u8; 32]) -> Self {
+        blake3::Hasher::new_keyed(key)
+    }
+
+    #[inline]
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    /// Multithreading has overhead, so it's used only when there's enough data to split
+    #[cfg(feature = "rayon")]
+    #[inline]
+    fn update_large(&mut self, data: &[u8]) {
+        if data.len() >= 1024 * 1024 {
+            blake3::Hasher::update_rayon(self, data);
+        } else {
+            blake3::Hasher::update(self, data);
         }
-        Ok(HashedRange {
-            hash: hasher.finalize().as_bytes()[0..20].try_into().unwrap(),
-            size,
-        })
+    }
+
+    #[inline]
+    fn finalize(self) -> [u8; 32] {
+        *blake3::Hasher::finalize(&self).as_bytes()
     }
 }
 
-#[derive(Debug)]
-pub struct Hasher {
-    ranges: SmallVec<[Option<HashedRange>; 1]>,
+impl ContentHasher for xxhash_rust::xxh3::Xxh3 {
+    #[inline]
+    fn new() -> Self {
+        xxhash_rust::xxh3::Xxh3::new()
+    }
+
+    #[inline]
+    fn new_keyed(key: &[u8; 32]) -> Self {
+        let mut seed = [0; 8];
+        seed.copy_from_slice(&key[..8]);
+        xxhash_rust::xxh3::Xxh3::with_seed(u64::from_le_bytes(seed))
+    }
+
+    #[inline]
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    #[inline]
+    fn finalize(self) -> [u8; 32] {
+        let mut out = [0; 32];
+        out[0..16].copy_from_slice(&self.digest128().to_le_bytes());
+        out
+    }
 }
 
-/// Compares two files using hashes by hashing incrementally until the first difference is found
-struct HashIter<'a> {
-    pub index: usize,
-    pub start_offset: u64,
-    pub end_offset: u64,
-    next_buffer_size: u64,
-    a_file: LazyFile<'a>,
-    b_file: LazyFile<'a>,
+impl ContentHasher for sha2::Sha256 {
+    #[inline]
+    fn new() -> Self {
+        sha2::Digest::new()
+    }
+
+    /// SHA-256 isn't vulnerable to length extension here, since the digests are never published
+    #[inline]
+    fn new_keyed(key: &[u8; 32]) -> Self {
+        sha2::Digest::new_with_prefix(key)
+    }
+
+    #[inline]
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    #[inline]
+    fn finalize(self) -> [u8; 32] {
+        sha2::Digest::finalize(self).into()
+    }
 }
 
-impl<'h> HashIter<'h> {
-    pub fn new(size: u64, a_path: &'h Path, b_path: &'h Path) -> Self {
-        HashIter {
-            index: 0,
-            start_offset: 0,
-            end_offset: size,
-            next_buffer_size: 2048,
-            a_file: LazyFile::new(a_path),
-            b_file: LazyFile::new(b_path),
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum HashAlgorithm {
+    /// Cryptographic hash. Safe to use on untrusted files.
+    #[default]
+    Blake3,
+    /// Much faster, but not collision-resistant. Use only on trusted local files.
+    Xxh3,
+    /// Slower than blake3, but a standard cryptographic hash, e.g. where policy requires one.
+    Sha256,
+}
+
+/// What other programs are allowed to do with files while they're open for comparison. Only Windows enforces it.
+/// Opening fails (after `open_retries`) if another program already uses the file in a way that isn't allowed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ShareMode {
+    /// Nobody else can open the file, so nothing can change it mid-hash.
+    /// Fails on files that any other program keeps open.
+    Exclusive,
+    /// Others can read, but not write.
+    Read,
+    /// Others can read, rename, or delete, but not write.
+    #[default]
+    ReadDelete,
+    /// No restrictions. Files can change while they're being compared.
+    All,
+}
+
+impl ShareMode {
+    #[cfg(windows)]
+    pub(crate) fn flags(self) -> u32 {
+        use windows_sys::Win32::Storage::FileSystem::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+        match self {
+            ShareMode::Exclusive => 0,
+            ShareMode::Read => FILE_SHARE_READ,
+            ShareMode::ReadDelete => FILE_SHARE_READ | FILE_SHARE_DELETE,
+            ShareMode::All => FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
         }
     }
+}
 
-    /// Compare (and compute if needed) the next two hashes
-    pub fn next<'a,'b>(&mut self, a_hash: &'a mut Hasher, b_hash: &'b mut Hasher) -> Result<Option<(&'a HashedRange, &'b HashedRange)>, io::Error> {
-        if self.start_offset >= self.end_offset {
-            return Ok(None);
-        }
+/// Options controlling how file content is compared
+#[derive(Debug, Clone)]
+pub struct HashSettings {
+    pub algorithm: HashAlgorithm,
+    /// Size in bytes of the first chunk read from each file.
+    /// Small values find early differences quickly, large ones avoid tiny reads of files that differ only near the end.
+    pub initial_buffer_size: u64,
+    /// Each next chunk is this many times larger than the previous one. Values below 2 are treated as 2.
+    /// A gentle ramp reduces thrashing on HDDs, a steep one gets to large reads faster on SSDs.
+    pub growth_factor: u64,
+    /// Upper limit for the chunk size. Each comparison allocates a buffer of up to this size,
+    /// so peak buffer memory is roughly this times the number of threads comparing files, unless limited by `buffer_budget`.
+    /// If it's smaller than `initial_buffer_size`, chunks simply never grow.
+    pub max_buffer_size: u64,
+    /// Number of bytes of each range's hash that are compared, from 1 to 32.
+    /// The default of 20 bytes (160 bits) is practically collision-free, and all 32 are only for the paranoid.
+    /// Hashes always take 32 bytes in memory and in caches, with the rest zeroed, so fewer don't save any space.
+    pub digest_len: usize,
+    /// Before hashing the first chunk with `algorithm`, compare its cheap xxh3 hash.
+    /// Speeds up trees full of same-size files that differ early. Files with a matching quick hash are still fully compared.
+    pub quick_prefilter: bool,
+    /// Ranges at least this large are hashed directly from a memory map (requires the `mmap` feature)
+    pub mmap_threshold: u64,
+    /// Files at least this large are mapped into memory when they're opened for a comparison, and their reads come from the map
+    /// without copying (requires the `mmap` feature). The file length is checked before each read, and if it's changed,
+    /// the map is dropped and reads fall back to `read`. Disabled by default.
+    pub mmap_file_threshold: u64,
+    /// Read both files of a comparison at the same time, on separate threads.
+    /// Helps when the files are on different disks, but causes seek contention on a single HDD.
+    pub parallel_reads: bool,
+    /// Compare this many first bytes of files directly, before hashing anything. 0 disables it.
+    /// Rejects files with different headers (e.g. fixed-size database pages) without hashing them.
+    pub raw_prefix_size: usize,
+    /// Don't read holes of sparse files. They're hashed as zeros, so sparse and dense copies still compare equal.
+    pub skip_holes: bool,
+    /// Seed hashes with this key, so that files crafted to collide can't fool comparisons of untrusted files.
+    /// Hashes depend on the key, so a random key from `random_key()` makes them useful only within a single run.
+    pub key: Option<[u8; 32]>,
+    /// Files smaller than this are read in one go, and all their ranges are hashed from memory.
+    /// The ranges are the same as if they were read incrementally.
+    pub small_file_threshold: u64,
+    /// Shared limit of memory for read buffers of all comparisons, regardless of how many threads run them.
+    /// It limits only how much is read at once, and doesn't change the hashes.
+    pub buffer_budget: Option<Arc<BufferBudget>>,
+    /// Limit of files open at once by all comparisons. By default it's derived from the OS limit, and shared by the whole process.
+    pub open_files: Option<Arc<OpenFileLimit>>,
+    /// When set, comparisons stop before reading the next range, and fail with `ErrorKind::Interrupted`.
+    /// Hashes computed so far are kept, so comparing again continues where it stopped.
+    /// `Ord` of `FileContent` can't fail, so this is only for explicit `compare_with_progress` calls.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Limit of read throughput of all comparisons, so that they don't starve other programs using the disk
+    pub rate_limit: Option<Arc<RateLimit>>,
+    /// Tell the OS that files are read sequentially, so that it reads ahead and doesn't keep them cached.
+    /// Disable it when files are read in random order, e.g. with a tiny `max_buffer_size`.
+    pub sequential_scan: bool,
+    /// Evict files from the OS cache once they've been compared, so that a run over more data than fits in RAM
+    /// doesn't push everything else out of the cache. It happens after every comparison, so a file compared with
+    /// many others is read from disk again each time, which is why it's off by default. Has no effect on Windows,
+    /// where `sequential_scan` drops read pages.
+    pub drop_cache: bool,
+    /// How many times to retry opening a file that is temporarily locked by another program,
+    /// e.g. being scanned by an antivirus (sharing violation on Windows). Other errors fail immediately.
+    pub open_retries: u32,
+    /// Delay before the first retry of opening a file. It doubles with each next retry.
+    pub open_retry_delay: Duration,
+    /// Access allowed to other programs while files are open, on Windows
+    pub share_mode: ShareMode,
+    /// Compare symlinks by the path they point to, without opening their targets. Links to the same path are equal,
+    /// even if the target doesn't exist. Otherwise symlinks are followed, and compared by the content of their targets.
+    pub compare_symlink_targets: bool,
+    /// Reads smaller than this are made this large, and following reads are served from the read-ahead buffer.
+    /// On cold caches it merges the small early chunks into one syscall. 0 disables it.
+    pub read_ahead_size: usize,
+    /// Read with `O_DIRECT` on Linux and `FILE_FLAG_NO_BUFFERING` on Windows, so that scanning doesn't evict
+    /// other programs' data from the OS cache. Reads are aligned to 4KB blocks. Files on filesystems that don't
+    /// support it are read normally.
+    pub direct_io: bool,
+    /// Reopen files after transient read errors, e.g. of a flaky external drive or network share
+    pub read_retries: Option<ReadRetries>,
+    /// Collects errors of all files that couldn't be read, e.g. to report them at the end of a run
+    pub error_log: Option<Arc<ErrorLog>>,
+    /// Reads interrupted by signals are retried, but after this many in a row the read fails with `ErrorKind::Interrupted`
+    pub max_interrupted_reads: u32,
+}
 
-        let i = self.index;
-        let (a_none, b_none, size) = {
-            let a = a_hash.ranges.get(i);
-            let b = b_hash.ranges.get(i);
+impl Default for HashSettings {
+    fn default() -> Self {
+        HashSettings {
+            algorithm: HashAlgorithm::Blake3,
+            initial_buffer_size: 2048,
+            growth_factor: 16,
+            max_buffer_size: 128 * 1024 * 1024,
+            digest_len: 20,
+            quick_prefilter: false,
+            mmap_threshold: 16 * 1024 * 1024,
+            mmap_file_threshold: u64::MAX,
+            parallel_reads: false,
+            raw_prefix_size: 0,
+            skip_holes: true,
+            key: None,
+            small_file_threshold: 64 * 1024,
+            buffer_budget: None,
+            open_files: Some(OpenFileLimit::os_default()),
+            cancel: None,
+            rate_limit: None,
+            sequential_scan: true,
+            drop_cache: false,
+            open_retries: 5,
+            open_retry_delay: Duration::from_millis(20),
+            share_mode: ShareMode::default(),
+            compare_symlink_targets: false,
+            read_ahead_size: 64 * 1024,
+            direct_io: false,
+            read_retries: Some(ReadRetries::default()),
+            error_log: None,
+            max_interrupted_reads: 1000,
+        }
+    }
+}
 
-            let failed = a.map_or(false, |a| a.is_none()) || b.map_or(false, |b| b.is_none());
-            if failed {
-                return Err(io::Error::new(io::ErrorKind::Other, "cmp i/o"));
-            }
+impl HashSettings {
+    /// Whether digests are the same as with default settings, so they can be shared outside of this run
+    pub(crate) fn has_default_digests(&self) -> bool {
+        let default = HashSettings::default();
+        self.algorithm == default.algorithm
+            && self.key.is_none()
+            && self.digest_len() == default.digest_len
+            && self.initial_buffer_size.max(1) == default.initial_buffer_size
+            && self.growth_factor.max(2) == default.growth_factor
+            && self.max_buffer_size == default.max_buffer_size
+    }
 
-            // If there is an existing hashed chunk, the chunk size used for comparison must obviously be it.
-            let size = a
-                .and_then(|a| a.as_ref().map(|a| a.size))
-                .or(b.and_then(|b| b.as_ref().map(|b| b.size)))
-                .unwrap_or(min(self.end_offset - self.start_offset, self.next_buffer_size));
-            (a.is_none(), b.is_none(), size)
-        };
+    /// Unpredictable key for `key`
+    pub fn random_key() -> [u8; 32] {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher as _};
+        use std::time::SystemTime;
 
-        // If any of the ranges is missing, compute it
-        if a_none {
-            a_hash.push(HashedRange::from_file(&mut self.a_file, self.start_offset, size));
+        // RandomState is seeded from the OS, so it's a source of randomness without extra dependencies
+        let mut seed = blake3::Hasher::new();
+        for i in 0..4u64 {
+            let mut h = RandomState::new().build_hasher();
+            h.write_u64(i);
+            seed.update(&h.finish().to_le_bytes());
         }
-        if b_none {
-            b_hash.push(HashedRange::from_file(&mut self.b_file, self.start_offset, size));
+        if let Ok(time) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            seed.update(&time.as_nanos().to_le_bytes());
         }
+        *blake3::Hasher::finalize(&seed).as_bytes()
+    }
 
-        self.index += 1;
-        self.start_offset += size;
+    pub(crate) fn digest_len(&self) -> usize {
+        self.digest_len.clamp(1, 32)
+    }
+
+    /// Size of the chunk to read after a chunk of `size` bytes. Never smaller than the initial size,
+    /// so that an empty or tiny range can't stall further reads.
+    fn next_buffer_size(&self, size: u64) -> u64 {
         // The buffer size is a trade-off between finding a difference quickly
         // and reading files one by one without trashing.
         // Exponential increase is meant to be a compromise that allows finding
         // the difference in the first few KB, but grow quickly to read identical files faster.
-        self.next_buffer_size = min(size * 16, 128 * 1024 * 1024);
+        min(size.saturating_mul(self.growth_factor.max(2)), self.max_buffer_size)
+            .max(size)
+            .max(self.initial_buffer_size.max(1))
+    }
+}
 
-        match (a_hash.ranges.get(i), b_hash.ranges.get(i)) {
-            (Some(Some(a)), Some(Some(b))) => Ok(Some((a, b))),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "cmp i/o")),
+/// Limit of memory for read buffers, shared by all comparisons using the same settings.
+/// Comparisons that can't get more memory from it read their chunks in smaller pieces, so they never wait for each other.
+#[derive(Debug)]
+pub struct BufferBudget {
+    available: AtomicU64,
+}
+
+impl BufferBudget {
+    pub fn new(bytes: u64) -> Self {
+        BufferBudget {
+            available: AtomicU64::new(bytes),
         }
     }
+
+    /// Bytes that haven't been taken by any buffer
+    pub fn available(&self) -> u64 {
+        self.available.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Takes up to `wanted` bytes. Returns how many were taken.
+    fn acquire(&self, wanted: u64) -> u64 {
+        let mut taken = 0;
+        let _ = self.available.fetch_update(AtomicOrdering::AcqRel, AtomicOrdering::Acquire, |available| {
+            taken = min(available, wanted);
+            Some(available - taken)
+        });
+        taken
+    }
+
+    fn release(&self, bytes: u64) {
+        self.available.fetch_add(bytes, AtomicOrdering::AcqRel);
+    }
 }
 
-impl Hasher {
-    #[inline]
-    pub fn new() -> Self {
-        Hasher {
-            ranges: SmallVec::new(),
+/// Limit of files open at the same time, shared by all comparisons using it.
+///
+/// Opening a file waits until another thread closes one. If no other thread could, because all the files are open
+/// by this thread or by threads that are waiting too, opening fails with the OS error for too many open files instead.
+/// Comparisons need two files at a time, so the limit should be at least two files per thread comparing files.
+#[derive(Debug)]
+pub struct OpenFileLimit {
+    max: usize,
+    open: Mutex<OpenFiles>,
+    closed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct OpenFiles {
+    open: usize,
+    /// The most open at once so far
+    peak: usize,
+    /// Threads that have files open, and how many
+    by_thread: HashMap<ThreadId, usize>,
+    /// Threads waiting in `acquire`
+    waiting: HashSet<ThreadId>,
+}
+
+/// Taken while a file is open. Dropping it lets another file be opened.
+#[derive(Debug)]
+pub(crate) struct OpenFileSlot {
+    limit: Arc<OpenFileLimit>,
+    thread: ThreadId,
+}
+
+impl Drop for OpenFileSlot {
+    fn drop(&mut self) {
+        self.limit.release(self.thread);
+    }
+}
+
+/// Shared by all settings using the default limit
+static DEFAULT_OPEN_FILE_LIMIT: Mutex<Option<Arc<OpenFileLimit>>> = Mutex::new(None);
+
+impl OpenFileLimit {
+    pub fn new(max_open_files: usize) -> Self {
+        OpenFileLimit {
+            max: max_open_files.max(1),
+            open: Mutex::new(OpenFiles::default()),
+            closed: Condvar::new(),
         }
     }
 
-    #[inline]
-    fn push(&mut self, range: Result<HashedRange, io::Error>) {
-        let r = match range {
-            Ok(r) => Some(r),
-            Err(err) => {
-                eprintln!("Can't compare files: {}", err);
-                None
-            },
+    /// Process-wide limit of half the OS limit of open files (the soft `RLIMIT_NOFILE` on Unix, capped at 64K),
+    /// so that the other half is left for the rest of the program: standard streams, directories being scanned,
+    /// the hash cache, journal and output files.
+    pub fn os_default() -> Arc<Self> {
+        let mut default = DEFAULT_OPEN_FILE_LIMIT.lock().unwrap_or_else(|e| e.into_inner());
+        default.get_or_insert_with(|| Arc::new(OpenFileLimit::new(os_open_file_limit() / 2))).clone()
+    }
+
+    /// Number of files that are open now
+    pub fn open(&self) -> usize {
+        self.open.lock().unwrap_or_else(|e| e.into_inner()).open
+    }
+
+    /// The most files that have been open at the same time
+    pub fn peak(&self) -> usize {
+        self.open.lock().unwrap_or_else(|e| e.into_inner()).peak
+    }
+
+    /// Waits until another file can be opened, unless waiting could never end
+    pub(crate) fn acquire(self: &Arc<Self>) -> io::Result<OpenFileSlot> {
+        let thread = std::thread::current().id();
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        while open.open >= self.max {
+            if open.by_thread.keys().all(|t| *t == thread || open.waiting.contains(t)) {
+                return Err(too_many_open_files());
+            }
+            open.waiting.insert(thread);
+            open = self.closed.wait(open).unwrap_or_else(|e| e.into_inner());
+            open.waiting.remove(&thread);
+        }
+        open.open += 1;
+        open.peak = open.peak.max(open.open);
+        *open.by_thread.entry(thread).or_insert(0) += 1;
+        Ok(OpenFileSlot {
+            limit: Arc::clone(self),
+            thread,
+        })
+    }
+
+    fn release(&self, thread: ThreadId) {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.open -= 1;
+        if let Some(count) = open.by_thread.get_mut(&thread) {
+            *count -= 1;
+            if *count == 0 {
+                open.by_thread.remove(&thread);
+            }
+        }
+        drop(open);
+        self.closed.notify_one();
+    }
+}
+
+#[cfg(unix)]
+fn too_many_open_files() -> io::Error {
+    io::Error::from_raw_os_error(libc::EMFILE)
+}
+
+#[cfg(windows)]
+fn too_many_open_files() -> io::Error {
+    io::Error::from_raw_os_error(windows_sys::Win32::Foundation::ERROR_TOO_MANY_OPEN_FILES as i32)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn too_many_open_files() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "too many open files")
+}
+
+#[cfg(unix)]
+fn os_open_file_limit() -> usize {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return 256;
+    }
+    // It may be unlimited
+    limit.rlim_cur.min(1 << 16) as usize
+}
+
+/// Windows has no small limit of handles
+#[cfg(windows)]
+fn os_open_file_limit() -> usize {
+    4096
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_open_file_limit() -> usize {
+    256
+}
+
+/// Token bucket limiting bytes read per second. It can be shared by many comparisons.
+/// Reads larger than the limit are allowed, and are followed by a proportionally long pause.
+#[derive(Debug)]
+pub struct RateLimit {
+    bytes_per_second: f64,
+    /// Bytes that can be read without waiting (negative when the limit is exceeded), as of the time since `start`
+    state: Mutex<(f64, Duration)>,
+    start: Instant,
+    /// `Instant::elapsed` and `thread::sleep`, except in tests
+    elapsed: fn(&Instant) -> Duration,
+    sleep: fn(Duration),
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self::with_clock(bytes_per_second, Instant::elapsed, std::thread::sleep)
+    }
+
+    fn with_clock(bytes_per_second: u64, elapsed: fn(&Instant) -> Duration, sleep: fn(Duration)) -> Self {
+        let bytes_per_second = bytes_per_second.max(1) as f64;
+        let start = Instant::now();
+        RateLimit {
+            bytes_per_second,
+            // Allows a burst of a second's worth
+            state: Mutex::new((bytes_per_second, elapsed(&start))),
+            start,
+            elapsed,
+            sleep,
+        }
+    }
+
+    /// Sleeps long enough to keep reads under the limit, given that `bytes` have just been read
+    fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let now = (self.elapsed)(&self.start);
+            let refill = now.saturating_sub(state.1).as_secs_f64() * self.bytes_per_second;
+            state.0 = (state.0 + refill).min(self.bytes_per_second) - bytes as f64;
+            state.1 = now;
+            if state.0 < 0. {
+                Duration::from_secs_f64(-state.0 / self.bytes_per_second)
+            } else {
+                Duration::ZERO
+            }
         };
-        self.ranges.push(r);
+        if !wait.is_zero() {
+            (self.sleep)(wait);
+        }
     }
+}
 
-    /// Incremental comparison reading files lazily
+/// Read errors after which the file is reopened, and read again from the position that failed
+#[derive(Debug, Clone)]
+pub struct ReadRetries {
+    /// Retries of each file. They're not reset after a successful read, so a failing file can't loop forever.
+    pub max_retries: u32,
+    /// Delay before the first retry. It doubles with each next retry.
+    pub delay: Duration,
+    pub kinds: Vec<io::ErrorKind>,
+    /// Errors that don't have a specific `ErrorKind`, like `EIO`
+    pub os_errors: Vec<i32>,
+}
+
+impl Default for ReadRetries {
+    fn default() -> Self {
+        ReadRetries {
+            max_retries: 3,
+            delay: Duration::from_millis(100),
+            kinds: vec![
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::ConnectionAborted,
+                io::ErrorKind::NotConnected,
+                io::ErrorKind::BrokenPipe,
+            ],
+            os_errors: transient_os_errors(),
+        }
+    }
+}
+
+impl ReadRetries {
+    /// Missing, inaccessible and changed files are never retried
+    pub(crate) fn is_transient(&self, err: &io::Error) -> bool {
+        let file_err = err.get_ref().and_then(|e| e.downcast_ref::<FileError>());
+        if file_err.map_or(false, |e| e.changed) || matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied) {
+            return false;
+        }
+        let os_error = err.raw_os_error().or_else(|| file_err.and_then(|e| e.raw_os_error));
+        self.kinds.contains(&err.kind()) || os_error.map_or(false, |code| self.os_errors.contains(&code))
+    }
+}
+
+#[cfg(unix)]
+fn transient_os_errors() -> Vec<i32> {
+    vec![libc::EIO]
+}
+
+#[cfg(windows)]
+fn transient_os_errors() -> Vec<i32> {
+    use windows_sys::Win32::Foundation::{ERROR_NETNAME_DELETED, ERROR_SEM_TIMEOUT, ERROR_UNEXP_NET_ERR};
+    vec![ERROR_NETNAME_DELETED as i32, ERROR_SEM_TIMEOUT as i32, ERROR_UNEXP_NET_ERR as i32]
+}
+
+#[cfg(not(any(unix, windows)))]
+fn transient_os_errors() -> Vec<i32> {
+    Vec::new()
+}
+
+/// Errors of files that couldn't be compared. It can be shared by many comparisons.
+/// Each unreadable file is recorded once, when it fails, even if the error isn't seen by the caller.
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    errors: Mutex<Vec<FileError>>,
+}
+
+impl ErrorLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, err: FileError) {
+        self.errors.lock().unwrap_or_else(|e| e.into_inner()).push(err);
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Errors recorded so far, in order they've happened
+    pub fn errors(&self) -> Vec<FileError> {
+        self.errors.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Buffers this small are always allowed, even if the budget is exhausted
+const MIN_BUDGETED_BUFFER_SIZE: u64 = 64 * 1024;
+
+/// Scratch space for reading files. It's grown when needed, so that it can be reused for subsequent ranges.
+#[derive(Debug, Default)]
+struct ReadBuffer {
+    data: Vec<u8>,
+    budget: Option<Arc<BufferBudget>>,
+    rate_limit: Option<Arc<RateLimit>>,
+    /// Bytes taken from the budget
+    reserved: u64,
+}
+
+impl ReadBuffer {
+    fn new(settings: &HashSettings) -> Self {
+        ReadBuffer {
+            data: Vec::new(),
+            budget: settings.buffer_budget.clone(),
+            rate_limit: settings.rate_limit.clone(),
+            reserved: 0,
+        }
+    }
+
+    /// Call after reading into the buffer, to keep reads under the rate limit
     #[inline]
-    pub fn compare(&mut self, other: &mut Hasher, size: u64, self_path: &Path, other_path: &Path) -> Result<Ordering, io::Error> {
-        let mut iter = HashIter::new(size, self_path, other_path);
+    fn throttle(&self, bytes_read: usize) {
+        if let Some(limit) = &self.rate_limit {
+            limit.consume(bytes_read as u64);
+        }
+    }
 
-        while let Some((a, b)) = iter.next(self, other)? {
-            let ord = a.cmp(b);
-            if ord != Ordering::Equal {
-                return Ok(ord);
+    /// Space for reading up to `wanted` bytes at once. It's non-empty, but may be smaller than wanted.
+    fn get(&mut self, wanted: u64) -> &mut [u8] {
+        let wanted = wanted.max(1);
+        if (self.data.len() as u64) < wanted {
+            let mut new_len = wanted;
+            if let Some(budget) = &self.budget {
+                let allowed = MIN_BUDGETED_BUFFER_SIZE + self.reserved;
+                if wanted > allowed {
+                    self.reserved += budget.acquire(wanted - allowed);
+                }
+                new_len = min(wanted, MIN_BUDGETED_BUFFER_SIZE + self.reserved);
+            }
+            if new_len > self.data.len() as u64 {
+                let mut data = take_pooled(new_len as usize);
+                std::mem::swap(&mut self.data, &mut data);
+                return_pooled(data);
             }
         }
-        Ok(Ordering::Equal)
+        let len = min(wanted, self.data.len() as u64) as usize;
+        &mut self.data[..len]
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::fs;
-    
+impl Drop for ReadBuffer {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.reserved);
+        }
+        // Memory taken from the budget must be given back with it
+        if self.reserved == 0 {
+            return_pooled(std::mem::take(&mut self.data));
+        }
+    }
+}
 
-    #[test]
-    fn range_hash() {
-        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
-        let path = &tmp.path().join("a");
-        fs::write(path, "aaa\n").expect("write");
-        let mut file = LazyFile::new(path);
-        let hashed = HashedRange::from_file(&mut file, 0, 4).expect("hash");
+/// Buffers kept for reuse per thread in each capacity bucket
+const POOLED_PER_BUCKET: usize = 2;
 
-        assert_eq!(4, hashed.size);
-        assert_eq!([22, 179, 164, 66, 194, 34, 185, 88, 69, 62, 115, 203, 129, 138, 81, 160, 96, 190, 209, 11], hashed.hash);
+thread_local! {
+    /// Buffers of finished reads. Every comparison needs a buffer, and allocating it anew each time is a waste.
+    /// Buckets are by power-of-two capacity.
+    static BUFFER_POOL: RefCell<Vec<Vec<Vec<u8>>>> = const { RefCell::new(Vec::new()) };
+}
 
-        let hashed = HashedRange::from_file(&mut file, 1, 2).expect("hash2");
-        assert_eq!(2, hashed.size);
+/// Zero-filled (or previously used) buffer of `len` bytes
+fn take_pooled(len: usize) -> Vec<u8> {
+    let bucket = len.next_power_of_two().trailing_zeros() as usize;
+    // A buffer up to twice as large is good enough
+    let mut data = BUFFER_POOL
+        .with(|pool| {
+            let mut pool = pool.borrow_mut();
+            (bucket..bucket + 2).find_map(|b| pool.get_mut(b).and_then(|b| b.pop()))
+        })
+        .unwrap_or_else(|| Vec::with_capacity(1 << bucket));
+    data.resize(len, 0);
+    data
+}
+
+fn return_pooled(data: Vec<u8>) {
+    if data.capacity() == 0 {
+        return;
+    }
+    // Round down, so that every buffer in a bucket is at least as large as the bucket size
+    let bucket = (usize::BITS - 1 - data.capacity().leading_zeros()) as usize;
+    // The pool may be already gone if the thread is exiting
+    let _ = BUFFER_POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() <= bucket {
+            pool.resize_with(bucket + 1, Vec::new);
+        }
+        if pool[bucket].len() < POOLED_PER_BUCKET {
+            pool[bucket].push(data);
+        }
+    });
+}
+
+/// Offsets and sizes of chunks a file is hashed in. They depend only on the file size and settings,
+/// so ranges computed in one comparison line up with ranges needed by any other comparison.
+#[derive(Debug, Clone)]
+struct Chunks<'a> {
+    start_offset: u64,
+    end_offset: u64,
+    next_buffer_size: u64,
+    settings: &'a HashSettings,
+}
+
+impl<'a> Chunks<'a> {
+    fn new(size: u64, settings: &'a HashSettings) -> Self {
+        Chunks {
+            start_offset: 0,
+            end_offset: size,
+            next_buffer_size: settings.initial_buffer_size.max(1),
+            settings,
+        }
+    }
+}
+
+impl Iterator for Chunks<'_> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        if self.start_offset >= self.end_offset {
+            return None;
+        }
+        let start = self.start_offset;
+        let size = min(self.end_offset - start, self.next_buffer_size);
+        self.start_offset += size;
+        self.next_buffer_size = self.settings.next_buffer_size(size);
+        Some((start, size))
+    }
+}
+
+/// Smaller ranges are read faster than a thread can be spawned
+const PARALLEL_READ_MIN_SIZE: u64 = 256 * 1024;
+
+/// Memory maps are hashed in chunks of this size, and the file length is checked before each,
+/// since reading a part of the map that a truncated file no longer has would fault
+#[cfg(feature = "mmap")]
+const MAP_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// A hashed chunk of data of arbitrary size. Files are compared a bit by bit.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
+struct HashedRange {
+    size: u64,
+    /// Bytes past the truncation length are zeroed
+    hash: [u8; 32],
+}
+
+/// Ordered by hash first. Corresponding ranges of compared files have the same size,
+/// and if they didn't, a difference of content shouldn't look like a difference of size.
+impl Ord for HashedRange {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.hash.cmp(&other.hash).then(self.size.cmp(&other.size))
+    }
+}
+
+impl PartialOrd for HashedRange {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl HashedRange {
+    /// Hashes `size` bytes from `start`. If the file is shorter, the range has only as many bytes as were read.
+    pub fn from_file(file: &mut LazyFile<'_, impl Read + ?Sized>, start: u64, size: u64, settings: &HashSettings, buffer: &mut ReadBuffer) -> Result<Self, io::Error> {
+        let range = match settings.algorithm {
+            HashAlgorithm::Blake3 => Self::from_file_with::<blake3::Hasher>(file, start, size, settings, buffer)?,
+            HashAlgorithm::Xxh3 => Self::from_file_with::<xxhash_rust::xxh3::Xxh3>(file, start, size, settings, buffer)?,
+            HashAlgorithm::Sha256 => Self::from_file_with::<sha2::Sha256>(file, start, size, settings, buffer)?,
+        };
+        Ok(range.truncated(settings))
+    }
+
+    fn truncated(mut self, settings: &HashSettings) -> Self {
+        self.hash[settings.digest_len()..].fill(0);
+        self
+    }
+
+    fn from_file_with<H: ContentHasher>(file: &mut LazyFile<'_, impl Read + ?Sized>, start: u64, size: u64, settings: &HashSettings, buffer: &mut ReadBuffer) -> Result<Self, io::Error> {
+        #[cfg(feature = "mmap")]
+        if file.mapped()?.is_some() {
+            let mut hasher = new_hasher::<H>(settings);
+            let end = start.saturating_add(size);
+            let mut pos = start;
+            while pos < end {
+                match file.mapped_range(pos, min(end - pos, MAP_CHUNK_SIZE))? {
+                    Some([]) => break,
+                    Some(bytes) => {
+                        hasher.update_large(bytes);
+                        if let Some(limit) = &settings.rate_limit {
+                            limit.consume(bytes.len() as u64);
+                        }
+                        pos += bytes.len() as u64;
+                    },
+                    // The file has changed, and the map is gone
+                    None => {
+                        pos += read_range(file, &mut hasher, pos, end - pos, buffer)?;
+                        break;
+                    },
+                }
+            }
+            return Ok(HashedRange {
+                hash: hasher.finalize(),
+                size: pos - start,
+            });
+        }
+
+        #[cfg(feature = "mmap")]
+        if size >= settings.mmap_threshold && !file.is_stream() {
+            return Self::from_mmap::<H>(file, start, size, settings, buffer);
+        }
+
+        let mut hasher = new_hasher::<H>(settings);
+        let hashed = if settings.skip_holes && file.is_sparse()? {
+            let fd = file.fd()?;
+            // Past the end of file there's nothing, not a hole
+            let end = min(start + size, fd.metadata()?.len().max(start));
+            let mut pos = start;
+            let mut hashed = 0;
+            for (data_start, data_end) in sparse::data_ranges(fd, start, end)? {
+                hash_zeros(&mut hasher, data_start - pos);
+                hashed += data_start - pos;
+                hashed += read_range(file, &mut hasher, data_start, data_end - data_start, buffer)?;
+                pos = data_end;
+            }
+            hash_zeros(&mut hasher, end - pos);
+            hashed + (end - pos)
+        } else {
+            read_range(file, &mut hasher, start, size, buffer)?
+        };
+        Ok(HashedRange {
+            hash: hasher.finalize(),
+            size: hashed,
+        })
+    }
+
+    fn from_bytes(data: &[u8], settings: &HashSettings) -> Self {
+        HashedRange {
+            size: data.len() as u64,
+            hash: match settings.algorithm {
+                HashAlgorithm::Blake3 => hash_bytes::<blake3::Hasher>(data, settings),
+                HashAlgorithm::Xxh3 => hash_bytes::<xxhash_rust::xxh3::Xxh3>(data, settings),
+                HashAlgorithm::Sha256 => hash_bytes::<sha2::Sha256>(data, settings),
+            },
+        }
+        .truncated(settings)
+    }
+
+    #[cfg(feature = "mmap")]
+    fn from_mmap<H: ContentHasher>(file: &mut LazyFile<'_, impl Read + ?Sized>, start: u64, size: u64, settings: &HashSettings, buffer: &mut ReadBuffer) -> Result<Self, io::Error> {
+        let fd = file.fd()?;
+        // The file may have been truncated since it was stat'ed, and mapping past its end would fault.
+        // Like the read loop, hash only the bytes that actually exist.
+        let len = fd.metadata()?.len();
+        let map_size = min(size, len.saturating_sub(start));
+        let mut hasher = new_hasher::<H>(settings);
+        let mut hashed = 0;
+        if map_size > 0 {
+            // The map is read-only and within the current file length
+            let map = unsafe { memmap2::MmapOptions::new().offset(start).len(map_size as usize).map(&*fd)? };
+            for chunk in map.chunks(MAP_CHUNK_SIZE as usize) {
+                // Truncated while it's being hashed, so the rest is read instead
+                if file.fd()?.metadata()?.len() < start + hashed + chunk.len() as u64 {
+                    hashed += read_range(file, &mut hasher, start + hashed, map_size - hashed, buffer)?;
+                    break;
+                }
+                hasher.update_large(chunk);
+                file.record_read(chunk.len() as u64);
+                if let Some(limit) = &settings.rate_limit {
+                    limit.consume(chunk.len() as u64);
+                }
+                hashed += chunk.len() as u64;
+            }
+        }
+        Ok(HashedRange {
+            hash: hasher.finalize(),
+            size: hashed,
+        })
+    }
+}
+
+/// Size and hash of a range, for storing hashes outside of the `Hasher`
+pub type RangeDigest = (u64, [u8; 32]);
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
+pub struct Hasher {
+    ranges: SmallVec<[HashedRange; 1]>,
+    /// Why the next range couldn't be computed. Not saved, so that the file is retried after loading.
+    #[cfg_attr(feature = "json", serde(skip))]
+    error: Option<FileError>,
+    #[cfg_attr(feature = "json", serde(skip))]
+    error_reported: bool,
+    /// Beginning of the file, if `raw_prefix_size` is used
+    prefix: Option<Box<[u8]>>,
+    /// xxh3 of the first range, if `quick_prefilter` is used
+    quick: Option<HashedRange>,
+    /// Bytes read from the file to compute hashes, in total
+    #[cfg_attr(feature = "json", serde(skip))]
+    bytes_read: u64,
+    #[cfg_attr(feature = "json", serde(skip))]
+    read_errors: u32,
+    /// Names, sizes and digests of alternate data streams, if `GroupPolicy::compare_streams` is used
+    #[cfg_attr(feature = "json", serde(skip))]
+    streams: Option<Vec<StreamDigest>>,
+}
+
+/// Name, size and digest of an alternate data stream
+pub(crate) type StreamDigest = (String, u64, [u8; 20]);
+
+/// Compares two files using hashes by hashing incrementally until the first difference is found
+struct HashIter<'a> {
+    pub index: usize,
+    pub chunks: Chunks<'a>,
+    /// Shared by both files, unless they're read in parallel
+    buffer: ReadBuffer,
+    parallel_buffer: ReadBuffer,
+    a_file: LazyFile<'a>,
+    b_file: LazyFile<'a>,
+    settings: &'a HashSettings,
+}
+
+impl<'h> HashIter<'h> {
+    pub fn new(size: u64, a_path: &'h Path, b_path: &'h Path, settings: &'h HashSettings) -> Self {
+        HashIter {
+            index: 0,
+            chunks: Chunks::new(size, settings),
+            buffer: ReadBuffer::new(settings),
+            parallel_buffer: ReadBuffer::new(settings),
+            a_file: LazyFile::new(a_path, settings).with_expected_size(size),
+            b_file: LazyFile::new(b_path, settings).with_expected_size(size),
+            settings,
+        }
+    }
+
+    /// Compare (and read if needed) first bytes of the files as-is.
+    /// Like `quick_compare`, it must be used for all comparisons or none.
+    pub fn prefix_compare(&mut self, a_hash: &mut Hasher, b_hash: &mut Hasher) -> Result<Ordering, io::Error> {
+        let len = min(self.chunks.end_offset, self.settings.raw_prefix_size as u64) as usize;
+        if len == 0 {
+            return Ok(Ordering::Equal);
+        }
+
+        if a_hash.prefix.is_none() {
+            match read_prefix(&mut self.a_file, len) {
+                Ok(prefix) => a_hash.prefix = Some(prefix),
+                Err(err) => return Err(a_hash.fail(&err, &self.a_file)),
+            }
+        }
+        if b_hash.prefix.is_none() {
+            match read_prefix(&mut self.b_file, len) {
+                Ok(prefix) => b_hash.prefix = Some(prefix),
+                Err(err) => return Err(b_hash.fail(&err, &self.b_file)),
+            }
+        }
+        Ok(a_hash.prefix.cmp(&b_hash.prefix))
+    }
+
+    /// Compare (and compute if needed) cheap hashes of the first range.
+    /// This orders files before their proper hashes are compared, so it must be used for all comparisons or none.
+    pub fn quick_compare(&mut self, a_hash: &mut Hasher, b_hash: &mut Hasher) -> Result<Ordering, io::Error> {
+        // Must cover exactly the same bytes as the first proper range
+        let size = match self.chunks.clone().next() {
+            Some((_, size)) => size,
+            None => return Ok(Ordering::Equal),
+        };
+
+        if a_hash.quick.is_none() {
+            match HashedRange::from_file_with::<xxhash_rust::xxh3::Xxh3>(&mut self.a_file, 0, size, self.settings, &mut self.buffer) {
+                Ok(quick) => a_hash.quick = Some(quick),
+                Err(err) => return Err(a_hash.fail(&err, &self.a_file)),
+            }
+        }
+        if b_hash.quick.is_none() {
+            match HashedRange::from_file_with::<xxhash_rust::xxh3::Xxh3>(&mut self.b_file, 0, size, self.settings, &mut self.buffer) {
+                Ok(quick) => b_hash.quick = Some(quick),
+                Err(err) => return Err(b_hash.fail(&err, &self.b_file)),
+            }
+        }
+        Ok(a_hash.quick.cmp(&b_hash.quick))
+    }
+
+    /// Compare (and compute if needed) the next two hashes
+    pub fn next<'a,'b>(&mut self, a_hash: &'a mut Hasher, b_hash: &'b mut Hasher) -> Result<Option<(&'a HashedRange, &'b HashedRange)>, io::Error> {
+        // Not a file's error, so it's not stored in the hashers
+        if self.settings.cancel.as_ref().map_or(false, |c| c.load(AtomicOrdering::Relaxed)) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "comparison cancelled"));
+        }
+
+        let (start, size) = match self.chunks.next() {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+
+        let i = self.index;
+        let a_none = a_hash.range_sized(i, size)?.is_none();
+        let b_none = b_hash.range_sized(i, size)?.is_none();
+
+        // If any of the ranges is missing, compute it
+        if a_none && b_none && self.settings.parallel_reads && size >= PARALLEL_READ_MIN_SIZE {
+            let (a, b) = self.hash_both_parallel(start, size);
+            a_hash.push(a, &self.a_file);
+            b_hash.push(b, &self.b_file);
+        } else {
+            if a_none {
+                a_hash.push(HashedRange::from_file(&mut self.a_file, start, size, self.settings, &mut self.buffer), &self.a_file);
+            }
+            if b_none {
+                b_hash.push(HashedRange::from_file(&mut self.b_file, start, size, self.settings, &mut self.buffer), &self.b_file);
+            }
+        }
+
+        self.index += 1;
+
+        match (a_hash.ranges.get(i), b_hash.ranges.get(i)) {
+            (Some(a), Some(b)) => Ok(Some((a, b))),
+            _ => Err(a_hash.error.as_ref().or(b_hash.error.as_ref())
+                .map_or_else(|| io::Error::new(io::ErrorKind::Other, "cmp i/o"), FileError::to_io_error)),
+        }
+    }
+
+    fn hash_both_parallel(&mut self, start: u64, size: u64) -> (Result<HashedRange, io::Error>, Result<HashedRange, io::Error>) {
+        let settings = self.settings;
+        let (a_file, a_buffer) = (&mut self.a_file, &mut self.buffer);
+        let (b_file, b_buffer) = (&mut self.b_file, &mut self.parallel_buffer);
+        std::thread::scope(|s| {
+            let b = s.spawn(move || HashedRange::from_file(b_file, start, size, settings, b_buffer));
+            let a = HashedRange::from_file(a_file, start, size, settings, a_buffer);
+            let b = b.join().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "hashing thread panicked")));
+            (a, b)
+        })
+    }
+}
+
+impl Hasher {
+    #[inline]
+    pub fn new() -> Self {
+        Hasher {
+            ranges: SmallVec::new(),
+            error: None,
+            error_reported: false,
+            prefix: None,
+            quick: None,
+            bytes_read: 0,
+            read_errors: 0,
+            streams: None,
+        }
+    }
+
+    /// Reuse hashes computed earlier. They must have been computed with the same settings.
+    pub fn preload(&mut self, ranges: &[RangeDigest]) {
+        if self.ranges.is_empty() {
+            self.ranges.extend(ranges.iter().map(|&(size, hash)| HashedRange { size, hash }));
+        }
+    }
+
+    /// Hashes computed so far
+    pub fn known_ranges(&self) -> impl Iterator<Item = RangeDigest> + '_ {
+        self.ranges.iter().map(|r| (r.size, r.hash))
+    }
+
+    /// Forget computed hashes to free their memory. They'll be computed again if needed.
+    /// The error, if any, is kept, so that unreadable files stay unreadable.
+    pub fn clear(&mut self) {
+        self.ranges = SmallVec::new();
+        self.prefix = None;
+        self.quick = None;
+        self.streams = None;
+    }
+
+    /// Bytes read from the file by all comparisons so far. Comparisons of different files stop reading early,
+    /// and hashes that are already known aren't read again.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Failed reads of the file by all comparisons so far, including ones that succeeded when retried
+    pub fn read_errors(&self) -> u32 {
+        self.read_errors
+    }
+
+    /// The error that stopped hashing of this file
+    pub fn error(&self) -> Option<&FileError> {
+        self.error.as_ref()
+    }
+
+    /// The error that stopped hashing of this file, if it hasn't been taken before
+    pub fn take_unreported_error(&mut self) -> Option<&FileError> {
+        if self.error_reported {
+            return None;
+        }
+        self.error_reported = true;
+        self.error.as_ref()
+    }
+
+    /// Range that has been computed already, or the error that prevented computing it
+    #[inline]
+    fn range(&self, index: usize) -> Result<Option<&HashedRange>, io::Error> {
+        match (self.ranges.get(index), &self.error) {
+            (None, Some(err)) => Err(err.to_io_error()),
+            (range, _) => Ok(range),
+        }
+    }
+
+    /// Like `range`, but a range that doesn't match the chunk `size` (e.g. preloaded with other settings)
+    /// is dropped along with all ranges after it. So is a range of a file that was truncated while being hashed,
+    /// which is rare enough that reading it again doesn't matter.
+    fn range_sized(&mut self, index: usize, size: u64) -> Result<Option<&HashedRange>, io::Error> {
+        if self.ranges.get(index).map_or(false, |r| r.size != size) {
+            self.ranges.truncate(index);
+        }
+        self.range(index)
+    }
+
+    #[inline]
+    fn push(&mut self, range: Result<HashedRange, io::Error>, file: &LazyFile<'_, impl Read + ?Sized>) {
+        match range {
+            Ok(r) => self.ranges.push(r),
+            Err(err) => {
+                self.fail(&err, file);
+            },
+        }
+    }
+
+    /// Hashing can't continue past a failed range
+    fn fail(&mut self, err: &io::Error, file: &LazyFile<'_, impl Read + ?Sized>) -> io::Error {
+        let err = FileError::new(file.path(), err);
+        let io_err = err.to_io_error();
+        file.log_error(&err);
+        self.error = Some(err);
+        io_err
+    }
+
+    /// Like `fail`, for errors that don't come from reading the file
+    fn fail_path(&mut self, err: &io::Error, path: &Path, settings: &HashSettings) -> io::Error {
+        let err = FileError::new(path, err);
+        let io_err = err.to_io_error();
+        if let Some(log) = &settings.error_log {
+            log.record(err.clone());
+        }
+        self.error = Some(err);
+        io_err
+    }
+
+    /// Incremental comparison reading files lazily
+    pub fn compare(&mut self, other: &mut Hasher, size: u64, self_path: &Path, other_path: &Path, settings: &HashSettings) -> Result<Ordering, io::Error> {
+        self.compare_with_progress(other, size, self_path, other_path, settings, &mut |_, _| {})
+    }
+
+    /// Incremental comparison reading files lazily.
+    /// Calls `progress(bytes_compared, total)` after each range is compared, and once more at the end, also if the files
+    /// differ or fail to be read. When they're equal, `bytes_compared` is then `total`.
+    pub fn compare_with_progress(&mut self, other: &mut Hasher, size: u64, self_path: &Path, other_path: &Path, settings: &HashSettings, progress: &mut dyn FnMut(u64, u64)) -> Result<Ordering, io::Error> {
+        let mut iter = HashIter::new(size, self_path, other_path, settings);
+        let res = self.compare_iter(other, &mut iter, size, settings, progress);
+        progress(if matches!(res, Ok(Ordering::Equal)) { size } else { min(iter.chunks.start_offset, size) }, size);
+        self.bytes_read += iter.a_file.bytes_read();
+        other.bytes_read += iter.b_file.bytes_read();
+        self.read_errors += iter.a_file.read_errors();
+        other.read_errors += iter.b_file.read_errors();
+        res
+    }
+
+    fn compare_iter(&mut self, other: &mut Hasher, iter: &mut HashIter<'_>, size: u64, settings: &HashSettings, progress: &mut dyn FnMut(u64, u64)) -> Result<Ordering, io::Error> {
+        if settings.raw_prefix_size > 0 {
+            let ord = iter.prefix_compare(self, other)?;
+            if ord != Ordering::Equal {
+                return Ok(ord);
+            }
+        }
+
+        // Quick hash of xxh3 data would be redundant
+        if settings.quick_prefilter && settings.algorithm != HashAlgorithm::Xxh3 {
+            let ord = iter.quick_compare(self, other)?;
+            if ord != Ordering::Equal {
+                return Ok(ord);
+            }
+        }
+
+        if size < settings.small_file_threshold {
+            self.hash_whole(&mut iter.a_file, size, settings, &mut iter.buffer);
+            other.hash_whole(&mut iter.b_file, size, settings, &mut iter.buffer);
+        }
+
+        while let Some((a, b)) = iter.next(self, other)? {
+            let ord = a.cmp(b);
+            if ord != Ordering::Equal {
+                return Ok(ord);
+            }
+            // The last range is reported by the final call
+            if iter.chunks.start_offset < size {
+                progress(iter.chunks.start_offset, size);
+            }
+        }
+        Ok(Ordering::Equal)
+    }
+
+    /// Computes all missing ranges from a single read of the file.
+    /// Errors are stored like errors of incremental reads.
+    fn hash_whole(&mut self, file: &mut LazyFile<'_, impl Read + ?Sized>, size: u64, settings: &HashSettings, buffer: &mut ReadBuffer) {
+        if self.error.is_some() || self.ranges.iter().map(|r| r.size).sum::<u64>() >= size {
+            return;
+        }
+        let data = buffer.get(size);
+        // Out of memory budget, so it has to be read incrementally
+        if (data.len() as u64) < size {
+            return;
+        }
+        let data = match read_into(file, data) {
+            Ok(filled) => {
+                buffer.throttle(filled);
+                &buffer.data[..filled]
+            },
+            Err(err) => {
+                self.fail(&err, file);
+                return;
+            },
+        };
+        for (index, (start_offset, chunk_size)) in Chunks::new(size, settings).enumerate() {
+            if matches!(self.range_sized(index, chunk_size), Ok(Some(_))) {
+                continue;
+            }
+            // The file may have been truncated since it was stat'ed
+            let end = min(start_offset + chunk_size, data.len() as u64) as usize;
+            let start = min(start_offset as usize, end);
+            self.ranges.push(HashedRange::from_bytes(&data[start..end], settings));
+        }
+    }
+
+    /// Makes sure the range at `index` is computed
+    fn hash_chunk(&mut self, file: &mut LazyFile<'_, impl Read + ?Sized>, index: usize, start: u64, size: u64, settings: &HashSettings, buffer: &mut ReadBuffer) -> Result<(), io::Error> {
+        if self.range_sized(index, size)?.is_none() {
+            let (bytes_before, errors_before) = (file.bytes_read(), file.read_errors());
+            self.push(HashedRange::from_file(file, start, size, settings, buffer), file);
+            self.bytes_read += file.bytes_read() - bytes_before;
+            self.read_errors += file.read_errors() - errors_before;
+            self.range(index)?;
+        }
+        Ok(())
+    }
+
+    /// Hash of the first chunk of the file, reusing it if it's already computed. Empty files have a zeroed hash.
+    pub fn first_chunk_hash(&mut self, size: u64, path: &Path, settings: &HashSettings) -> Result<[u8; 32], io::Error> {
+        let (start, chunk_size) = match Chunks::new(size, settings).next() {
+            Some(chunk) => chunk,
+            None => return Ok([0; 32]),
+        };
+        let mut file = LazyFile::new(path, settings).with_expected_size(size);
+        self.hash_chunk(&mut file, 0, start, chunk_size, settings, &mut ReadBuffer::new(settings))?;
+        Ok(self.ranges[0].hash)
+    }
+
+    /// Compares the file with a stream, chunk by chunk, reading the stream only forward.
+    /// A stream that ends before the file orders before it, like a smaller file would, and a longer one after it.
+    /// Raw prefix and quick prefilters don't apply, so the order may differ from comparisons of two files.
+    pub fn compare_with_reader(&mut self, size: u64, path: &Path, reader: &mut dyn Read, settings: &HashSettings) -> Result<Ordering, io::Error> {
+        let mut file = LazyFile::new(path, settings).with_expected_size(size);
+        let mut stream = LazyFile::from_reader(Path::new("<stream>"), reader, settings);
+        let mut buffer = ReadBuffer::new(settings);
+        for (index, (start_offset, chunk_size)) in Chunks::new(size, settings).enumerate() {
+            self.hash_chunk(&mut file, index, start_offset, chunk_size, settings, &mut buffer)?;
+            let stream_range = HashedRange::from_file(&mut stream, start_offset, chunk_size, settings, &mut buffer)?;
+            if stream_range.size < chunk_size {
+                return Ok(Ordering::Greater);
+            }
+            let ord = self.ranges[index].cmp(&stream_range);
+            if ord != Ordering::Equal {
+                return Ok(ord);
+            }
+        }
+
+        // The stream must end where the file does
+        if HashedRange::from_file(&mut stream, size, 1, settings, &mut buffer)?.size > 0 {
+            return Ok(Ordering::Less);
+        }
+        Ok(Ordering::Equal)
+    }
+
+    /// Alternate data streams of the file, computed once. Failure makes the whole file unreadable.
+    pub(crate) fn streams(&mut self, path: &Path, settings: &HashSettings) -> Result<&[StreamDigest], io::Error> {
+        if let Some(err) = &self.error {
+            return Err(err.to_io_error());
+        }
+        if self.streams.is_none() {
+            let streams = streams::named_streams(path).and_then(|streams| {
+                streams.into_iter().map(|(name, size)| {
+                    let digest = Hasher::new().digest(size, &streams::stream_path(path, &name), settings)?;
+                    Ok((name, size, digest))
+                }).collect::<Result<Vec<_>, io::Error>>()
+            });
+            match streams {
+                Ok(streams) => self.streams = Some(streams),
+                Err(err) => return Err(self.fail_path(&err, path, settings)),
+            }
+        }
+        Ok(self.streams.as_deref().unwrap_or_default())
+    }
+
+    /// Hash of only the first `prefix_len` bytes of the file, for cheap grouping of candidates.
+    /// Files with different quick hashes can't be equal. Files with the same one still need to be compared.
+    /// It's computed with the algorithm and digest length of the settings, so only hashes computed with the same settings can be compared.
+    pub fn quick_hash(path: &Path, prefix_len: u64, settings: &HashSettings) -> Result<[u8; 20], io::Error> {
+        let range = HashedRange::from_file(&mut LazyFile::new(path, settings), 0, prefix_len, settings, &mut ReadBuffer::new(settings))
+            .map_err(|err| FileError::new(path, &err).to_io_error())?;
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&range.hash[..20]);
+        Ok(hash)
+    }
+
+    /// Digest of the whole file, computed from the same ranges as used by `compare`.
+    /// Files that compare as equal have equal digests.
+    pub fn digest(&mut self, size: u64, path: &Path, settings: &HashSettings) -> Result<[u8; 20], io::Error> {
+        let mut file = LazyFile::new(path, settings).with_expected_size(size);
+        let mut buffer = ReadBuffer::new(settings);
+        let mut index = 0;
+        for (start_offset, chunk_size) in Chunks::new(size, settings) {
+            self.hash_chunk(&mut file, index, start_offset, chunk_size, settings, &mut buffer)?;
+            index += 1;
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        for range in &self.ranges[..index] {
+            hasher.update(&range.size.to_le_bytes());
+            hasher.update(&range.hash);
+        }
+        let mut digest = [0; 20];
+        digest.copy_from_slice(&blake3::Hasher::finalize(&hasher).as_bytes()[..20]);
+        Ok(digest)
+    }
+}
+
+fn new_hasher<H: ContentHasher>(settings: &HashSettings) -> H {
+    match &settings.key {
+        Some(key) => H::new_keyed(key),
+        None => H::new(),
+    }
+}
+
+fn hash_bytes<H: ContentHasher>(data: &[u8], settings: &HashSettings) -> [u8; 32] {
+    let mut hasher = new_hasher::<H>(settings);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Hashes `size` bytes read from `start`, or fewer if the file ends earlier. Returns the number of bytes hashed.
+fn read_range(file: &mut LazyFile<'_, impl Read + ?Sized>, hasher: &mut impl ContentHasher, start: u64, size: u64, buffer: &mut ReadBuffer) -> Result<u64, io::Error> {
+    let mut pos = start;
+    let end = start + size;
+    while pos < end {
+        let data = buffer.get(end - pos);
+        match file.read_at(pos, data) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update_large(&data[0..n]);
+                pos += n as u64;
+                buffer.throttle(n);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos - start)
+}
+
+/// Hashes what a hole in a sparse file would read as
+fn hash_zeros(hasher: &mut impl ContentHasher, mut size: u64) {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    while size > 0 {
+        let n = min(size, ZEROS.len() as u64);
+        hasher.update(&ZEROS[..n as usize]);
+        size -= n;
+    }
+}
+
+/// Reads up to `len` bytes from the start of the file
+fn read_prefix(file: &mut LazyFile<'_, impl Read + ?Sized>, len: usize) -> Result<Box<[u8]>, io::Error> {
+    let mut prefix = vec![0; len];
+    let filled = read_into(file, &mut prefix)?;
+    prefix.truncate(filled);
+    Ok(prefix.into_boxed_slice())
+}
+
+/// Fills the buffer from the start of the file. Returns fewer bytes only if the file is shorter.
+fn read_into(file: &mut LazyFile<'_, impl Read + ?Sized>, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(filled as u64, &mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Partitions files of the same `size` into groups of equal content, computing each range of each file at most once.
+/// Unreadable files end up in groups of their own. Groups are sorted, and ordered by their first index.
+pub fn group_equal(files: &mut [(&mut Hasher, &Path)], size: u64, settings: &HashSettings) -> Vec<Vec<usize>> {
+    let mut done = Vec::new();
+    let mut buffer = ReadBuffer::new(settings);
+    let mut groups = vec![(0..files.len()).collect::<Vec<_>>()];
+    for (index, (start, chunk_size)) in Chunks::new(size, settings).enumerate() {
+        let mut next_groups = Vec::new();
+        for group in groups {
+            let mut by_hash = BTreeMap::<[u8; 32], Vec<usize>>::new();
+            for i in group {
+                let (hasher, path) = &mut files[i];
+                match hasher.hash_chunk(&mut LazyFile::new(path, settings).with_expected_size(size), index, start, chunk_size, settings, &mut buffer) {
+                    Ok(()) => by_hash.entry(hasher.ranges[index].hash).or_default().push(i),
+                    Err(_) => done.push(vec![i]),
+                }
+            }
+            for (_, group) in by_hash {
+                if group.len() > 1 {
+                    next_groups.push(group);
+                } else {
+                    done.push(group);
+                }
+            }
+        }
+        groups = next_groups;
+        if groups.is_empty() {
+            break;
+        }
+    }
+    done.extend(groups);
+    done.sort_unstable();
+    done
+}
+
+/// Size and digest of a content-defined chunk
+pub type ChunkDigest = (u64, [u8; 20]);
+
+/// Random values for the rolling hash, generated with splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Splits the file into chunks of about `average_size` bytes at boundaries chosen by the content (a rolling hash),
+/// so that data shifted by insertions or deletions still produces the same chunks. Unlike ranges used for comparisons,
+/// these can find files that share large parts. See `shared_bytes`.
+pub fn content_chunks(path: &Path, average_size: u64) -> Result<Vec<ChunkDigest>, io::Error> {
+    let average_size = average_size.max(64).next_power_of_two();
+    // The high bits depend on the last 64 bytes, the low ones only on the last few, as in FastCDC
+    let mask = !(u64::MAX >> average_size.trailing_zeros());
+    let min_size = average_size / 4;
+    let max_size = average_size * 4;
+
+    let mut file = LazyFile::new(path, &HashSettings::default());
+    let mut buffer = vec![0; 64 * 1024];
+    let mut chunks = Vec::new();
+    let mut hasher = blake3::Hasher::new();
+    let mut chunk_size = 0;
+    let mut rolling = 0u64;
+    let mut pos = 0;
+    let mut finish = |hasher: &mut blake3::Hasher, chunk_size: &mut u64| {
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&blake3::Hasher::finalize(hasher).as_bytes()[..20]);
+        chunks.push((*chunk_size, hash));
+        *hasher = blake3::Hasher::new();
+        *chunk_size = 0;
+    };
+    loop {
+        let n = match file.read_at(pos, &mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => return Err(FileError::new(path, &e).to_io_error()),
+        };
+        pos += n as u64;
+        let mut chunk_start = 0;
+        for (i, &byte) in buffer[..n].iter().enumerate() {
+            rolling = (rolling << 1).wrapping_add(GEAR[byte as usize]);
+            chunk_size += 1;
+            if (chunk_size >= min_size && rolling & mask == 0) || chunk_size >= max_size {
+                hasher.update(&buffer[chunk_start..=i]);
+                chunk_start = i + 1;
+                finish(&mut hasher, &mut chunk_size);
+                rolling = 0;
+            }
+        }
+        hasher.update(&buffer[chunk_start..n]);
+    }
+    if chunk_size > 0 {
+        finish(&mut hasher, &mut chunk_size);
+    }
+    Ok(chunks)
+}
+
+/// Estimate of how many bytes of content the two files have in common, from their `content_chunks`
+pub fn shared_bytes(a: &[ChunkDigest], b: &[ChunkDigest]) -> u64 {
+    let mut counts = std::collections::HashMap::<&ChunkDigest, usize>::new();
+    for chunk in a {
+        *counts.entry(chunk).or_default() += 1;
+    }
+    let mut shared = 0;
+    for chunk in b {
+        if let Some(count) = counts.get_mut(chunk).filter(|c| **c > 0) {
+            *count -= 1;
+            shared += chunk.0;
+        }
+    }
+    shared
+}
+
+/// Stable identifier of the file's content: hex of its digest with default settings.
+/// The same bytes have the same fingerprint on any machine, so fingerprints can be stored and matched later.
+pub fn fingerprint_file(path: &Path) -> Result<String, io::Error> {
+    Ok(to_hex(&hash_file(path)?))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(hex, "{:02x}", b);
+    }
+    hex
+}
+
+/// Digest of the file's content, using default settings. See `FileContent::digest`.
+pub fn hash_file(path: &Path) -> Result<[u8; 20], io::Error> {
+    let size = fs::metadata(path)?.len();
+    Hasher::new().digest(size, path, &HashSettings::default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+
+    #[test]
+    fn range_hash() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let path = &tmp.path().join("a");
+        fs::write(path, "aaa\n").expect("write");
+        let mut file = LazyFile::new(path, &HashSettings::default());
+        let mut buffer = ReadBuffer::default();
+        let hashed = HashedRange::from_file(&mut file, 0, 4, &HashSettings::default(), &mut buffer).expect("hash");
+
+        assert_eq!(4, hashed.size);
+        assert_eq!([22, 179, 164, 66, 194, 34, 185, 88, 69, 62, 115, 203, 129, 138, 81, 160, 96, 190, 209, 11], hashed.hash[..20]);
+        assert_eq!([0; 12], hashed.hash[20..]);
+
+        let full = HashSettings {
+            digest_len: 32,
+            ..HashSettings::default()
+        };
+        let hashed = HashedRange::from_file(&mut file, 0, 4, &full, &mut buffer).expect("hash");
+        assert_eq!([22, 179, 164, 66, 194, 34, 185, 88, 69, 62, 115, 203, 129, 138, 81, 160, 96, 190, 209, 11, 155, 246, 100, 159, 43, 187, 67, 169, 229, 123, 255, 120], hashed.hash);
+
+        let hashed = HashedRange::from_file(&mut file, 1, 2, &HashSettings::default(), &mut buffer).expect("hash2");
+        assert_eq!(2, hashed.size);
+        assert_eq!(4, buffer.data.len(), "buffer is reused, not shrunk");
+    }
+
+    #[test]
+    fn other_algorithms_compare() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        fs::write(a_path, "same\n").expect("write");
+        fs::write(b_path, "same\n").expect("write");
+        fs::write(c_path, "diff\n").expect("write");
+
+        for algorithm in [HashAlgorithm::Xxh3, HashAlgorithm::Sha256] {
+            let settings = HashSettings {
+                algorithm,
+                ..HashSettings::default()
+            };
+            let mut a = Hasher::new();
+            let mut b = Hasher::new();
+            let mut c = Hasher::new();
+            assert_eq!(Ordering::Equal, a.compare(&mut b, 5, a_path, b_path, &settings).expect("cmp"));
+            assert_ne!(Ordering::Equal, a.compare(&mut c, 5, a_path, c_path, &settings).expect("cmp"));
+        }
+
+        // Digests are the standard SHA-256 ones
+        let settings = HashSettings { algorithm: HashAlgorithm::Sha256, ..HashSettings::default() };
+        let hashed = HashedRange::from_bytes(b"abc", &settings);
+        assert_eq!([0xba, 0x78, 0x16, 0xbf], hashed.hash[..4]);
+    }
+
+    #[test]
+    fn initial_buffer_larger_than_file() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        fs::write(a_path, "aaa\n").expect("write");
+        fs::write(b_path, "aaa\n").expect("write");
+
+        let settings = HashSettings {
+            initial_buffer_size: 1 << 20,
+            ..HashSettings::default()
+        };
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 4, a_path, b_path, &settings).expect("cmp"));
+        assert_eq!(1, a.ranges.len());
+        assert_eq!(4, a.ranges[0].size);
+    }
+
+    #[test]
+    fn max_buffer_smaller_than_initial() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        fs::write(a_path, vec![1u8; 10000]).expect("write");
+        fs::write(b_path, vec![1u8; 10000]).expect("write");
+
+        let settings = HashSettings {
+            initial_buffer_size: 4096,
+            max_buffer_size: 1024,
+            ..HashSettings::default()
+        };
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 10000, a_path, b_path, &settings).expect("cmp"));
+        let sizes: Vec<_> = a.ranges.iter().map(|r| r.size).collect();
+        assert_eq!(vec![4096, 4096, 1808], sizes);
+    }
+
+    #[test]
+    fn growth_factor_below_two() {
+        for growth_factor in [0, 1] {
+            let settings = HashSettings {
+                initial_buffer_size: 1000,
+                max_buffer_size: 100_000,
+                growth_factor,
+                ..HashSettings::default()
+            };
+            // Grows anyway, until it reaches the maximum
+            let mut size = settings.initial_buffer_size;
+            let mut sizes = vec![size];
+            while size < settings.max_buffer_size {
+                let next = settings.next_buffer_size(size);
+                assert!(next > size);
+                size = next;
+                sizes.push(size);
+            }
+            assert_eq!(vec![1000, 2000, 4000, 8000, 16000, 32000, 64000, 100_000], sizes);
+            assert_eq!(settings.max_buffer_size, settings.next_buffer_size(size));
+        }
+    }
+
+    #[test]
+    fn quick_prefilter() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        let mut content = vec![7u8; 10000];
+        fs::write(a_path, &content).expect("write");
+        fs::write(b_path, &content).expect("write");
+        content[9999] = 0;
+        fs::write(c_path, &content).expect("write");
+
+        let settings = HashSettings {
+            quick_prefilter: true,
+            ..HashSettings::default()
+        };
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let mut c = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 10000, a_path, b_path, &settings).expect("cmp"));
+        // Same early bytes, so the quick hash matches and the difference must be found by the strong hash
+        assert_ne!(Ordering::Equal, a.compare(&mut c, 10000, a_path, c_path, &settings).expect("cmp"));
+        assert_eq!(a.quick, c.quick);
+        assert!(c.ranges.len() > 1);
+
+        content[0] = 0;
+        fs::write(c_path, &content).expect("write");
+        let mut d = Hasher::new();
+        assert_ne!(Ordering::Equal, a.compare(&mut d, 10000, a_path, c_path, &settings).expect("cmp"));
+        assert!(d.ranges.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn mmap_matches_read() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let path = &tmp.path().join("a");
+        fs::write(path, vec![3u8; 10000]).expect("write");
+
+        let mapped = HashSettings {
+            mmap_threshold: 0,
+            ..HashSettings::default()
+        };
+        let mut buffer = ReadBuffer::default();
+        let mut file = LazyFile::new(path, &HashSettings::default());
+        let read = HashedRange::from_file(&mut file, 100, 5000, &HashSettings::default(), &mut buffer).expect("hash");
+        assert_eq!(read, HashedRange::from_file(&mut file, 100, 5000, &mapped, &mut buffer).expect("hash"));
+
+        // Truncated file must not be mapped past its end
+        let read = HashedRange::from_file(&mut file, 9000, 5000, &HashSettings::default(), &mut buffer).expect("hash");
+        assert_eq!(read, HashedRange::from_file(&mut file, 9000, 5000, &mapped, &mut buffer).expect("hash"));
+        let read = HashedRange::from_file(&mut file, 20000, 5000, &HashSettings::default(), &mut buffer).expect("hash");
+        assert_eq!(read, HashedRange::from_file(&mut file, 20000, 5000, &mapped, &mut buffer).expect("hash"));
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn mapped_file() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let mut content = vec![3u8; 100_000];
+        fs::write(a_path, &content).expect("write");
+        content[99_999] = 0;
+        fs::write(b_path, &content).expect("write");
+
+        let mapped = HashSettings {
+            mmap_file_threshold: 1000,
+            raw_prefix_size: 100,
+            ..HashSettings::default()
+        };
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_ne!(Ordering::Equal, a.compare(&mut b, 100_000, a_path, b_path, &mapped).expect("cmp"));
+        assert_eq!(100_100, a.bytes_read());
+        let settings = HashSettings { raw_prefix_size: 100, ..HashSettings::default() };
+        assert_eq!(a.digest(100_000, a_path, &mapped).expect("digest"), Hasher::new().digest(100_000, a_path, &settings).expect("digest"));
+
+        let mut file = LazyFile::new(a_path, &mapped).with_expected_size(100_000);
+        assert!(file.mapped().expect("map").is_some());
+        let mut buf = [0; 10];
+        assert_eq!(10, file.read_at(99_990, &mut buf).expect("read"));
+        assert_eq!(5, file.read_at(99_995, &mut buf).expect("read"));
+        assert_eq!(0, file.read_at(100_000, &mut buf).expect("read"));
+
+        // Too small to map
+        let mut file = LazyFile::new(a_path, &HashSettings { mmap_file_threshold: 200_000, ..HashSettings::default() });
+        assert!(file.mapped().expect("map").is_none());
+
+        // Size changed since stat
+        let mut file = LazyFile::new(a_path, &mapped).with_expected_size(50_000);
+        file.mapped().unwrap_err();
+
+        // Truncated after it's been mapped. Windows doesn't allow that.
+        #[cfg(unix)]
+        {
+            let mut file = LazyFile::new(a_path, &mapped).with_expected_size(100_000);
+            assert!(file.mapped().expect("map").is_some());
+            fs::OpenOptions::new().write(true).open(a_path).expect("open").set_len(50_000).expect("truncate");
+            // Reads fall back to `read`, which tells the file has changed
+            let err = file.read_at(60_000, &mut buf).unwrap_err();
+            assert!(err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).expect("file error").changed);
+            assert!(file.mapped().expect("map").is_none());
+        }
+    }
+
+    #[test]
+    fn parallel_reads() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        let mut content = vec![9u8; 3_000_000];
+        fs::write(a_path, &content).expect("write");
+        fs::write(b_path, &content).expect("write");
+        content[2_999_999] = 0;
+        fs::write(c_path, &content).expect("write");
+
+        let settings = HashSettings {
+            parallel_reads: true,
+            ..HashSettings::default()
+        };
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let mut c = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 3_000_000, a_path, b_path, &settings).expect("cmp"));
+        let mut a2 = Hasher::new();
+        assert_ne!(Ordering::Equal, a2.compare(&mut c, 3_000_000, a_path, c_path, &settings).expect("cmp"));
+        assert_eq!(a.ranges, a2.ranges);
+    }
+
+    #[test]
+    fn compare_progress() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        fs::write(a_path, vec![1u8; 100_000]).expect("write");
+        fs::write(b_path, vec![1u8; 100_000]).expect("write");
+
+        let mut calls = Vec::new();
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let ord = a.compare_with_progress(&mut b, 100_000, a_path, b_path, &HashSettings::default(), &mut |done, total| calls.push((done, total)));
+        assert_eq!(Ordering::Equal, ord.expect("cmp"));
+        assert_eq!(vec![(2048, 100_000), (2048 + 32768, 100_000), (100_000, 100_000)], calls);
+
+        // Files that differ get the final call too
+        fs::write(b_path, vec![2u8; 100_000]).expect("write");
+        let mut calls = Vec::new();
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let ord = a.compare_with_progress(&mut b, 100_000, a_path, b_path, &HashSettings::default(), &mut |done, total| calls.push((done, total)));
+        assert_ne!(Ordering::Equal, ord.expect("cmp"));
+        assert_eq!(Some(&(2048, 100_000)), calls.last());
+    }
+
+    #[test]
+    fn error_has_path() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let missing = &tmp.path().join("missing");
+        fs::write(a_path, "aaa\n").expect("write");
+
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let err = a.compare(&mut b, 4, a_path, missing, &HashSettings::default()).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        let err = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).expect("file error");
+        assert_eq!(&**missing, &*err.path);
+
+        // The error is remembered
+        let err = a.compare(&mut b, 4, a_path, missing, &HashSettings::default()).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn raw_prefix() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        let d_path = &tmp.path().join("d");
+        fs::write(a_path, "aaaa\n").expect("write");
+        fs::write(b_path, "aaaa\n").expect("write");
+        fs::write(c_path, "aaab\n").expect("write");
+        fs::write(d_path, "baaa\n").expect("write");
+
+        let settings = HashSettings {
+            raw_prefix_size: 4,
+            ..HashSettings::default()
+        };
+        for (path, expected) in [(b_path, Ordering::Equal), (c_path, Ordering::Less), (d_path, Ordering::Less)] {
+            let mut a = Hasher::new();
+            let mut other = Hasher::new();
+            assert_eq!(expected, a.compare(&mut other, 5, a_path, path, &settings).expect("cmp"));
+            // With the default settings the order may differ, but equality must not
+            let mut a = Hasher::new();
+            let mut other = Hasher::new();
+            assert_eq!(expected == Ordering::Equal, a.compare(&mut other, 5, a_path, path, &HashSettings::default()).expect("cmp") == Ordering::Equal);
+        }
+
+        // Differing prefix doesn't need hashing
+        let mut a = Hasher::new();
+        let mut d = Hasher::new();
+        a.compare(&mut d, 5, a_path, d_path, &settings).expect("cmp");
+        assert!(d.ranges.is_empty());
+
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 0, a_path, b_path, &settings).expect("cmp"));
+    }
+
+    #[test]
+    fn sparse_equals_dense() {
+        use std::io::Write;
+
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let sparse_path = &tmp.path().join("sparse");
+        let dense_path = &tmp.path().join("dense");
+        let size = 3 * 1024 * 1024;
+        let mut dense = vec![0u8; size];
+        dense[1000] = 1;
+        dense[size - 1] = 2;
+        fs::write(dense_path, &dense).expect("write");
+
+        let mut file = fs::File::create(sparse_path).expect("create");
+        file.set_len(size as u64).expect("len");
+        file.seek(SeekFrom::Start(1000)).expect("seek");
+        file.write_all(&[1]).expect("write");
+        file.seek(SeekFrom::Start(size as u64 - 1)).expect("seek");
+        file.write_all(&[2]).expect("write");
+        drop(file);
+
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let settings = HashSettings::default();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, size as u64, sparse_path, dense_path, &settings).expect("cmp"));
+        assert_eq!(a.ranges, b.ranges);
+    }
+
+    #[test]
+    fn out_of_order_reads() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let path = &tmp.path().join("a");
+        let data: Vec<u8> = (0..100u8).collect();
+        fs::write(path, &data).expect("write");
+
+        let settings = HashSettings::default();
+        let mut buffer = ReadBuffer::default();
+        let mut file = LazyFile::new(path, &HashSettings::default());
+        let tail = HashedRange::from_file(&mut file, 50, 50, &settings, &mut buffer).expect("hash");
+        let head = HashedRange::from_file(&mut file, 0, 50, &settings, &mut buffer).expect("hash");
+        let next = HashedRange::from_file(&mut file, 50, 50, &settings, &mut buffer).expect("hash");
+        assert_eq!(tail, next);
+        assert_eq!(&head.hash[..20], &blake3::hash(&data[..50]).as_bytes()[..20]);
+        assert_eq!(&tail.hash[..20], &blake3::hash(&data[50..]).as_bytes()[..20]);
+    }
+
+    #[test]
+    fn keyed() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        fs::write(a_path, "hello").expect("write");
+        fs::write(b_path, "hello").expect("write");
+
+        let settings = HashSettings {
+            key: Some(HashSettings::random_key()),
+            ..HashSettings::default()
+        };
+        assert_ne!(settings.key, Some(HashSettings::random_key()));
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 5, a_path, b_path, &settings).expect("cmp"));
+        let keyed = a.digest(5, a_path, &settings).expect("digest");
+        assert_ne!(keyed, Hasher::new().digest(5, a_path, &HashSettings::default()).expect("digest"));
+    }
+
+    #[test]
+    fn compare_with_reader() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let path = &tmp.path().join("a");
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        fs::write(path, &data).expect("write");
+        let size = data.len() as u64;
+        let settings = HashSettings::default();
+
+        let mut hasher = Hasher::new();
+        assert_eq!(Ordering::Equal, hasher.compare_with_reader(size, path, &mut &data[..], &settings).expect("cmp"));
+        // Ranges computed for the first stream are reused
+        assert_eq!(Ordering::Equal, hasher.compare_with_reader(size, path, &mut &data[..], &settings).expect("cmp"));
+        assert_eq!(Ordering::Greater, hasher.compare_with_reader(size, path, &mut &data[..99_999], &settings).expect("cmp"));
+        assert_eq!(Ordering::Greater, hasher.compare_with_reader(size, path, &mut &data[..10], &settings).expect("cmp"));
+        let mut longer = data.clone();
+        longer.push(0);
+        assert_eq!(Ordering::Less, hasher.compare_with_reader(size, path, &mut &longer[..], &settings).expect("cmp"));
+        let mut different = data.clone();
+        different[50_000] ^= 1;
+        assert_ne!(Ordering::Equal, hasher.compare_with_reader(size, path, &mut &different[..], &settings).expect("cmp"));
+        // Readers don't have to be `Send`
+        let mut reader = io::Cursor::new(std::rc::Rc::<[u8]>::from(&data[..]));
+        assert_eq!(Ordering::Equal, hasher.compare_with_reader(size, path, &mut reader, &settings).expect("cmp"));
+
+        let empty = &tmp.path().join("empty");
+        fs::write(empty, "").expect("write");
+        assert_eq!(Ordering::Equal, Hasher::new().compare_with_reader(0, empty, &mut io::empty(), &settings).expect("cmp"));
+        assert_eq!(Ordering::Less, Hasher::new().compare_with_reader(0, empty, &mut &data[..], &settings).expect("cmp"));
+    }
+
+    #[test]
+    fn small_files_in_one_read() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let incremental = HashSettings {
+            small_file_threshold: 0,
+            ..HashSettings::default()
+        };
+        let whole = HashSettings::default();
+
+        let data: Vec<u8> = (0..60_000u32).map(|i| i as u8).collect();
+        fs::write(a_path, &data).expect("write");
+        for diff_at in [None, Some(0), Some(3000), Some(59_999)] {
+            let mut b_data = data.clone();
+            if let Some(i) = diff_at {
+                b_data[i] ^= 1;
+            }
+            fs::write(b_path, &b_data).expect("write");
+
+            let (mut a1, mut b1, mut a2, mut b2) = (Hasher::new(), Hasher::new(), Hasher::new(), Hasher::new());
+            let size = data.len() as u64;
+            let expected = a1.compare(&mut b1, size, a_path, b_path, &incremental).expect("cmp");
+            assert_eq!(expected, a2.compare(&mut b2, size, a_path, b_path, &whole).expect("cmp"));
+            assert_eq!(a1.ranges[..], a2.ranges[..a1.ranges.len()]);
+            assert_eq!(a2.digest(size, a_path, &whole).expect("digest"), a1.digest(size, a_path, &incremental).expect("digest"));
+        }
+    }
+
+    #[test]
+    fn empty_files() {
+        let settings = HashSettings::default();
+        assert_eq!(settings.initial_buffer_size, settings.next_buffer_size(0));
+        assert_eq!(settings.initial_buffer_size, settings.next_buffer_size(1));
+
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        fs::write(a_path, "").expect("write");
+        fs::write(b_path, "").expect("write");
+        fs::write(c_path, "c").expect("write");
+
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 0, a_path, b_path, &settings).expect("cmp"));
+        assert_eq!(a.digest(0, a_path, &settings).expect("digest"), b.digest(0, b_path, &settings).expect("digest"));
+
+        // Stat said the size is 1, but the empty file has nothing
+        for settings in [HashSettings { small_file_threshold: 0, ..HashSettings::default() }, settings] {
+            let mut a = Hasher::new();
+            let mut c = Hasher::new();
+            a.compare(&mut c, 1, a_path, c_path, &settings).unwrap_err();
+            assert!(a.error().expect("changed").changed);
+            assert!(c.error().is_none());
+        }
+    }
+
+    #[test]
+    fn ranges_reused_across_comparisons() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        let mut content = vec![7u8; 1_000_000];
+        fs::write(a_path, &content).expect("write");
+        fs::write(b_path, &content).expect("write");
+        content[999_999] = 0;
+        fs::write(c_path, &content).expect("write");
+
+        let settings = HashSettings::default();
+        let mut a = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut Hasher::new(), 1_000_000, a_path, b_path, &settings).expect("cmp"));
+        let ranges = a.ranges.clone();
+
+        // All of A's ranges are known, so it's not read again
+        fs::remove_file(a_path).expect("rm");
+        let mut c = Hasher::new();
+        assert_ne!(Ordering::Equal, a.compare(&mut c, 1_000_000, a_path, c_path, &settings).expect("cmp"));
+        assert_eq!(ranges, a.ranges);
+        assert_eq!(a.ranges.len(), c.ranges.len());
+        assert_eq!(a.ranges.iter().map(|r| r.size).collect::<Vec<_>>(), c.ranges.iter().map(|r| r.size).collect::<Vec<_>>());
+
+        // Ranges of a different schedule are recomputed
+        let mut stale = Hasher::new();
+        stale.preload(&[(1, [0; 32])]);
+        let mut c2 = Hasher::new();
+        assert_eq!(Ordering::Equal, stale.compare(&mut c2, 1_000_000, c_path, c_path, &settings).expect("cmp"));
+        assert_eq!(c.ranges[0], stale.ranges[0]);
+    }
+
+    #[test]
+    fn large_range() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let path = &tmp.path().join("a");
+        let data: Vec<u8> = (0..5_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(path, &data).expect("write");
+
+        // Same result whether or not it's hashed on multiple threads
+        let settings = HashSettings {
+            digest_len: 32,
+            ..HashSettings::default()
+        };
+        let range = HashedRange::from_file(&mut LazyFile::new(path, &HashSettings::default()), 0, 5_000_000, &settings, &mut ReadBuffer::default()).expect("hash");
+        assert_eq!(blake3::hash(&data).as_bytes(), &range.hash);
+    }
+
+    #[test]
+    fn buffer_budget() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(a_path, &data).expect("write");
+        fs::write(b_path, &data).expect("write");
+
+        let mut a = Hasher::new();
+        assert_eq!(Ordering::Equal, a.compare(&mut Hasher::new(), 3_000_000, a_path, b_path, &HashSettings::default()).expect("cmp"));
+
+        for limit in [0, 100_000, 1 << 30] {
+            let budget = Arc::new(BufferBudget::new(limit));
+            let settings = HashSettings {
+                buffer_budget: Some(budget.clone()),
+                parallel_reads: true,
+                ..HashSettings::default()
+            };
+            let mut a2 = Hasher::new();
+            assert_eq!(Ordering::Equal, a2.compare(&mut Hasher::new(), 3_000_000, a_path, b_path, &settings).expect("cmp"));
+            assert_eq!(a.ranges, a2.ranges);
+            assert_eq!(limit, budget.available());
+
+            let mut buffer = ReadBuffer::new(&settings);
+            assert!(buffer.get(1 << 20).len() as u64 <= MIN_BUDGETED_BUFFER_SIZE + limit);
+            assert_eq!(limit.saturating_sub((1 << 20) - MIN_BUDGETED_BUFFER_SIZE), budget.available());
+        }
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let mut buffer = ReadBuffer::default();
+        let ptr = buffer.get(5000).as_ptr();
+        drop(buffer);
+        // Smaller requests can reuse a larger buffer
+        let mut buffer = ReadBuffer::default();
+        assert_eq!(ptr, buffer.get(3000).as_ptr());
+        assert_eq!(3000, buffer.get(3000).len());
+
+        // Growing gives back the smaller one
+        assert_ne!(ptr, buffer.get(20000).as_ptr());
+        assert_eq!(ptr, ReadBuffer::default().get(4096).as_ptr());
+    }
+
+    #[test]
+    fn default_digests() {
+        assert!(HashSettings::default().has_default_digests());
+        // Doesn't affect hashes
+        assert!(HashSettings { parallel_reads: true, quick_prefilter: true, ..HashSettings::default() }.has_default_digests());
+        assert!(!HashSettings { algorithm: HashAlgorithm::Xxh3, ..HashSettings::default() }.has_default_digests());
+        assert!(!HashSettings { key: Some([0; 32]), ..HashSettings::default() }.has_default_digests());
+        assert!(!HashSettings { growth_factor: 4, ..HashSettings::default() }.has_default_digests());
+        assert_eq!("00ff10", to_hex(&[0, 255, 16]));
+    }
+
+    #[test]
+    fn bytes_read() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        let mut content = vec![5u8; 1_000_000];
+        fs::write(a_path, &content).expect("write");
+        content[0] = 0;
+        fs::write(b_path, &content).expect("write");
+        fs::write(c_path, &content).expect("write");
+
+        let settings = HashSettings::default();
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        assert_ne!(Ordering::Equal, a.compare(&mut b, 1_000_000, a_path, b_path, &settings).expect("cmp"));
+        // Only the first chunk was needed
+        assert_eq!(settings.initial_buffer_size, a.bytes_read());
+        assert_eq!(settings.initial_buffer_size, b.bytes_read());
+
+        let mut c = Hasher::new();
+        assert_eq!(Ordering::Equal, b.compare(&mut c, 1_000_000, b_path, c_path, &settings).expect("cmp"));
+        assert_eq!(1_000_000, b.bytes_read());
+        assert_eq!(1_000_000, c.bytes_read());
+        b.digest(1_000_000, b_path, &settings).expect("digest");
+        assert_eq!(1_000_000, b.bytes_read());
+    }
+
+    #[test]
+    fn cancel() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let content = vec![5u8; 1_000_000];
+        fs::write(a_path, &content).expect("write");
+        fs::write(b_path, &content).expect("write");
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let settings = HashSettings {
+            cancel: Some(cancel.clone()),
+            ..HashSettings::default()
+        };
+        let mut a = Hasher::new();
+        let mut b = Hasher::new();
+        let mut ranges = 0;
+        let err = a.compare_with_progress(&mut b, 1_000_000, a_path, b_path, &settings, &mut |_, _| {
+            ranges += 1;
+            if ranges == 2 {
+                cancel.store(true, AtomicOrdering::Relaxed);
+            }
+        }).unwrap_err();
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+        assert!(a.error().is_none());
+        assert_eq!(2, a.ranges.len());
+
+        cancel.store(false, AtomicOrdering::Relaxed);
+        let read = a.bytes_read();
+        assert_eq!(Ordering::Equal, a.compare(&mut b, 1_000_000, a_path, b_path, &settings).expect("cmp"));
+        assert_eq!(1_000_000, a.bytes_read());
+        assert!(read < a.bytes_read());
+    }
+
+    #[test]
+    fn content_defined_chunks() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..300_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+        fs::write(a_path, &data).expect("write");
+        let mut shifted = b"inserted at the start".to_vec();
+        shifted.extend_from_slice(&data);
+        fs::write(b_path, &shifted).expect("write");
+
+        let a = content_chunks(a_path, 4096).expect("chunks");
+        let b = content_chunks(b_path, 4096).expect("chunks");
+        assert_eq!(300_000, a.iter().map(|c| c.0).sum::<u64>());
+        assert!(a.len() > 20);
+        assert_eq!(300_000, shared_bytes(&a, &a));
+        let shared = shared_bytes(&a, &b);
+        assert!(shared > 250_000, "{}", shared);
+        assert_eq!(shared, shared_bytes(&b, &a));
+    }
+
+    #[test]
+    fn range_order() {
+        let low = HashedRange { size: 10, hash: [1; 32] };
+        let high = HashedRange { size: 10, hash: [2; 32] };
+        assert_eq!(Ordering::Less, low.cmp(&high));
+        assert_eq!(Ordering::Greater, high.cmp(&low));
+        // Hash decides, even if sizes differ
+        let big = HashedRange { size: 20, hash: [1; 32] };
+        assert_eq!(Ordering::Greater, high.cmp(&big));
+        // Size only breaks ties
+        assert_eq!(Ordering::Less, low.cmp(&big));
+        assert_eq!(Ordering::Equal, low.cmp(&low.clone()));
+    }
+
+    #[test]
+    fn truncated_file() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        fs::write(a_path, vec![0u8; 10_000]).expect("write");
+        // Stat said 10000 bytes, but it's been truncated since
+        fs::write(b_path, vec![0u8; 6_000]).expect("write");
+
+        for small_file_threshold in [0, 1 << 20] {
+            let settings = HashSettings { small_file_threshold, ..HashSettings::default() };
+            let mut a = Hasher::new();
+            let mut b = Hasher::new();
+            a.compare(&mut b, 10_000, a_path, b_path, &settings).unwrap_err();
+            assert!(a.error().is_none());
+            assert!(b.error().expect("changed").changed);
+            let err = Hasher::new().digest(10_000, b_path, &settings).unwrap_err();
+            assert!(err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).expect("file error").changed);
+        }
+
+        // Truncated after it has been opened
+        let mut file = LazyFile::new(a_path, &HashSettings::default()).with_expected_size(10_000);
+        let mut buf = vec![0; 100_000];
+        assert_eq!(10_000, file.read_at(0, &mut buf).expect("read"));
+        fs::write(a_path, vec![0u8; 6_000]).expect("write");
+        assert!(file.read_at(8_000, &mut buf).unwrap_err().get_ref().and_then(|e| e.downcast_ref::<FileError>()).expect("file error").changed);
+        file.close();
+        file.fd().unwrap_err();
+
+        // Without an expected size, only what's there is hashed
+        let mut buffer = ReadBuffer::default();
+        let range = HashedRange::from_file(&mut LazyFile::new(b_path, &HashSettings::default()), 4_000, 4_000, &HashSettings::default(), &mut buffer).expect("hash");
+        assert_eq!(2_000, range.size);
+    }
+
+    #[test]
+    fn digest_len() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        let c_path = &tmp.path().join("c");
+        let mut content = vec![1u8; 50_000];
+        fs::write(a_path, &content).expect("write");
+        fs::write(b_path, &content).expect("write");
+        content[40_000] = 2;
+        fs::write(c_path, &content).expect("write");
+
+        let mut full = None;
+        for digest_len in [12, 20, 32] {
+            let settings = HashSettings { digest_len, ..HashSettings::default() };
+            let mut a = Hasher::new();
+            let mut b = Hasher::new();
+            let mut c = Hasher::new();
+            assert_eq!(Ordering::Equal, a.compare(&mut b, 50_000, a_path, b_path, &settings).expect("cmp"));
+            let ord = a.compare(&mut c, 50_000, a_path, c_path, &settings).expect("cmp");
+            assert_ne!(Ordering::Equal, ord);
+            assert_eq!(ord.reverse(), c.compare(&mut b, 50_000, c_path, b_path, &settings).expect("cmp"));
+            for range in &a.ranges {
+                assert!(range.hash[digest_len..].iter().all(|&b| b == 0));
+                assert!(range.hash[..digest_len].iter().any(|&b| b != 0));
+            }
+            // Truncations of the same hash
+            let full = full.get_or_insert_with(|| HashedRange::from_file(&mut LazyFile::new(a_path, &HashSettings::default()), 0, 2048, &HashSettings { digest_len: 32, ..HashSettings::default() }, &mut ReadBuffer::default()).expect("hash"));
+            assert_eq!(full.hash[..digest_len], a.ranges[0].hash[..digest_len]);
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let a_path = &tmp.path().join("a");
+        let b_path = &tmp.path().join("b");
+        fs::write(a_path, vec![1u8; 15_000]).expect("write");
+        fs::write(b_path, vec![1u8; 15_000]).expect("write");
+
+        // Time passes only by sleeping
+        static SLEPT: Mutex<Duration> = Mutex::new(Duration::ZERO);
+        let elapsed = |_: &Instant| *SLEPT.lock().unwrap();
+        let sleep = |wait| *SLEPT.lock().unwrap() += wait;
+
+        // Limit is smaller than a chunk. 10 KB are free, 20 KB take 2 seconds.
+        for small_file_threshold in [0, 1 << 20] {
+            let settings = HashSettings {
+                rate_limit: Some(Arc::new(RateLimit::with_clock(10_000, elapsed, sleep))),
+                small_file_threshold,
+                ..HashSettings::default()
+            };
+            let start = elapsed(&Instant::now());
+            assert_eq!(Ordering::Equal, Hasher::new().compare(&mut Hasher::new(), 15_000, a_path, b_path, &settings).expect("cmp"));
+            let slept = elapsed(&Instant::now()) - start;
+            assert!(slept >= Duration::from_millis(1999) && slept <= Duration::from_millis(2001), "{:?}", slept);
+        }
+    }
+
+    #[test]
+    fn open_file_limit() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let paths: Vec<_> = (0..10).map(|i| {
+            let path = tmp.path().join(i.to_string());
+            fs::write(&path, vec![i as u8; 1000]).expect("write");
+            path
+        }).collect();
+
+        let limit = Arc::new(OpenFileLimit::new(3));
+        let settings = HashSettings { open_files: Some(limit.clone()), ..HashSettings::default() };
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for (i, path) in paths.iter().enumerate() {
+                        let mut file = LazyFile::new(path, &settings);
+                        let mut buf = [0; 10];
+                        assert_eq!(10, file.read_at(0, &mut buf).expect("read"));
+                        assert_eq!([i as u8; 10], buf);
+                    }
+                });
+            }
+        });
+        assert!(limit.peak() <= 3);
+        assert!(limit.peak() > 0);
+        assert_eq!(0, limit.open());
+        assert!(OpenFileLimit::os_default().max >= 1);
+    }
+
+    #[test]
+    fn open_file_limit_single_thread() {
+        let tmp = tempdir::TempDir::new("hashtest").expect("tmp");
+        let paths: Vec<_> = (0..3).map(|i| {
+            let path = tmp.path().join(i.to_string());
+            fs::write(&path, "hello").expect("write");
+            path
+        }).collect();
+
+        let limit = Arc::new(OpenFileLimit::new(2));
+        let settings = HashSettings { open_files: Some(limit.clone()), ..HashSettings::default() };
+        let mut files: Vec<_> = paths.iter().map(|path| LazyFile::new(path, &settings)).collect();
+        let mut buf = [0; 5];
+        assert_eq!(5, files[0].read_at(0, &mut buf).expect("read"));
+        assert_eq!(5, files[1].read_at(0, &mut buf).expect("read"));
+        // Nothing else could close a file, so it fails instead of waiting
+        let err = files[2].read_at(0, &mut buf).unwrap_err();
+        assert_eq!(too_many_open_files().raw_os_error(), err.get_ref().unwrap().downcast_ref::<FileError>().unwrap().raw_os_error);
+        assert_eq!(2, limit.open());
+
+        files[0].close();
+        assert_eq!(5, files[2].read_at(0, &mut buf).expect("read"));
+        drop(files);
+        assert_eq!(0, limit.open());
     }
 }
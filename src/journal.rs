@@ -0,0 +1,297 @@
+use crate::link::{replace_with_copy, replace_with_hardlink, CopyOptions};
+use crate::metadata::Metadata;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+const MAGIC: &[u8; 8] = b"dkjourn1";
+/// Anything larger is assumed to be garbage
+const MAX_PATH_LEN: usize = 1 << 16;
+const MAX_PATHS: usize = 1 << 24;
+
+/// Groups of linked files, so that linking can be undone with `undo`.
+///
+/// The journal is an append-only log of groups, each with the master and the paths linked to it.
+/// Groups are recorded before the paths are replaced, so that an interrupted run can be undone too.
+/// Each group has a checksum, and an incomplete or invalid last group, as left by an interrupted write, is dropped.
+/// Invalid groups followed by valid ones make the journal corrupt.
+#[derive(Debug)]
+pub struct Journal {
+    file: fs::File,
+}
+
+/// A group of paths recorded in a `Journal`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub master: PathBuf,
+    /// Paths that have been (or were going to be) replaced with links to the master
+    pub paths: Vec<PathBuf>,
+    /// For each path, the index of the first path that was a hardlink of the same file before the paths were replaced.
+    /// It's the path's own index, unless it was one of several links of a file in the group.
+    pub linked_to: Vec<usize>,
+}
+
+/// What `undo` has done
+#[derive(Debug, Default, Clone)]
+pub struct UndoSummary {
+    /// Paths replaced with copies of their master
+    pub copied: usize,
+    /// Paths that weren't links of the master (anymore), or whose master is gone, so they were left as they are
+    pub skipped: Vec<Box<Path>>,
+}
+
+impl Journal {
+    /// Appends to the journal, or creates it. A partially written group at the end is removed first,
+    /// since new groups appended after it would be unreadable.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut file, &mut data)?;
+        // Also if the header has been cut off
+        let end = if data.len() < MAGIC.len() && MAGIC.starts_with(&data) { 0 } else { parse(&data)?.1 };
+        file.set_len(end as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        if end == 0 {
+            file.write_all(MAGIC)?;
+        }
+        Ok(Journal { file })
+    }
+
+    /// Appends the group, and flushes it to disk. Paths that are hardlinks of each other are recorded as such,
+    /// so that `undo` can link them to each other again.
+    pub fn record(&mut self, master: &Path, paths: &[impl AsRef<Path>]) -> io::Result<()> {
+        let mut files = HashMap::new();
+        let mut record = Vec::new();
+        record.push(b'\n');
+        record.extend_from_slice(&(paths.len() as u64 + 1).to_le_bytes());
+        for (i, path) in std::iter::once(master).chain(paths.iter().map(|p| p.as_ref())).enumerate() {
+            let bytes = path_to_bytes(path);
+            record.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            record.extend_from_slice(&bytes);
+            // Files without IDs, or that can't be read, aren't known to be linked to anything
+            let linked_to = match Metadata::from_path(path) {
+                Ok(m) if i > 0 && m.ino != 0 => *files.entry((m.dev, m.ino)).or_insert(i - 1),
+                _ => i.saturating_sub(1),
+            };
+            record.extend_from_slice(&(linked_to as u64).to_le_bytes());
+        }
+        let checksum = xxh3_64(&record);
+        record.extend_from_slice(&checksum.to_le_bytes());
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    /// All complete groups in the journal, in the order they were recorded
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<JournalEntry>> {
+        Ok(parse(&fs::read(path)?)?.0)
+    }
+}
+
+/// Replaces each path recorded in the journal that is still a link of its master with a copy of the master,
+/// with its modification time and permissions. Symlinks to the master are replaced with copies too.
+/// Paths that were hardlinks of each other before they were linked to the master become hardlinks of one copy again.
+/// The latest groups are undone first.
+/// It stops at the first error, and can be run again, since paths that are already copies are skipped.
+pub fn undo(journal: impl AsRef<Path>) -> io::Result<UndoSummary> {
+    let options = CopyOptions {
+        preserve_mtime: true,
+        preserve_permissions: true,
+    };
+    let mut summary = UndoSummary::default();
+    for entry in Journal::read(journal)?.into_iter().rev() {
+        let master = match Metadata::from_followed_path(&entry.master) {
+            Ok(m) => m,
+            Err(_) => {
+                summary.skipped.extend(entry.paths.into_iter().map(PathBuf::into_boxed_path));
+                continue;
+            },
+        };
+        // Index of the copy each file of the group has been restored as
+        let mut copies: HashMap<usize, usize> = HashMap::new();
+        for (i, path) in entry.paths.iter().enumerate() {
+            // Symlinks are followed, so they're undone too, and the copy replaces the symlink itself
+            if !Metadata::from_followed_path(path).map_or(false, |m| m.same_file(&master)) {
+                summary.skipped.push(path.clone().into_boxed_path());
+                continue;
+            }
+            let linked_to = entry.linked_to.get(i).copied().unwrap_or(i);
+            match copies.get(&linked_to) {
+                Some(&copy) => replace_with_hardlink(&entry.paths[copy], path)?,
+                None => {
+                    replace_with_copy(&entry.master, path, &options, false)?;
+                    copies.insert(linked_to, i);
+                },
+            }
+            summary.copied += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Groups after the header, and the position where the last complete one ends
+fn parse(data: &[u8]) -> io::Result<(Vec<JournalEntry>, usize)> {
+    if !data.starts_with(MAGIC) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a dupe-krill journal"));
+    }
+    let mut entries = Vec::new();
+    let mut pos = MAGIC.len();
+    while pos < data.len() {
+        match parse_entry(&data[pos..]) {
+            Some((entry, len)) => {
+                entries.push(entry);
+                pos += len;
+            },
+            // Only the last group can be partially written
+            None if (pos + 1..data.len()).any(|start| parse_entry(&data[start..]).is_some()) => return Err(corrupt()),
+            None => break,
+        }
+    }
+    Ok((entries, pos))
+}
+
+/// The group at the start of `data`, and its length. `None` if it's incomplete or doesn't match its checksum.
+fn parse_entry(data: &[u8]) -> Option<(JournalEntry, usize)> {
+    let mut rest = data;
+    if take(&mut rest, 1)? != b"\n" {
+        return None;
+    }
+    let count = read_u64(&mut rest)? as usize;
+    if count == 0 || count > MAX_PATHS {
+        return None;
+    }
+    let mut paths = Vec::with_capacity(count.min(1024));
+    let mut linked_to = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let path_len = read_u64(&mut rest)? as usize;
+        if path_len > MAX_PATH_LEN {
+            return None;
+        }
+        paths.push(bytes_to_path(take(&mut rest, path_len)?.to_vec()).ok()?);
+        linked_to.push(read_u64(&mut rest)? as usize);
+    }
+    let len = data.len() - rest.len();
+    if read_u64(&mut rest)? != xxh3_64(&data[..len]) {
+        return None;
+    }
+    let master = paths.remove(0);
+    linked_to.remove(0);
+    Some((JournalEntry { master, paths, linked_to }, len + 8))
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Some(taken)
+}
+
+fn read_u64(data: &mut &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(data, 8)?);
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt journal")
+}
+
+/// Paths are stored as they are, so the journal can be read only on the same platform
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(std::ffi::OsString::from_vec(bytes).into())
+}
+
+/// UTF-16 code units, since Windows paths don't have to be valid Unicode
+#[cfg(windows)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str().encode_wide().flat_map(u16::to_le_bytes).collect()
+}
+
+#[cfg(windows)]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    if bytes.len() % 2 != 0 {
+        return Err(corrupt());
+    }
+    let wide: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    Ok(std::ffi::OsString::from_wide(&wide).into())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    String::from_utf8(bytes).map(PathBuf::from).map_err(|_| corrupt())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partial_write() {
+        let tmp = tempdir::TempDir::new("journaltest").expect("tmp");
+        let path = tmp.path().join("journal");
+        let mut journal = Journal::open(&path).expect("open");
+        journal.record(Path::new("/a"), &["/b", "/c"]).expect("record");
+        drop(journal);
+
+        // Partially written group is dropped, and appending continues after the last complete one
+        let mut data = fs::read(&path).expect("read");
+        data.extend_from_slice(b"\n\x02\x00\x00");
+        fs::write(&path, data).expect("write");
+        let entry = JournalEntry {
+            master: "/a".into(),
+            paths: vec!["/b".into(), "/c".into()],
+            linked_to: vec![0, 1],
+        };
+        assert_eq!(vec![entry.clone()], Journal::read(&path).expect("read"));
+        Journal::open(&path).expect("open").record(Path::new("/d"), &["/e"]).expect("record");
+        let entries = Journal::read(&path).expect("read");
+        assert_eq!(2, entries.len());
+        assert_eq!(entry, entries[0]);
+        assert_eq!(Path::new("/d"), entries[1].master);
+
+        fs::write(&path, "garbage!").expect("write");
+        assert!(Journal::open(&path).is_err());
+    }
+
+    #[test]
+    fn invalid_groups() {
+        let tmp = tempdir::TempDir::new("journaltest").expect("tmp");
+        let path = tmp.path().join("journal");
+        Journal::open(&path).expect("open").record(Path::new("/a"), &["/b"]).expect("record");
+        let valid = fs::read(&path).expect("read");
+
+        // What a crash can leave after the last write
+        let mut data = valid.clone();
+        data.extend_from_slice(&[0; 100]);
+        fs::write(&path, &data).expect("write");
+        Journal::open(&path).expect("open");
+        assert_eq!(valid, fs::read(&path).expect("read"));
+
+        // A damaged group followed by a valid one isn't just partially written
+        let mut data = valid.clone();
+        *data.last_mut().expect("checksum") ^= 1;
+        data.extend_from_slice(&valid[MAGIC.len()..]);
+        fs::write(&path, &data).expect("write");
+        assert!(Journal::read(&path).is_err());
+        assert!(Journal::open(&path).is_err());
+    }
+}
@@ -41,6 +41,14 @@ struct JsonSerializable {
     dupes: Vec<Vec<Vec<Box<Path>>>>,
     stats: Stats,
     scan_duration: Duration,
+    /// Files that couldn't be read, if the hash settings have an error log
+    errors: Vec<JsonError>,
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    path: Box<Path>,
+    error: String,
 }
 
 impl JsonSerializable {
@@ -60,6 +68,13 @@ impl JsonSerializable {
                 .collect(),
             stats: *stats,
             scan_duration,
+            errors: scanner.settings.hashing.error_log.as_ref().map(|log| log.errors()).unwrap_or_default()
+                .into_iter()
+                .map(|err| JsonError {
+                    error: err.message().to_owned(),
+                    path: err.path,
+                })
+                .collect(),
         }
     }
 }
@@ -1,25 +1,633 @@
+use crate::hasher::{ErrorLog, HashSettings, OpenFileLimit, OpenFileSlot, ReadRetries};
+#[cfg(windows)]
+use crate::hasher::ShareMode;
+use crate::sparse;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Alignment of offsets, lengths and buffers of direct I/O. It's a multiple of sector sizes of common disks.
+const DIRECT_IO_ALIGN: usize = 4096;
+/// Direct reads larger than this are split, so that the bounce buffer stays small
+const DIRECT_IO_MAX_READ: usize = 1 << 20;
+
+/// I/O error of a specific file.
+/// It's returned wrapped in an `io::Error` of the same kind, and can be extracted with `get_ref()` and `downcast_ref()`.
+#[derive(Debug, Clone)]
+pub struct FileError {
+    pub path: Box<Path>,
+    pub kind: io::ErrorKind,
+    pub raw_os_error: Option<i32>,
+    /// The file has been modified since it was found, rather than being unreadable
+    pub changed: bool,
+    /// Metadata of the file couldn't be read, e.g. because it was deleted after it was found. Such files are skipped.
+    pub metadata: bool,
+    /// Other files changed along with this one have been restored, so nothing has changed
+    pub rolled_back: bool,
+    message: String,
+}
+
+impl FileError {
+    pub fn new(path: &Path, err: &io::Error) -> Self {
+        // Don't wrap twice
+        if let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()) {
+            return err.clone();
+        }
+        FileError {
+            path: path.into(),
+            kind: err.kind(),
+            raw_os_error: err.raw_os_error(),
+            changed: false,
+            metadata: false,
+            rolled_back: false,
+            message: err.to_string(),
+        }
+    }
+
+    /// Like `new`, but the message says what was being done, e.g. "can't open: Permission denied"
+    pub fn during(path: &Path, operation: &str, err: &io::Error) -> Self {
+        if let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()) {
+            return err.clone();
+        }
+        let mut file_err = Self::new(path, err);
+        file_err.message = format!("can't {}: {}", operation, file_err.message);
+        file_err
+    }
+
+    /// The file's size isn't the `expected_size` it had when it was found, so its content can't be compared reliably
+    pub fn changed(path: &Path, expected_size: u64, actual_size: u64) -> Self {
+        FileError {
+            path: path.into(),
+            kind: io::ErrorKind::Other,
+            raw_os_error: None,
+            changed: true,
+            metadata: false,
+            rolled_back: false,
+            message: format!("file changed during scan (size was {}, now {})", expected_size, actual_size),
+        }
+    }
+
+    /// The path is no longer the file it was when it was planned to be replaced, or the file has been modified since
+    pub(crate) fn replaced(path: &Path) -> Self {
+        FileError {
+            path: path.into(),
+            kind: io::ErrorKind::Other,
+            raw_os_error: None,
+            changed: true,
+            metadata: false,
+            rolled_back: false,
+            message: "file changed since the plan to replace it was made".into(),
+        }
+    }
+
+    /// Stat of the file failed
+    pub fn metadata(path: &Path, err: &io::Error) -> Self {
+        let mut file_err = Self::during(path, "read metadata", err);
+        file_err.metadata = true;
+        file_err
+    }
+
+    /// The error stopped changes to a group of files, which have been undone unless `restored` is false
+    pub(crate) fn with_rollback(mut self, restored: bool) -> Self {
+        self.rolled_back = restored;
+        self.message += if restored { " (other changes were rolled back)" } else { " (rolling back other changes failed)" };
+        self
+    }
+
+    /// Description of the error, without the path
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn to_io_error(&self) -> io::Error {
+        io::Error::new(self.kind, self.clone())
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+impl Error for FileError {}
 
 /// Open the file only if necessary.
 /// The file will be closed automatically when this object goes out of scope.
-pub struct LazyFile<'a> {
+/// `R` is the type of the reader given to `from_reader`. Files opened from paths don't have one.
+pub struct LazyFile<'a, R: Read + ?Sized = io::Empty> {
     path: &'a Path,
     file: Option<fs::File>,
+    sparse: Option<bool>,
+    /// Position of the file handle, if known
+    offset: Option<u64>,
+    bytes_read: u64,
+    read_ahead: Vec<u8>,
+    /// Position in the file of the first byte of `read_ahead`
+    read_ahead_pos: u64,
+    read_ahead_size: usize,
+    /// Bypass the OS cache. Cleared if the file can't be read that way.
+    direct_io: bool,
+    /// Over-allocated, so that an aligned slice of it can be read into
+    aligned: Vec<u8>,
+    sequential_scan: bool,
+    drop_cache: bool,
+    open_retries: u32,
+    open_retry_delay: Duration,
+    read_retries: Option<ReadRetries>,
+    /// Read errors retried so far
+    read_retry_count: u32,
+    read_errors: u32,
+    max_interrupted_reads: u32,
+    error_log: Option<Arc<ErrorLog>>,
+    /// Size from the earlier stat. A different size means the file has been modified since.
+    expected_size: Option<u64>,
+    /// Shared limit of open files
+    open_files: Option<Arc<OpenFileLimit>>,
+    /// Taken from `open_files` while `file` is open
+    open_slot: Option<OpenFileSlot>,
+    /// Forward-only source of the content, read instead of the file at `path`
+    stream: Option<Stream<'a, R>>,
+    /// If the path is a symlink, its target is the content. `None` once it's been checked.
+    compare_symlink_target: Option<bool>,
+    /// Bytes consumed from the `stream`
+    stream_pos: u64,
+    #[cfg(feature = "mmap")]
+    mmap_file_threshold: u64,
+    /// The whole file, if it's large enough to be mapped. `None` until it's been checked.
+    #[cfg(feature = "mmap")]
+    map: Option<Option<memmap2::Mmap>>,
+    #[cfg(windows)]
+    share_mode: ShareMode,
+}
+
+/// Content read forward-only instead of the file
+enum Stream<'a, R: ?Sized> {
+    Reader(&'a mut R),
+    SymlinkTarget(io::Cursor<Vec<u8>>),
+}
+
+impl<R: Read + ?Sized> Read for Stream<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Reader(reader) => reader.read(buf),
+            Stream::SymlinkTarget(target) => target.read(buf),
+        }
+    }
 }
 
 impl<'a> LazyFile<'a> {
-    pub fn new(path: &'a Path) -> Self {
-        LazyFile { path, file: None }
+    pub fn new(path: &'a Path, settings: &HashSettings) -> Self {
+        Self::with_stream(path, None, settings)
+    }
+}
+
+impl<'a, R: Read + ?Sized> LazyFile<'a, R> {
+    fn with_stream(path: &'a Path, stream: Option<Stream<'a, R>>, settings: &HashSettings) -> Self {
+        LazyFile {
+            path,
+            file: None,
+            sparse: None,
+            offset: None,
+            bytes_read: 0,
+            read_ahead: Vec::new(),
+            read_ahead_pos: 0,
+            read_ahead_size: settings.read_ahead_size,
+            direct_io: settings.direct_io,
+            aligned: Vec::new(),
+            sequential_scan: settings.sequential_scan,
+            drop_cache: settings.drop_cache,
+            open_retries: settings.open_retries,
+            open_retry_delay: settings.open_retry_delay,
+            read_retries: settings.read_retries.clone(),
+            read_retry_count: 0,
+            read_errors: 0,
+            max_interrupted_reads: settings.max_interrupted_reads,
+            error_log: settings.error_log.clone(),
+            expected_size: None,
+            open_files: settings.open_files.clone(),
+            open_slot: None,
+            stream,
+            compare_symlink_target: Some(settings.compare_symlink_targets),
+            stream_pos: 0,
+            #[cfg(feature = "mmap")]
+            mmap_file_threshold: settings.mmap_file_threshold,
+            #[cfg(feature = "mmap")]
+            map: None,
+            #[cfg(windows)]
+            share_mode: settings.share_mode,
+        }
+    }
+
+    /// Reads from a stream, e.g. a pipe or a decompressor, instead of opening the file.
+    /// The `path` is only for error messages. The stream can't seek back, so reads must be in increasing order,
+    /// except for re-reading what is still in the read-ahead buffer.
+    pub fn from_reader(path: &'a Path, reader: &'a mut R, settings: &HashSettings) -> Self {
+        Self::with_stream(path, Some(Stream::Reader(reader)), settings)
+    }
+
+    /// Whether it reads from a stream, which doesn't have a file handle.
+    /// Symlinks compared by their target are streams too, but only once they've been read.
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// With `compare_symlink_targets`, content of a symlink is the target path, and the target isn't opened.
+    /// Broken symlinks are just symlinks with some text.
+    fn check_symlink(&mut self) -> Result<(), io::Error> {
+        if self.compare_symlink_target.take() != Some(true) || self.stream.is_some() {
+            return Ok(());
+        }
+        let target = match symlink_target(self.path)? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        if self.expected_size.map_or(false, |size| size != target.len() as u64) {
+            return Err(self.changed(target.len() as u64));
+        }
+        self.stream = Some(Stream::SymlinkTarget(io::Cursor::new(target)));
+        Ok(())
+    }
+
+    /// Fail reads with a `FileError::changed` error if the file doesn't have this size
+    pub fn with_expected_size(mut self, size: u64) -> Self {
+        self.expected_size = Some(size);
+        self
+    }
+
+    fn changed(&self, actual_size: u64) -> io::Error {
+        FileError::changed(self.path, self.expected_size.unwrap_or(0), actual_size).to_io_error()
+    }
+
+    /// Whether the file may have holes
+    pub fn is_sparse(&mut self) -> Result<bool, io::Error> {
+        if let Some(sparse) = self.sparse {
+            return Ok(sparse);
+        }
+        self.check_symlink()?;
+        if self.is_stream() {
+            return Ok(false);
+        }
+        let sparse = sparse::is_sparse(&self.fd()?.metadata()?);
+        self.sparse = Some(sparse);
+        Ok(sparse)
+    }
+
+    /// Whether the file handle is open, i.e. the file has been needed since it's been created or closed
+    pub fn is_open(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn path(&self) -> &'a Path {
+        self.path
+    }
+
+    /// Read from `pos`. Sequential reads don't need to seek.
+    /// Small reads are buffered, and large reads bypass the buffer to avoid copying.
+    pub fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.check_symlink()?;
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = self.mapped_range(pos, buf.len() as u64)? {
+            buf[..mapped.len()].copy_from_slice(mapped);
+            return Ok(mapped.len());
+        }
+        if let Some(n) = self.read_buffered(pos, buf) {
+            return Ok(n);
+        }
+        if buf.len() >= self.read_ahead_size {
+            let n = self.read_direct(pos, buf)?;
+            self.bytes_read += n as u64;
+            return Ok(n);
+        }
+        let mut read_ahead = std::mem::take(&mut self.read_ahead);
+        if read_ahead.len() < self.read_ahead_size {
+            read_ahead = vec![0; self.read_ahead_size];
+        }
+        let res = self.read_direct(pos, &mut read_ahead);
+        read_ahead.truncate(*res.as_ref().unwrap_or(&0));
+        self.read_ahead = read_ahead;
+        self.read_ahead_pos = pos;
+        res?;
+        // Nothing buffered means end of file
+        Ok(self.read_buffered(pos, buf).unwrap_or(0))
+    }
+
+    /// Copy from the read-ahead buffer, if it has data at `pos`
+    fn read_buffered(&mut self, pos: u64, buf: &mut [u8]) -> Option<usize> {
+        let start = usize::try_from(pos.checked_sub(self.read_ahead_pos)?).ok()?;
+        let available = self.read_ahead.get(start..).filter(|a| !a.is_empty())?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.bytes_read += n as u64;
+        Some(n)
+    }
+
+    fn read_direct(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let mut interrupted = 0;
+        let n = loop {
+            let res = if self.is_stream() { self.read_stream(pos, buf) } else { self.read_file(pos, buf) };
+            match res {
+                Ok(n) => break n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted && interrupted < self.max_interrupted_reads => interrupted += 1,
+                Err(err) => {
+                    self.read_errors += 1;
+                    if self.is_stream() || !self.retry_read(&err) {
+                        return Err(err);
+                    }
+                },
+            }
+        };
+        // Truncated after it has been opened
+        if n == 0 && !buf.is_empty() && self.expected_size.map_or(false, |size| pos < size) {
+            return Err(self.changed(pos));
+        }
+        Ok(n)
+    }
+
+    fn read_stream(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Ok(0),
+        };
+        if pos < self.stream_pos {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't seek a stream back from {} to {}", self.stream_pos, pos)));
+        }
+        if pos > self.stream_pos {
+            self.stream_pos += io::copy(&mut stream.by_ref().take(pos - self.stream_pos), &mut io::sink())?;
+        }
+        let n = if pos == self.stream_pos { stream.read(buf)? } else { 0 };
+        self.stream_pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_file(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.direct_io {
+            return self.read_aligned(pos, buf);
+        }
+        let offset = self.offset;
+        let fd = self.fd()?;
+        if offset != Some(pos) {
+            fd.seek(SeekFrom::Start(pos))?;
+        }
+        // After an error the position is unknown until the next seek
+        let n = fd.read(buf)?;
+        self.offset = Some(pos + n as u64);
+        Ok(n)
+    }
+
+    /// Closes the file after a transient error, so that the next read reopens it and seeks back.
+    /// Returns `false` if the error is permanent or the file has used up its retries.
+    fn retry_read(&mut self, err: &io::Error) -> bool {
+        let delay = match &self.read_retries {
+            Some(retries) if self.read_retry_count < retries.max_retries && retries.is_transient(err) => {
+                retries.delay.saturating_mul(1 << self.read_retry_count.min(16))
+            },
+            _ => return false,
+        };
+        self.read_retry_count += 1;
+        self.close();
+        std::thread::sleep(delay);
+        true
+    }
+
+    /// Direct I/O can only read whole aligned blocks into aligned memory, so the blocks covering the
+    /// requested range are read into a bounce buffer. If the OS rejects it anyway, the file is reopened for buffered I/O.
+    fn read_aligned(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let align = DIRECT_IO_ALIGN as u64;
+        let start = pos / align * align;
+        let skip = (pos - start) as usize;
+        let wanted = buf.len().min(DIRECT_IO_MAX_READ);
+        let len = (skip + wanted + DIRECT_IO_ALIGN - 1) / DIRECT_IO_ALIGN * DIRECT_IO_ALIGN;
+        if self.aligned.len() < len + DIRECT_IO_ALIGN {
+            self.aligned = vec![0; len + DIRECT_IO_ALIGN];
+        }
+        let mut aligned = std::mem::take(&mut self.aligned);
+        let base = aligned.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        let block = &mut aligned[base..base + len];
+        let offset = self.offset;
+        let res = self.fd().and_then(|fd| {
+            if offset != Some(start) {
+                fd.seek(SeekFrom::Start(start))?;
+            }
+            fd.read(block)
+        });
+        let res = match res {
+            Ok(n) => {
+                self.offset = Some(start + n as u64);
+                let n = n.saturating_sub(skip).min(wanted);
+                buf[..n].copy_from_slice(&block[skip..skip + n]);
+                Ok(n)
+            },
+            Err(err) => Err(err),
+        };
+        self.aligned = aligned;
+        match res {
+            Err(ref err) if is_alignment_error(err) => {
+                self.close();
+                self.direct_io = false;
+                self.aligned = Vec::new();
+                self.read_direct(pos, buf)
+            },
+            res => res,
+        }
+    }
+
+    /// Failed reads, including ones that have been retried successfully
+    pub fn read_errors(&self) -> u32 {
+        self.read_errors
+    }
+
+    /// Adds the error to the shared log, if there's one
+    pub(crate) fn log_error(&self, err: &FileError) {
+        if let Some(log) = &self.error_log {
+            log.record(err.clone());
+        }
+    }
+
+    /// Total of bytes read from the file. Bytes read ahead count only once they're used.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The whole file mapped into memory, if it's at least `mmap_file_threshold` large.
+    /// The map is made once, and only if the file still has its expected size.
+    #[cfg(feature = "mmap")]
+    pub fn mapped(&mut self) -> Result<Option<&[u8]>, io::Error> {
+        self.check_symlink()?;
+        if self.map.is_none() {
+            let threshold = self.mmap_file_threshold;
+            let map = if self.is_stream() || self.expected_size.map_or(false, |size| size < threshold) {
+                None
+            } else {
+                let fd = self.fd()?;
+                let len = fd.metadata()?.len();
+                if len == 0 || len < threshold {
+                    None
+                } else {
+                    // The map is read-only, and `mapped_range` checks the length before reading it
+                    let map = unsafe { memmap2::Mmap::map(&*fd)? };
+                    // Opening checks the size too, but the file may have been opened before it's changed
+                    if self.expected_size.map_or(false, |size| size != len) {
+                        return Err(self.changed(len));
+                    }
+                    Some(map)
+                }
+            };
+            self.map = Some(map);
+        }
+        Ok(self.map.as_ref().and_then(|map| map.as_deref()))
+    }
+
+    /// At most `len` bytes of the map from `pos`, or `None` if the file isn't mapped (see `mapped`). Reading a part of the map
+    /// that the file no longer has would fault, so its length is checked first. If it's changed, the map is dropped.
+    #[cfg(feature = "mmap")]
+    pub fn mapped_range(&mut self, pos: u64, len: u64) -> Result<Option<&[u8]>, io::Error> {
+        let map_len = match self.mapped()? {
+            Some(map) => map.len() as u64,
+            None => return Ok(None),
+        };
+        if self.fd()?.metadata()?.len() != map_len {
+            self.map = Some(None);
+            return Ok(None);
+        }
+        let start = pos.min(map_len);
+        let end = pos.saturating_add(len).min(map_len);
+        self.bytes_read += end - start;
+        Ok(self.map.as_ref().and_then(|map| map.as_deref()).map(|map| &map[start as usize..end as usize]))
+    }
+
+    /// Count bytes read other than with `read_at`, e.g. from a memory map
+    #[cfg(feature = "mmap")]
+    pub fn record_read(&mut self, bytes: u64) {
+        self.bytes_read += bytes;
+    }
+
+    /// Waits for a slot in the limit of open files
+    fn open(&mut self) -> Result<fs::File, io::Error> {
+        let res = match &self.open_files {
+            Some(limit) => limit.acquire().map(Some),
+            None => Ok(None),
+        };
+        let res = res.and_then(|slot| {
+            let file = self.open_retrying()?;
+            self.open_slot = slot;
+            Ok(file)
+        });
+        res.map_err(|err| FileError::during(self.path, "open", &err).to_io_error())
+    }
+
+    /// Retries while another program briefly keeps the file locked
+    fn open_retrying(&mut self) -> Result<fs::File, io::Error> {
+        let mut retries = self.open_retries;
+        let mut delay = self.open_retry_delay;
+        loop {
+            match self.open_once() {
+                Err(err) if retries > 0 && is_sharing_violation(&err) => {
+                    retries -= 1;
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Falls back to buffered I/O if the filesystem doesn't support direct I/O
+    fn open_once(&mut self) -> Result<fs::File, io::Error> {
+        if self.direct_io {
+            match self.open_with(true) {
+                Err(ref err) if is_alignment_error(err) => self.direct_io = false,
+                res => return res,
+            }
+        }
+        self.open_with(false)
     }
 
-    /// Open the file (or reuse already-opened handle)
+    #[cfg(windows)]
+    fn open_with(&self, direct_io: bool) -> Result<fs::File, io::Error> {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Storage::FileSystem::{FILE_FLAG_NO_BUFFERING, FILE_FLAG_SEQUENTIAL_SCAN};
+
+        let mut options = fs::OpenOptions::new();
+        options.read(true).share_mode(self.share_mode.flags());
+        let mut flags = 0;
+        // Windows reads ahead more aggressively, and drops pages already read from the cache first
+        if self.sequential_scan {
+            flags |= FILE_FLAG_SEQUENTIAL_SCAN;
+        }
+        if direct_io {
+            flags |= FILE_FLAG_NO_BUFFERING;
+        }
+        options.custom_flags(flags);
+        options.open(open_path(self.path))
+    }
+
+    #[cfg(not(windows))]
+    fn open_with(&self, direct_io: bool) -> Result<fs::File, io::Error> {
+        let mut options = fs::OpenOptions::new();
+        options.read(true);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        if direct_io {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_DIRECT);
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+        let _ = direct_io;
+        let file = options.open(open_path(self.path))?;
+        if self.sequential_scan {
+            advise_sequential(&file);
+        }
+        Ok(file)
+    }
+
+    /// Release the file handle now, e.g. so that other programs can modify the file on Windows.
+    /// The file will be opened again if it's needed later.
+    pub fn close(&mut self) {
+        if let Some(file) = self.file.take() {
+            if self.drop_cache && self.bytes_read > 0 {
+                advise_dont_need(&file);
+            }
+            drop(file);
+            self.open_slot = None;
+        }
+        self.offset = None;
+        #[cfg(feature = "mmap")]
+        {
+            self.map = None;
+        }
+        // The file may change while it's closed, but the stream can't be read again
+        if !self.is_stream() {
+            self.read_ahead = Vec::new();
+        }
+    }
+
+    /// Open the file (or reuse already-opened handle).
+    /// The handle may be moved by the caller, so its position is forgotten.
     pub fn fd(&mut self) -> Result<&mut fs::File, io::Error> {
+        self.check_symlink()?;
+        if self.is_stream() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "a stream has no file handle"));
+        }
+        self.offset = None;
         if let Some(ref mut fd) = self.file {
             Ok(fd)
         } else {
-            self.file = Some(fs::File::open(self.path)?);
+            self.file = Some(self.open()?);
+            if let Some(ref mut fd) = self.file {
+                // Stays open, so that it's closed and released like any other file
+                if let Some(size) = self.expected_size {
+                    let actual_size = fd.metadata().map_err(|err| FileError::during(self.path, "stat", &err).to_io_error())?.len();
+                    if actual_size != size {
+                        return Err(self.changed(actual_size));
+                    }
+                }
+            }
             if let Some(ref mut fd) = self.file {
                 Ok(fd)
             } else {
@@ -28,3 +636,381 @@ impl<'a> LazyFile<'a> {
         }
     }
 }
+
+impl<R: Read + ?Sized> Drop for LazyFile<'_, R> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// The target path of a symlink, as the bytes compared by `compare_symlink_targets`. `None` if it isn't a symlink.
+/// Its length is the size of the symlink's content, since Windows reports symlinks as empty.
+pub(crate) fn symlink_target(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let m = fs::symlink_metadata(path).map_err(|err| FileError::during(path, "stat", &err).to_io_error())?;
+    if !m.file_type().is_symlink() {
+        return Ok(None);
+    }
+    let target = fs::read_link(path).map_err(|err| FileError::during(path, "read link", &err).to_io_error())?;
+    #[cfg(unix)]
+    let target = {
+        use std::os::unix::ffi::OsStringExt;
+        target.into_os_string().into_vec()
+    };
+    #[cfg(not(unix))]
+    let target = target.to_string_lossy().into_owned().into_bytes();
+    Ok(Some(target))
+}
+
+/// The file is open by another program that doesn't allow sharing, which is usually temporary.
+/// Access denied is a different error, and it's permanent.
+#[cfg(windows)]
+fn is_sharing_violation(err: &io::Error) -> bool {
+    use windows_sys::Win32::Foundation::ERROR_SHARING_VIOLATION;
+    err.raw_os_error() == Some(ERROR_SHARING_VIOLATION as i32)
+}
+
+/// Unix doesn't have mandatory locks that prevent opening
+#[cfg(not(windows))]
+fn is_sharing_violation(_: &io::Error) -> bool {
+    false
+}
+
+/// Direct I/O isn't supported by the filesystem, or its alignment is stricter than `DIRECT_IO_ALIGN`
+#[cfg(windows)]
+fn is_alignment_error(err: &io::Error) -> bool {
+    use windows_sys::Win32::Foundation::ERROR_INVALID_PARAMETER;
+    err.raw_os_error() == Some(ERROR_INVALID_PARAMETER as i32)
+}
+
+#[cfg(unix)]
+fn is_alignment_error(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EINVAL)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_alignment_error(_: &io::Error) -> bool {
+    false
+}
+
+/// Kernel reads ahead more aggressively
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise_sequential(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+    // It's only a hint, so errors don't matter
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", windows)))]
+fn advise_sequential(_: &fs::File) {}
+
+/// Kernel drops the file's cached pages
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise_dont_need(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn advise_dont_need(_: &fs::File) {}
+
+/// Path in a form that can be opened even if it's longer than `MAX_PATH`. Shorter paths are left as they are.
+#[cfg(windows)]
+pub(crate) fn open_path(path: &Path) -> Cow<'_, Path> {
+    let absolute = if path.is_absolute() {
+        Cow::Borrowed(path)
+    } else {
+        match std::env::current_dir() {
+            Ok(dir) => Cow::Owned(dir.join(path)),
+            Err(_) => return Cow::Borrowed(path),
+        }
+    };
+    match absolute.to_str().and_then(extended_length_path) {
+        Some(extended) => Cow::Owned(extended.into()),
+        None => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn open_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+#[cfg(any(windows, test))]
+const MAX_PATH: usize = 260;
+
+/// Converts an absolute path to the `\\?\` form, which isn't limited to `MAX_PATH`.
+/// Windows doesn't resolve `.` and `..` in such paths, so it's done here. Trailing dots and spaces are kept,
+/// unlike in normal paths, since they may be part of the actual name.
+/// Returns `None` for paths shorter than `MAX_PATH`, which don't need it, and for paths that are already verbatim,
+/// or have an unusual prefix.
+#[cfg(any(windows, test))]
+fn extended_length_path(absolute: &str) -> Option<String> {
+    if absolute.encode_utf16().count() < MAX_PATH {
+        return None;
+    }
+    let path = absolute.replace('/', "\\");
+    let (mut extended, rest) = if let Some(unc) = path.strip_prefix("\\\\") {
+        let mut parts = unc.splitn(3, '\\');
+        let server = parts.next().filter(|s| !s.is_empty() && *s != "?" && *s != ".")?;
+        let share = parts.next().filter(|s| !s.is_empty())?;
+        (format!("\\\\?\\UNC\\{}\\{}", server, share), parts.next().unwrap_or(""))
+    } else {
+        let drive = path.as_bytes();
+        if drive.len() < 3 || !drive[0].is_ascii_alphabetic() || drive[1] != b':' || drive[2] != b'\\' {
+            return None;
+        }
+        (format!("\\\\?\\{}", &path[..2]), &path[3..])
+    };
+    let mut components = Vec::new();
+    for component in rest.split('\\') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                components.pop();
+            },
+            _ => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        extended.push('\\');
+    }
+    for component in components {
+        extended.push('\\');
+        extended.push_str(component);
+    }
+    Some(extended)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_ahead() {
+        let tmp = tempdir::TempDir::new("lazyfiletest").expect("tmp");
+        let path = &tmp.path().join("a");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(path, &data).expect("write");
+
+        let mut file = LazyFile::new(path, &HashSettings::default());
+        let mut buf = vec![0; 100_000];
+        // Small reads are served from one read-ahead, large ones are read directly
+        for &(pos, len) in &[(0, 2048), (2048, 32768), (34816, 100_000), (100, 10), (199_990, 100), (200_000, 10)] {
+            let mut filled = 0;
+            while filled < len {
+                match file.read_at(pos + filled as u64, &mut buf[filled..len]).expect("read") {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            let pos = pos as usize;
+            assert_eq!(&data[pos.min(data.len())..(pos + len).min(data.len())], &buf[..filled]);
+        }
+    }
+
+    #[test]
+    fn extended_length_paths() {
+        let d = "d".repeat(MAX_PATH);
+        let ext = |path: &str| extended_length_path(&path.replace("LONG", &d));
+        let expected = |path: &str| Some(path.replace("LONG", &d));
+        assert_eq!(expected(r"\\?\C:\LONG\file.txt"), ext(r"C:\LONG\.\other\..\file.txt"));
+        assert_eq!(expected(r"\\?\C:\LONG\file. "), ext("C:/LONG//file. "));
+        assert_eq!(expected(r"\\?\C:\LONG\file."), ext(r"C:\LONG\file."));
+        assert_eq!(expected(r"\\?\c:\"), ext(r"c:\LONG\..\.."));
+        assert_eq!(expected(r"\\?\UNC\server\share\LONG"), ext(r"\\server\share\LONG\"));
+        assert_eq!(expected(r"\\?\UNC\server\share\"), ext(r"\\server\share\LONG\.."));
+        assert_eq!(None, ext(r"\\?\C:\LONG"));
+        assert_eq!(None, ext(r"\\.\pipe\LONG"));
+        assert_eq!(None, ext(r"\\LONG"));
+        assert_eq!(None, ext(r"relative\LONG"));
+
+        // Short paths work as they are
+        assert_eq!(None, extended_length_path(r"C:\dir\file."));
+        let short = format!(r"C:\{}", "d".repeat(MAX_PATH - 4));
+        assert_eq!(None, extended_length_path(&short));
+        assert!(extended_length_path(&format!("{}d", short)).is_some());
+    }
+
+    #[test]
+    fn close() {
+        let tmp = tempdir::TempDir::new("lazyfiletest").expect("tmp");
+        let path = &tmp.path().join("a");
+        fs::write(path, "hello").expect("write");
+
+        let mut file = LazyFile::new(path, &HashSettings::default());
+        assert!(!file.is_open());
+        let mut buf = [0; 5];
+        assert_eq!(5, file.read_at(0, &mut buf).expect("read"));
+        file.close();
+        assert!(!file.is_open());
+
+        fs::write(path, "world").expect("write");
+        assert_eq!(5, file.read_at(0, &mut buf).expect("read"));
+        assert_eq!(b"world", &buf);
+        assert!(file.is_open());
+    }
+
+    #[test]
+    fn stream() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reader = &data[..];
+        let mut file = LazyFile::from_reader(Path::new("stream"), &mut reader, &HashSettings::default());
+        assert!(!file.is_sparse().expect("sparse"));
+        file.fd().unwrap_err();
+
+        let mut buf = [0; 10];
+        assert_eq!(10, file.read_at(0, &mut buf).expect("read"));
+        assert_eq!(&data[..10], &buf);
+        // Still buffered
+        assert_eq!(10, file.read_at(5, &mut buf).expect("read"));
+        assert_eq!(&data[5..15], &buf);
+        // Skips forward
+        assert_eq!(10, file.read_at(150_000, &mut buf).expect("read"));
+        assert_eq!(&data[150_000..150_010], &buf);
+        assert_eq!(io::ErrorKind::Unsupported, file.read_at(100, &mut buf).unwrap_err().kind());
+        assert_eq!(0, file.read_at(300_000, &mut buf).expect("read"));
+    }
+
+    #[test]
+    fn open_error_has_path() {
+        let tmp = tempdir::TempDir::new("lazyfiletest").expect("tmp");
+        let path = &tmp.path().join("missing");
+        let mut file = LazyFile::new(path, &HashSettings::default());
+        let err = file.fd().unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().starts_with(&format!("{}: can't open: ", path.display())));
+        let file_err = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).expect("file error");
+        assert_eq!(&**path, &*file_err.path);
+        assert!(file_err.raw_os_error.is_some());
+        // Doesn't wrap twice
+        assert_eq!(err.to_string(), FileError::during(path, "read", &err).to_string());
+    }
+
+    #[test]
+    fn read_ahead_size() {
+        struct CountingReader<'a>(&'a [u8], usize);
+        impl Read for CountingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1 += 1;
+                self.0.read(buf)
+            }
+        }
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reads = Vec::new();
+        for read_ahead_size in [0, 64 << 10, 1 << 20] {
+            let mut reader = CountingReader(&data, 0);
+            {
+                let settings = HashSettings { read_ahead_size, ..HashSettings::default() };
+                let mut file = LazyFile::from_reader(Path::new("stream"), &mut reader, &settings);
+                let mut buf = vec![0; 300_000];
+                let mut pos = 0;
+                for len in [2048, 32768, 65536, 199_648] {
+                    let mut filled = 0;
+                    while filled < len {
+                        filled += file.read_at((pos + filled) as u64, &mut buf[pos + filled..pos + len]).expect("read");
+                    }
+                    pos += len;
+                }
+                assert_eq!(data, buf);
+            }
+            reads.push(reader.1);
+        }
+        assert!(reads[0] > reads[1], "{:?}", reads);
+        assert!(reads[1] > reads[2], "{:?}", reads);
+        assert_eq!(1, reads[2]);
+    }
+
+    #[test]
+    fn direct_io() {
+        let dir = tempdir::TempDir::new("lazyfile").expect("tmp");
+        let path = dir.path().join("a");
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).expect("write");
+
+        for read_ahead_size in [0, 64 << 10] {
+            let settings = HashSettings { direct_io: true, read_ahead_size, ..HashSettings::default() };
+            let mut file = LazyFile::new(&path, &settings).with_expected_size(data.len() as u64);
+            // Unaligned positions and lengths, out of order, and past the end
+            for (pos, len) in [(0, 100), (5000, 4096), (1, 1_500_000), (2_999_999, 10), (4095, 8193), (3_000_000, 1)] {
+                let mut buf = vec![0; len];
+                let n = file.read_at(pos as u64, &mut buf).expect("read");
+                let expected = &data[pos.min(data.len())..(pos + len).min(data.len())];
+                assert!(n > 0 || expected.is_empty());
+                assert_eq!(&expected[..n], &buf[..n]);
+            }
+        }
+    }
+
+    #[test]
+    fn read_retries() {
+        let dir = tempdir::TempDir::new("lazyfile").expect("tmp");
+        let path = dir.path().join("a");
+        fs::write(&path, "hello world").expect("write");
+        let settings = HashSettings {
+            read_retries: Some(ReadRetries { max_retries: 2, delay: Duration::ZERO, ..ReadRetries::default() }),
+            read_ahead_size: 0,
+            ..HashSettings::default()
+        };
+        let mut file = LazyFile::new(&path, &settings).with_expected_size(11);
+        let mut buf = [0; 5];
+        assert_eq!(5, file.read_at(0, &mut buf).expect("read"));
+
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        assert!(!file.retry_read(&io::Error::from(io::ErrorKind::NotFound)));
+        assert!(!file.retry_read(&FileError::during(&path, "read", &io::Error::from(io::ErrorKind::PermissionDenied)).to_io_error()));
+        assert!(!file.retry_read(&FileError::changed(&path, 11, 5).to_io_error()));
+        assert!(file.is_open());
+        assert!(file.retry_read(&FileError::during(&path, "read", &timed_out).to_io_error()));
+        assert!(!file.is_open());
+        // Resumes where it failed
+        assert_eq!(5, file.read_at(5, &mut buf).expect("read"));
+        assert_eq!(b" worl", &buf);
+        // The budget isn't reset by successful reads
+        assert!(file.retry_read(&timed_out));
+        assert!(!file.retry_read(&timed_out));
+        #[cfg(unix)]
+        assert!(ReadRetries::default().is_transient(&io::Error::from_raw_os_error(libc::EIO)));
+    }
+
+    #[test]
+    fn interrupted_reads() {
+        /// Fails with `Interrupted` the given number of times before each read
+        struct InterruptingReader<'a>(&'a [u8], u32, u32);
+        impl Read for InterruptingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.2 < self.1 {
+                    self.2 += 1;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                self.2 = 0;
+                self.0.read(buf)
+            }
+        }
+
+        let settings = HashSettings { max_interrupted_reads: 3, read_ahead_size: 0, ..HashSettings::default() };
+        let mut buf = [0; 5];
+        let mut reader = InterruptingReader(b"hello world", 3, 0);
+        let mut file = LazyFile::from_reader(Path::new("stream"), &mut reader, &settings);
+        assert_eq!(5, file.read_at(0, &mut buf).expect("read"));
+        assert_eq!(5, file.read_at(5, &mut buf).expect("read"));
+        assert_eq!(0, file.read_errors());
+
+        let mut reader = InterruptingReader(b"hello world", 4, 0);
+        let mut file = LazyFile::from_reader(Path::new("stream"), &mut reader, &settings);
+        assert_eq!(io::ErrorKind::Interrupted, file.read_at(0, &mut buf).unwrap_err().kind());
+        assert_eq!(1, file.read_errors());
+
+        // Comparisons give up too, rather than retrying each failed read
+        let dir = tempdir::TempDir::new("lazyfile").expect("tmp");
+        let path = dir.path().join("a");
+        fs::write(&path, "hello world").expect("write");
+        let mut reader = InterruptingReader(b"hello world", u32::MAX, 0);
+        let err = crate::hasher::Hasher::new().compare_with_reader(11, &path, &mut reader, &settings).unwrap_err();
+        assert_eq!(io::ErrorKind::Interrupted, err.kind());
+    }
+}
@@ -1,15 +1,73 @@
+// The types are constructed with `new()`, they have no meaningful `Default`
+#![allow(clippy::new_without_default)]
+
+mod cache;
 mod file;
 mod hasher;
+mod journal;
 #[cfg(feature = "json")]
 mod json;
 mod lazyfile;
+mod link;
 mod metadata;
+mod reflink;
 mod scanner;
+mod sparse;
+mod streams;
 mod ui;
+mod volume;
 
+pub use crate::cache::HashCache;
 pub use crate::file::FileContent;
+pub use crate::file::FileSet;
+pub use crate::file::FirstChunkIndex;
+pub use crate::file::GroupPolicy;
+pub use crate::file::HashMemo;
+#[cfg(feature = "json")]
+pub use crate::file::HashState;
+pub use crate::hasher::BufferBudget;
+pub use crate::hasher::ChunkDigest;
+pub use crate::hasher::content_chunks;
+pub use crate::hasher::ErrorLog;
+pub use crate::hasher::fingerprint_file;
+pub use crate::hasher::hash_file;
+pub use crate::hasher::HashAlgorithm;
+pub use crate::hasher::HashSettings;
+pub use crate::hasher::OpenFileLimit;
+pub use crate::hasher::RateLimit;
+pub use crate::hasher::ReadRetries;
+pub use crate::hasher::shared_bytes;
+pub use crate::hasher::ShareMode;
+pub use crate::journal::undo;
+pub use crate::journal::Journal;
+pub use crate::journal::JournalEntry;
+pub use crate::journal::UndoSummary;
 #[cfg(feature = "json")]
 pub use crate::json::JsonOutput;
+pub use crate::lazyfile::FileError;
+pub use crate::lazyfile::LazyFile;
+pub use crate::link::CopyOptions;
+pub use crate::link::CrossDeviceFallback;
+pub use crate::link::DeletionPlan;
+pub use crate::link::DeletionSummary;
+pub use crate::link::LinkAction;
+pub use crate::link::LinkScope;
+pub use crate::link::LinkSummary;
+pub use crate::link::MasterPolicy;
+pub use crate::link::PlannedAction;
+pub use crate::link::ReflinkFallback;
+pub use crate::link::SizeMode;
+pub use crate::metadata::Metadata;
+pub use crate::metadata::MetadataCache;
+pub use crate::scanner::EmptyFiles;
+pub use crate::scanner::ReparsePoints;
 pub use crate::scanner::RunMode;
+pub use crate::scanner::ScanListener;
 pub use crate::scanner::Scanner;
+pub use crate::scanner::Stats;
+pub use crate::sparse::allocated_size;
+pub use crate::ui::human_size;
 pub use crate::ui::UI as TextUserInterface;
+pub use crate::volume::filesystem_type;
+pub use crate::volume::FilesystemCache;
+pub use crate::volume::FilesystemType;
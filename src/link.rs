@@ -0,0 +1,1045 @@
+use crate::file::{path_starts_with, FileSet};
+use crate::lazyfile::FileError;
+use crate::metadata::{FileIdentity, Metadata};
+use crate::reflink::{clone_file, clone_unsupported};
+use crate::sparse;
+use crate::sparse::allocated_size;
+use crate::volume::{filesystem_type, FilesystemType};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Created next to the file it replaces, and renamed over it
+const TEMP_NAME: &str = ".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221";
+/// Original of a replaced file, followed by the hash of its name (see `backup_path`)
+const BACKUP_NAME: &str = ".tmp-dupe-original-e1iIQcBFn5pC4MUSm-";
+
+/// Which path of a `FileSet` is the master, which is kept as it is, and which the other paths are linked to
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub enum MasterPolicy {
+    /// The first path
+    #[default]
+    First,
+    /// The path with the earliest modification time. Ties are broken by path, and files without one are last.
+    OldestMtime,
+    /// The path with the latest modification time, with ties broken like in `OldestMtime`
+    NewestMtime,
+    /// The path within the first of these directories that has any of the paths, e.g. `C:\Canonical` before `C:\Cache`.
+    /// Paths outside all of them are last, and ties are broken by path.
+    PathPriority(Vec<PathBuf>),
+}
+
+/// How file sizes are counted
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum SizeMode {
+    /// File lengths, like `du --apparent-size`
+    #[default]
+    Logical,
+    /// Space allocated on disk, like `du`, which is less for sparse and compressed files (see `allocated_size`)
+    Physical,
+}
+
+/// Which paths of a group of duplicates are linked to each other
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum LinkScope {
+    /// All of them, to one master
+    #[default]
+    Global,
+    /// Only paths in the same directory, so that each directory keeps one copy of its own.
+    /// Each directory gets its own master (see `FileSet::split_by_directory`).
+    PerDirectory,
+}
+
+/// What `FileSet::reflink` does with files that can't be cloned
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+pub enum ReflinkFallback {
+    /// Hardlink them instead
+    #[default]
+    Hardlink,
+    /// Leave them, and count them as `LinkSummary::unsupported`
+    Report,
+}
+
+/// What a `PlannedAction` that hardlinks does with paths on another device than the master, which can't be linked to it.
+/// It matters only if duplicates have been looked for across devices (see `GroupPolicy::across_devices`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+pub enum CrossDeviceFallback {
+    /// Fail, like linking does
+    #[default]
+    Fail,
+    /// Replace them with clones of the master where the filesystem can clone across devices (e.g. btrfs subvolumes),
+    /// otherwise with copies of it, listed in `LinkSummary::copied`
+    Copy,
+    /// Like `Copy`, but holes of a sparse master stay holes in the copies
+    SparseCopy,
+}
+
+/// What clones get from the master. Hardlinks and symlinks always share it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+pub struct CopyOptions {
+    /// Modification time of the master, instead of the time of cloning
+    pub preserve_mtime: bool,
+    /// Permissions of the master (on Windows, its read-only, hidden, system and archive attributes)
+    pub preserve_permissions: bool,
+}
+
+/// What linking a `FileSet` has done
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+pub struct LinkSummary {
+    /// Paths replaced with hardlinks, symlinks or clones of the master
+    pub links: usize,
+    /// How many of the `links` are copy-on-write clones
+    pub reflinks: usize,
+    /// Paths left as they were, because the filesystem can't clone them and the fallback is `ReflinkFallback::Report`
+    pub unsupported: usize,
+    /// Paths replaced with copies of the master, because they're on another device (see `CrossDeviceFallback`).
+    /// They aren't counted as `links`.
+    pub copied: Vec<Box<Path>>,
+    /// Paths left as they were, because they already were hardlinks of the master
+    pub already_linked: usize,
+    /// Read-only paths left as they were, because replacing them with links to a writable master would make them writable
+    pub kept_readonly: usize,
+    /// Space freed by the replaced files, counted like `FileSet::bytes_saved`
+    pub bytes_saved: u64,
+    /// Like `bytes_saved`, but counting space allocated on disk
+    pub allocated_bytes_saved: u64,
+}
+
+impl LinkSummary {
+    fn add(&mut self, other: &LinkSummary) {
+        self.links += other.links;
+        self.reflinks += other.reflinks;
+        self.unsupported += other.unsupported;
+        self.copied.extend(other.copied.iter().cloned());
+        self.already_linked += other.already_linked;
+        self.kept_readonly += other.kept_readonly;
+        self.bytes_saved += other.bytes_saved;
+        self.allocated_bytes_saved += other.allocated_bytes_saved;
+    }
+}
+
+impl FileSet {
+    /// Index of the master in `paths`
+    pub fn master(&self, policy: &MasterPolicy) -> io::Result<usize> {
+        match policy {
+            MasterPolicy::First => Ok(0),
+            MasterPolicy::OldestMtime => self.min_by_metadata(|a, b| oldest_first(a.modified, b.modified)),
+            MasterPolicy::NewestMtime => self.min_by_metadata(|a, b| newest_first(a.modified, b.modified)),
+            MasterPolicy::PathPriority(prefixes) => {
+                let rank = |path: &Path| prefixes.iter().position(|prefix| path_starts_with(path, prefix)).unwrap_or(prefixes.len());
+                Ok((0..self.paths.len()).min_by_key(|&i| (rank(&self.paths[i]), &self.paths[i])).unwrap_or(0))
+            },
+        }
+    }
+
+    /// Index of the path with the smallest metadata, with ties broken by path
+    fn min_by_metadata(&self, cmp: impl Fn(&Metadata, &Metadata) -> Ordering) -> io::Result<usize> {
+        let mut metadata = Vec::with_capacity(self.paths.len());
+        for path in &self.paths {
+            metadata.push(Metadata::from_path(path).map_err(|err| FileError::metadata(path, &err).to_io_error())?);
+        }
+        Ok((0..self.paths.len())
+            .min_by(|&a, &b| cmp(&metadata[a], &metadata[b]).then_with(|| self.paths[a].cmp(&self.paths[b])))
+            .unwrap_or(0))
+    }
+
+    /// Moves the master chosen by the policy to the front, where the actions expect it.
+    /// The other paths stay in the same order.
+    pub fn set_master(&mut self, policy: &MasterPolicy) -> io::Result<()> {
+        let master = self.master(policy)?;
+        if master != 0 {
+            let path = self.paths.remove(master);
+            self.paths.insert(0, path);
+        }
+        Ok(())
+    }
+
+    /// Replaces all other paths with hardlinks to the first one, which is the master.
+    /// The paths must have identical content, e.g. be a group of duplicates found in a dry run.
+    ///
+    /// Each path is replaced atomically, so if any step fails, that path is left untouched.
+    /// It stops at the first error, and the paths linked before it are restored (see `PlannedAction::execute`).
+    ///
+    /// If there are more paths than the filesystem allows links to one file, they're linked to several masters
+    /// (see `plans`). Then only the group that has failed is restored.
+    pub fn hardlink(&self) -> io::Result<LinkSummary> {
+        self.execute_plans(LinkAction::Hardlink)
+    }
+
+    /// Like `hardlink`, but replaces the other paths with symlinks to the master, which can be on another device
+    /// (see `GroupPolicy::across_devices`). Relative master paths are linked as-is, so they should be absolute.
+    ///
+    /// On Windows creating symlinks requires the privilege to do so or Developer Mode, otherwise it fails with
+    /// `PermissionDenied`.
+    pub fn symlink(&self) -> io::Result<LinkSummary> {
+        self.execute_plans(LinkAction::Symlink)
+    }
+
+    /// Like `hardlink`, but replaces the other paths with copy-on-write clones of the master, which remain separate files,
+    /// so modifying one doesn't modify the others. It's supported on ReFS, btrfs, XFS and APFS.
+    /// Elsewhere, or if a file can't be cloned, it does the `fallback`.
+    pub fn reflink(&self, fallback: ReflinkFallback) -> io::Result<LinkSummary> {
+        self.execute_plans(LinkAction::Reflink(fallback))
+    }
+
+    fn execute_plans(&self, action: LinkAction) -> io::Result<LinkSummary> {
+        let mut summary = LinkSummary::default();
+        for plan in self.plans(action)? {
+            summary.add(&plan.execute()?);
+        }
+        Ok(summary)
+    }
+
+    /// Like `plan`, but if the action may create hardlinks, and the filesystem of the master limits how many links
+    /// a file can have (see `FilesystemType::max_hardlinks`), the paths are split with `split_by_links`,
+    /// and each part gets its own plan.
+    pub fn plans(&self, action: LinkAction) -> io::Result<Vec<PlannedAction>> {
+        let limit = match self.paths.first() {
+            Some(master) if action.creates_hardlinks() && self.paths.len() > 1 => filesystem_type(master).ok().and_then(|fs_type| fs_type.max_hardlinks()),
+            _ => None,
+        };
+        match limit {
+            Some(limit) => self.split_by_links(limit)?.iter().map(|set| set.plan(action)).collect(),
+            None => Ok(vec![self.plan(action)?]),
+        }
+    }
+
+    /// Space that linking the paths to the master (the first path) would free. Each file is counted once, however many
+    /// of its links are in the set, and only if all of its links are, since otherwise it isn't deleted.
+    /// Hardlinks of the master and files on other devices don't free anything.
+    pub fn bytes_saved(&self, size: SizeMode) -> io::Result<u64> {
+        let master = match self.paths.first() {
+            Some(path) => stat(path)?,
+            None => return Ok(0),
+        };
+        let paths = self.paths[1..].iter().map(|path| Ok((&**path, stat(path)?))).collect::<io::Result<Vec<_>>>()?;
+        total_size(&freed_files(paths.iter().map(|(path, metadata)| (*path, metadata)), &master), size)
+    }
+
+    /// Splits the paths into sets of paths in the same directory, in the order in which the directories first appear.
+    /// The paths in each set stay in the same order, so the master policy has to be applied to each set.
+    pub fn split_by_directory(&self) -> Vec<FileSet> {
+        let mut sets: Vec<FileSet> = Vec::new();
+        for path in &self.paths {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            match sets.iter_mut().find(|set| set.paths[0].parent().unwrap_or_else(|| Path::new("")) == dir) {
+                Some(set) => set.push(path.clone()),
+                None => sets.push(FileSet::new(path.clone(), 0)),
+            }
+        }
+        sets
+    }
+
+    /// Splits the paths into sets in which the first path is the master, and linking the others to it
+    /// won't give it more than `max_links` links. Links the masters already have, including ones outside the set,
+    /// are counted. Paths that are already links of a master don't add any.
+    ///
+    /// `max_hardlinks` of each set is the number of links its master will have.
+    pub fn split_by_links(&self, max_links: u64) -> io::Result<Vec<FileSet>> {
+        let mut sets: Vec<FileSet> = Vec::new();
+        let mut master_metadata = None;
+        for path in &self.paths {
+            let metadata = stat(path)?;
+            if let (Some(set), Some(master)) = (sets.last_mut(), &master_metadata) {
+                if metadata.same_file(master) {
+                    set.push(path.clone());
+                    continue;
+                }
+                if set.max_hardlinks < max_links {
+                    set.max_hardlinks += 1;
+                    set.push(path.clone());
+                    continue;
+                }
+            }
+            sets.push(FileSet::new(path.clone(), metadata.nlink.max(1)));
+            master_metadata = Some(metadata);
+        }
+        Ok(sets)
+    }
+
+    /// What the action would do with the paths, without changing anything. The first path is the master.
+    pub fn plan(&self, action: LinkAction) -> io::Result<PlannedAction> {
+        let mut plan = PlannedAction {
+            action,
+            master: self.paths.first().cloned().unwrap_or_else(|| Path::new("").into()),
+            paths: Vec::new(),
+            bytes_saved: 0,
+            already_linked: 0,
+            copy_options: CopyOptions::default(),
+            cross_device: CrossDeviceFallback::default(),
+            identities: HashMap::new(),
+        };
+        if self.paths.len() < 2 {
+            return Ok(plan);
+        }
+        let master_metadata = stat(&plan.master)?;
+        plan.record_identity(&plan.master.clone(), &master_metadata);
+        let mut paths_metadata = Vec::new();
+        for path in &self.paths[1..] {
+            if *path == plan.master {
+                continue;
+            }
+            let metadata = stat(path)?;
+            if metadata.same_file(&master_metadata) {
+                plan.already_linked += 1;
+                continue;
+            }
+            check_size(path, &metadata, &master_metadata)?;
+            plan.record_identity(path, &metadata);
+            plan.paths.push(path.clone());
+            paths_metadata.push(metadata);
+        }
+        let freed = freed_files(plan.paths.iter().map(|path| &**path).zip(&paths_metadata), &master_metadata);
+        plan.bytes_saved = freed.iter().map(|(_, metadata)| metadata.size).sum();
+        Ok(plan)
+    }
+}
+
+/// How duplicates are replaced
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+pub enum LinkAction {
+    Hardlink,
+    Symlink,
+    Reflink(ReflinkFallback),
+}
+
+impl LinkAction {
+    fn creates_hardlinks(self) -> bool {
+        matches!(self, LinkAction::Hardlink | LinkAction::Reflink(ReflinkFallback::Hardlink))
+    }
+}
+
+/// Paths of a group of duplicates that are going to be replaced, created by `FileSet::plan` or `Scanner::plan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize))]
+pub struct PlannedAction {
+    pub action: LinkAction,
+    /// The file that stays as it is
+    pub master: Box<Path>,
+    /// Paths to be replaced with links to the master
+    pub paths: Vec<Box<Path>>,
+    /// Expected `LinkSummary::bytes_saved`
+    pub bytes_saved: u64,
+    /// Paths that aren't in `paths`, because they're already hardlinks of the master
+    pub already_linked: usize,
+    /// What clones and copies get from the master
+    pub copy_options: CopyOptions,
+    pub cross_device: CrossDeviceFallback,
+    /// Files the master and the paths were when the plan was made
+    #[cfg_attr(feature = "json", serde(skip))]
+    identities: HashMap<Box<Path>, FileIdentity>,
+}
+
+impl PlannedAction {
+    /// Replaces the paths. Files are checked again right before they're replaced, in case they were changed
+    /// since the plan was made: if the master or a path is no longer the same file, with the same modification time
+    /// and size, it fails with `FileError::changed` set.
+    ///
+    /// It's all or nothing: until all paths are replaced, the original files are kept via temporary hardlinks
+    /// (or renamed aside where files can't be hardlinked), and if anything fails, the replaced paths are restored. The error then has `FileError::rolled_back` set.
+    ///
+    /// Windows doesn't allow linking or replacing read-only files, so the read-only attribute of the master and of each
+    /// replaced file is cleared for a moment, and set again afterwards, or when the file is restored, even if it fails
+    /// or panics. Links get the attributes of the master, like everything else about it, so read-only paths aren't
+    /// replaced with links to a writable master (see `LinkSummary::kept_readonly`). Attributes that can't be set again
+    /// are reported on stderr.
+    pub fn execute(&self) -> io::Result<LinkSummary> {
+        let mut summary = LinkSummary {
+            already_linked: self.already_linked,
+            ..LinkSummary::default()
+        };
+        if self.paths.is_empty() {
+            return Ok(summary);
+        }
+        let master_metadata = stat(&self.master)?;
+        self.check_unchanged(&self.master, &master_metadata)?;
+        let _master = if self.action.creates_hardlinks() { Some(Writable::new(&self.master)?) } else { None };
+        self.replace_all(&master_metadata, &mut summary).map(|_| summary)
+    }
+
+    fn replace_all(&self, master_metadata: &Metadata, summary: &mut LinkSummary) -> io::Result<()> {
+        // Unknown filesystems may still support it
+        let reflink_supported = matches!(self.action, LinkAction::Reflink(_)) && {
+            let fs_type = filesystem_type(&self.master).unwrap_or(FilesystemType::Unknown);
+            fs_type.supports_reflink() || fs_type == FilesystemType::Unknown
+        };
+        let mut backups = Vec::new();
+        for path in self.paths.iter() {
+            match self.replace(path, backup_path(path), master_metadata, reflink_supported, summary) {
+                Ok(Some(backup)) => backups.push(backup),
+                Ok(None) => {},
+                Err(err) => return Err(roll_back(&backups, path, &err)),
+            }
+        }
+        // Counted while the backups still have the original files
+        let freed = freed_files(backups.iter().filter(|backup| backup.linked).map(|backup| (&*backup.backup, &backup.metadata)), master_metadata);
+        summary.bytes_saved = total_size(&freed, SizeMode::Logical)?;
+        summary.allocated_bytes_saved = total_size(&freed, SizeMode::Physical).unwrap_or(summary.bytes_saved);
+        for backup in backups {
+            // The original file may still have links outside the set
+            if backup.readonly && stat(&backup.backup).map_or(false, |m| m.nlink > 1) {
+                eprintln!("the original file of {} has other links, which are no longer read-only", backup.path.display());
+            }
+            let _ = fs::remove_file(backup.backup);
+        }
+        Ok(())
+    }
+
+    /// If the path has been replaced, `backup` is the original file (see `keep_original`)
+    fn replace<'a>(&self, path: &'a Path, backup: PathBuf, master_metadata: &Metadata, reflink_supported: bool, summary: &mut LinkSummary) -> io::Result<Option<Backup<'a>>> {
+        let master = &*self.master;
+        let metadata = stat(path)?;
+        // E.g. linked by another plan since this one was made
+        if metadata.same_file(master_metadata) {
+            summary.already_linked += 1;
+            return Ok(None);
+        }
+        self.check_unchanged(path, &metadata)?;
+        if metadata.readonly && !master_metadata.readonly {
+            summary.kept_readonly += 1;
+            return Ok(None);
+        }
+        check_size(path, &metadata, master_metadata)?;
+        let copied = summary.copied.len();
+        let writable = Writable::new(path)?;
+        keep_original(path, &backup, !self.action.creates_hardlinks())?;
+        let res = match self.action {
+            LinkAction::Hardlink => self.hardlink_or_copy(path, &metadata, master_metadata, summary).map(|_| true),
+            LinkAction::Symlink => replace_with_symlink(master, path).map(|_| true),
+            LinkAction::Reflink(fallback) => {
+                let cloned = if reflink_supported {
+                    match replace_with_clone(master, path, &self.copy_options) {
+                        Ok(()) => Ok(true),
+                        Err(err) if !clone_unsupported(&err) => Err(err),
+                        Err(_) => Ok(false),
+                    }
+                } else {
+                    Ok(false)
+                };
+                match cloned {
+                    Ok(true) => {
+                        summary.reflinks += 1;
+                        Ok(true)
+                    },
+                    Ok(false) if fallback == ReflinkFallback::Hardlink => self.hardlink_or_copy(path, &metadata, master_metadata, summary).map(|_| true),
+                    Ok(false) => {
+                        summary.unsupported += 1;
+                        Ok(false)
+                    },
+                    Err(err) => Err(err),
+                }
+            },
+        };
+        match res {
+            Ok(true) => {
+                // Copies aren't links, and don't free anything
+                let linked = copied == summary.copied.len();
+                if linked {
+                    summary.links += 1;
+                }
+                Ok(Some(Backup { path, backup, readonly: writable.replaced(), metadata, linked }))
+            },
+            // The original may have been renamed aside
+            Ok(false) => {
+                restore(path, &backup)?;
+                Ok(None)
+            },
+            // The path may have been replaced by a wrong link
+            Err(err) => {
+                if let Err(err) = restore(path, &backup) {
+                    eprintln!("unable to restore {} from {} due to {}", path.display(), backup.display(), err);
+                    writable.replaced();
+                }
+                Err(err)
+            },
+        }
+    }
+
+    /// Files without IDs can't be checked, other than by their size
+    fn record_identity(&mut self, path: &Path, metadata: &Metadata) {
+        if let Some(identity) = FileIdentity::from_metadata(metadata) {
+            self.identities.insert(path.into(), identity);
+        }
+    }
+
+    fn check_unchanged(&self, path: &Path, metadata: &Metadata) -> io::Result<()> {
+        match self.identities.get(path) {
+            Some(identity) if FileIdentity::from_metadata(metadata).as_ref() != Some(identity) => Err(FileError::replaced(path).to_io_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Paths on other devices are cloned or copied instead, if `cross_device` allows it
+    fn hardlink_or_copy(&self, path: &Path, metadata: &Metadata, master_metadata: &Metadata, summary: &mut LinkSummary) -> io::Result<()> {
+        let master = &*self.master;
+        if self.cross_device == CrossDeviceFallback::Fail || metadata.dev == master_metadata.dev {
+            return replace_with_hardlink(master, path);
+        }
+        match replace_with_clone(master, path, &self.copy_options) {
+            Ok(()) => {
+                summary.reflinks += 1;
+                Ok(())
+            },
+            Err(err) if !clone_unsupported(&err) => Err(err),
+            Err(_) => {
+                replace_with_copy(master, path, &self.copy_options, self.cross_device == CrossDeviceFallback::SparseCopy)?;
+                summary.copied.push(path.into());
+                Ok(())
+            },
+        }
+    }
+}
+
+/// The original file of a replaced path
+struct Backup<'a> {
+    path: &'a Path,
+    backup: PathBuf,
+    /// The original file was read-only, and has been made writable
+    readonly: bool,
+    /// Of the original file
+    metadata: Metadata,
+    /// Replaced with a link rather than a copy
+    linked: bool,
+}
+
+/// Puts the original files back, and returns the error of `path` that caused it
+fn roll_back(backups: &[Backup<'_>], path: &Path, err: &io::Error) -> io::Error {
+    let mut restored = true;
+    for Backup { path, backup, readonly, .. } in backups.iter().rev() {
+        if let Err(err) = restore(path, backup) {
+            eprintln!("unable to restore {} from {} due to {}", path.display(), backup.display(), err);
+            restored = false;
+        } else if *readonly {
+            make_readonly_again(path);
+        }
+    }
+    FileError::new(path, err).with_rollback(restored).to_io_error()
+}
+
+/// Clears the read-only attribute (see `make_writable`), and sets it again when dropped, unless the path has been
+/// replaced since. So it's set again also if replacing fails or panics.
+struct Writable<'a> {
+    path: &'a Path,
+    readonly: bool,
+}
+
+impl<'a> Writable<'a> {
+    fn new(path: &'a Path) -> io::Result<Self> {
+        Ok(Writable { path, readonly: make_writable(path)? })
+    }
+
+    /// The path is no longer the file that was made writable. Returns whether that file was read-only.
+    fn replaced(mut self) -> bool {
+        std::mem::replace(&mut self.readonly, false)
+    }
+}
+
+impl Drop for Writable<'_> {
+    fn drop(&mut self) {
+        if self.readonly {
+            make_readonly_again(self.path);
+        }
+    }
+}
+
+/// Clears the read-only attribute, and returns whether it was set. Only Windows refuses to link or replace
+/// read-only files, so elsewhere nothing is changed.
+#[cfg(windows)]
+fn make_writable(path: &Path) -> io::Result<bool> {
+    let mut permissions = fs::metadata(path).map_err(|err| FileError::metadata(path, &err).to_io_error())?.permissions();
+    if !permissions.readonly() {
+        return Ok(false);
+    }
+    #[allow(clippy::permissions_set_readonly_false)] // only on Windows
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions).map_err(|err| FileError::during(path, "clear the read-only attribute", &err).to_io_error())?;
+    Ok(true)
+}
+
+#[cfg(not(windows))]
+fn make_writable(_: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Failures are only reported, since there's nothing else to do about them
+fn make_readonly_again(path: &Path) {
+    let res = fs::metadata(path).and_then(|m| {
+        let mut permissions = m.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions)
+    });
+    if let Err(err) = res {
+        eprintln!("unable to make {} read-only again due to {}", path.display(), err);
+    }
+}
+
+/// Next to the path, and the same for each run, so that a backup left over by an interrupted run is found
+fn backup_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}{:016x}", BACKUP_NAME, xxh3_64(name.as_bytes())))
+}
+
+/// Keeps the original file at `backup` as a hardlink of it. If it can't be linked (e.g. on FAT or SMB) and `rename`
+/// is allowed, the file is renamed there instead, and `path` is missing until it's replaced.
+///
+/// A backup left over by an interrupted run is removed first. The path still exists, so it's either the original
+/// or a duplicate of it.
+fn keep_original(path: &Path, backup: &Path, rename: bool) -> io::Result<()> {
+    if fs::symlink_metadata(backup).is_ok() {
+        fs::remove_file(backup).map_err(|err| FileError::during(backup, "remove the backup left over", &err).to_io_error())?;
+    }
+    match fs::hard_link(path, backup) {
+        Err(_) if rename => fs::rename(path, backup),
+        res => res,
+    }
+    .map_err(|err| FileError::during(path, "keep the original", &err).to_io_error())
+}
+
+fn restore(path: &Path, backup: &Path) -> io::Result<()> {
+    rename_over(backup, path)?;
+    // Renaming does nothing if both are links of the same file
+    let _ = fs::remove_file(backup);
+    Ok(())
+}
+
+fn stat(path: &Path) -> io::Result<Metadata> {
+    Metadata::from_path(path).map_err(|err| FileError::metadata(path, &err).to_io_error())
+}
+
+/// Fails if the file can't be a duplicate of the master
+fn check_size(path: &Path, metadata: &Metadata, master: &Metadata) -> io::Result<()> {
+    if metadata.size != master.size {
+        return Err(FileError::changed(path, master.size, metadata.size).to_io_error());
+    }
+    Ok(())
+}
+
+/// Files that replacing the paths with links to the master would delete, with one path of each, like `du` counts them.
+/// Each file is counted once, however many of its links are replaced, and only if all of its links are, since
+/// otherwise it isn't deleted. Hardlinks of the master and files on other devices, which can be only copied,
+/// don't free anything.
+fn freed_files<'a>(paths: impl IntoIterator<Item = (&'a Path, &'a Metadata)>, master: &Metadata) -> Vec<(&'a Path, &'a Metadata)> {
+    let mut freed = Vec::new();
+    // Replaced links of each file, with one of its paths
+    let mut files: HashMap<(u64, u128), (&Path, &Metadata, u64)> = HashMap::new();
+    for (path, metadata) in paths {
+        if metadata.same_file(master) || metadata.dev != master.dev {
+            continue;
+        }
+        if metadata.ino == 0 {
+            if metadata.nlink <= 1 {
+                freed.push((path, metadata));
+            }
+            continue;
+        }
+        files.entry((metadata.dev, metadata.ino)).or_insert((path, metadata, 0)).2 += 1;
+    }
+    freed.extend(files.into_values().filter(|&(_, metadata, links)| metadata.nlink <= links).map(|(path, metadata, _)| (path, metadata)));
+    freed
+}
+
+fn total_size(files: &[(&Path, &Metadata)], size: SizeMode) -> io::Result<u64> {
+    files.iter().map(|&(path, metadata)| match size {
+        SizeMode::Logical => Ok(metadata.size),
+        SizeMode::Physical => Ok(allocated_size(path, &fs::metadata(path).map_err(|err| FileError::metadata(path, &err).to_io_error())?)),
+    }).sum()
+}
+
+/// Missing times are last
+fn oldest_first(a: Option<SystemTime>, b: Option<SystemTime>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    }
+}
+
+/// Missing times are last too
+fn newest_first(a: Option<SystemTime>, b: Option<SystemTime>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => b.cmp(&a),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    }
+}
+
+/// Paths that would be deleted, so that they can be shown before anything is deleted. Created by `FileSet::plan_deletion`.
+#[derive(Debug, Clone)]
+pub struct DeletionPlan {
+    /// The copy that is kept
+    pub master: Box<Path>,
+    pub delete: Vec<Box<Path>>,
+}
+
+/// What `DeletionPlan::execute` has done
+#[derive(Debug, Default, Clone)]
+pub struct DeletionSummary {
+    pub deleted: usize,
+    /// Space freed by the deleted files. Files that still have hardlinks elsewhere don't free anything.
+    pub bytes_saved: u64,
+    /// Files that weren't deleted, because they no longer had the same content as the master
+    pub changed: Vec<Box<Path>>,
+    /// Files that weren't deleted, because they're the master found under another path, e.g. through a symlinked directory
+    pub aliases: Vec<Box<Path>>,
+}
+
+impl FileSet {
+    /// All paths but the first one, to be deleted with `DeletionPlan::execute`.
+    /// The paths must have identical content, e.g. be a group of duplicates found in a dry run.
+    pub fn plan_deletion(&self) -> DeletionPlan {
+        let master = self.paths.first().cloned().unwrap_or_else(|| Path::new("").into());
+        let delete = self.paths.iter().skip(1).filter(|p| **p != master).cloned().collect();
+        DeletionPlan { master, delete }
+    }
+}
+
+impl DeletionPlan {
+    /// Deletes the files. Nothing is done unless `confirmed` is set, since deleted files can't be restored.
+    ///
+    /// Each file is compared byte by byte with the master immediately before it's deleted,
+    /// and files that differ are kept. Paths that resolve to the master itself are kept too. It stops at the first error.
+    pub fn execute(&self, confirmed: bool) -> io::Result<DeletionSummary> {
+        if !confirmed {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "deletion of duplicates hasn't been confirmed"));
+        }
+        let master = fs::canonicalize(&self.master).map_err(|err| FileError::during(&self.master, "resolve", &err).to_io_error())?;
+        let mut summary = DeletionSummary::default();
+        for path in &self.delete {
+            if fs::canonicalize(path).map_err(|err| FileError::during(path, "resolve", &err).to_io_error())? == master {
+                summary.aliases.push(path.clone());
+                continue;
+            }
+            let metadata = Metadata::from_path(path).map_err(|err| FileError::metadata(path, &err).to_io_error())?;
+            if !same_content(&self.master, path)? {
+                summary.changed.push(path.clone());
+                continue;
+            }
+            fs::remove_file(path).map_err(|err| FileError::during(path, "delete", &err).to_io_error())?;
+            summary.deleted += 1;
+            if metadata.nlink <= 1 {
+                summary.bytes_saved += metadata.size;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Reads both files in full, without relying on any hashes computed earlier
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a_file = fs::File::open(a).map_err(|err| FileError::during(a, "open", &err).to_io_error())?;
+    let mut b_file = fs::File::open(b).map_err(|err| FileError::during(b, "open", &err).to_io_error())?;
+    if a_file.metadata()?.len() != b_file.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut a_buf = vec![0; 1 << 16];
+    let mut b_buf = vec![0; 1 << 16];
+    loop {
+        let a_len = fill(&mut a_file, &mut a_buf).map_err(|err| FileError::new(a, &err).to_io_error())?;
+        let b_len = fill(&mut b_file, &mut b_buf).map_err(|err| FileError::new(b, &err).to_io_error())?;
+        if a_buf[..a_len] != b_buf[..b_len] {
+            return Ok(false);
+        }
+        if a_len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads until the buffer is full or the file ends
+fn fill(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Makes `dest` a hardlink of `source`, via a temporary link renamed over `dest`.
+/// On Windows these are `CreateHardLinkW` and `MoveFileExW` with `MOVEFILE_REPLACE_EXISTING`.
+/// In POSIX link guarantees not to overwrite, and rename guarantees to replace atomically, so `dest` never stops
+/// existing, and it's unchanged if this fails.
+pub(crate) fn replace_with_hardlink(source: &Path, dest: &Path) -> io::Result<()> {
+    replace_via_temp(dest, |temp_path| fs::hard_link(source, temp_path))?;
+    // Filesystems without file IDs can't be checked
+    let (source_metadata, dest_metadata) = (Metadata::from_path(source)?, Metadata::from_path(dest)?);
+    if dest_metadata.ino != 0 && !source_metadata.same_file(&dest_metadata) {
+        let err = io::Error::new(io::ErrorKind::Other, "it leads to another file");
+        return Err(FileError::during(dest, "hardlink", &err).to_io_error());
+    }
+    Ok(())
+}
+
+/// Replaces `to` atomically, so that it never stops existing
+#[cfg(windows)]
+fn rename_over(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{MoveFileExW, MOVEFILE_REPLACE_EXISTING};
+
+    let wide = |path: &Path| -> Vec<u16> { crate::lazyfile::open_path(path).as_os_str().encode_wide().chain(Some(0)).collect() };
+    if unsafe { MoveFileExW(wide(from).as_ptr(), wide(to).as_ptr(), MOVEFILE_REPLACE_EXISTING) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// POSIX rename is atomic
+#[cfg(not(windows))]
+fn rename_over(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)
+}
+
+/// Like `replace_with_hardlink`, but `dest` becomes a clone of `source`
+fn replace_with_clone(source: &Path, dest: &Path, options: &CopyOptions) -> io::Result<()> {
+    replace_via_temp(dest, |temp_path| clone_file(source, temp_path).and_then(|_| preserve(source, temp_path, options)))
+}
+
+/// Like `replace_with_clone`, but `dest` becomes a copy of `source`. If `sparse`, only the data ranges are written.
+pub(crate) fn replace_with_copy(source: &Path, dest: &Path, options: &CopyOptions, sparse: bool) -> io::Result<()> {
+    replace_via_temp(dest, |temp_path| {
+        let copied = if sparse { copy_sparse(source, temp_path) } else { fs::copy(source, temp_path).map(|_| ()) };
+        copied.and_then(|_| preserve(source, temp_path, options))
+    })
+}
+
+/// Creates the replacement next to `dest` with `create`, and renames it over `dest`.
+/// If either fails, the temporary file is removed, and `dest` is left as it was.
+fn replace_via_temp(dest: &Path, create: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let temp_path = dest.with_file_name(TEMP_NAME);
+    if let Err(err) = create(&temp_path).and_then(|_| rename_over(&temp_path, dest)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn copy_sparse(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+
+    let mut source_file = fs::File::open(source)?;
+    let mut dest_file = fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+    let len = source_file.metadata()?.len();
+    sparse::set_sparse(&dest_file)?;
+    for (start, end) in sparse::data_ranges(&source_file, 0, len)? {
+        source_file.seek(SeekFrom::Start(start))?;
+        dest_file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut (&mut source_file).take(end - start), &mut dest_file)?;
+    }
+    dest_file.set_len(len)
+}
+
+/// Like `replace_with_hardlink`, but `dest` becomes a symlink to `source`. The link gets an absolute path,
+/// since a relative one would be resolved from the directory of `dest`.
+fn replace_with_symlink(source: &Path, dest: &Path) -> io::Result<()> {
+    let source = if source.is_relative() {
+        std::env::current_dir().map_err(|err| FileError::during(source, "make the path absolute", &err).to_io_error())?.join(source)
+    } else {
+        source.to_path_buf()
+    };
+    replace_via_temp(dest, |temp_path| symlink_file(&source, temp_path))?;
+    let followed = |path: &Path| Metadata::from_followed_path(path).map_err(|err| FileError::metadata(path, &err).to_io_error());
+    let (source_metadata, dest_metadata) = (followed(&source)?, followed(dest)?);
+    if dest_metadata.ino != 0 && !source_metadata.same_file(&dest_metadata) {
+        let err = io::Error::new(io::ErrorKind::Other, "it leads to another file");
+        return Err(FileError::during(dest, "symlink", &err).to_io_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink_file(source: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(source, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(source: &Path, link: &Path) -> io::Result<()> {
+    use windows_sys::Win32::Foundation::ERROR_PRIVILEGE_NOT_HELD;
+
+    std::os::windows::fs::symlink_file(source, link).map_err(|err| {
+        if err.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD as i32) {
+            let err = io::Error::new(io::ErrorKind::PermissionDenied, "creating symlinks requires Developer Mode or the SeCreateSymbolicLinkPrivilege");
+            FileError::during(link, "symlink", &err).to_io_error()
+        } else {
+            err
+        }
+    })
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink_file(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Makes the copy look like the master, as far as `options` say
+fn preserve(master: &Path, copy: &Path, options: &CopyOptions) -> io::Result<()> {
+    let metadata = fs::metadata(master)?;
+    // Before permissions, since times of read-only files can't be changed on Windows
+    if options.preserve_mtime {
+        set_modified(copy, metadata.modified()?)?;
+    }
+    if options.preserve_permissions {
+        set_permissions(copy, &metadata)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let omit = libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT };
+    let mtime = libc::timespec {
+        tv_sec: since_epoch.as_secs() as libc::time_t,
+        tv_nsec: since_epoch.subsec_nanos() as _,
+    };
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), [omit, mtime].as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use std::time::Duration;
+    use windows_sys::Win32::Foundation::FILETIME;
+    use windows_sys::Win32::Storage::FileSystem::SetFileTime;
+
+    // FILETIME counts 100ns intervals since 1601
+    let since_1601 = time.duration_since(SystemTime::UNIX_EPOCH - Duration::from_secs(11_644_473_600)).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let intervals = since_1601.as_nanos() / 100;
+    let mtime = FILETIME {
+        dwLowDateTime: intervals as u32,
+        dwHighDateTime: (intervals >> 32) as u32,
+    };
+    let file = fs::OpenOptions::new().write(true).open(path)?;
+    if unsafe { SetFileTime(file.as_raw_handle(), ptr::null(), ptr::null(), &mtime) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn set_modified(_: &Path, _: SystemTime) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(windows)]
+fn set_permissions(path: &Path, master: &fs::Metadata) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::{SetFileAttributesW, FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_NORMAL, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_SYSTEM};
+
+    let attributes = master.file_attributes() & (FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM | FILE_ATTRIBUTE_ARCHIVE);
+    let wide: Vec<u16> = crate::lazyfile::open_path(path).as_os_str().encode_wide().chain(Some(0)).collect();
+    if unsafe { SetFileAttributesW(wide.as_ptr(), if attributes == 0 { FILE_ATTRIBUTE_NORMAL } else { attributes }) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn set_permissions(path: &Path, master: &fs::Metadata) -> io::Result<()> {
+    fs::set_permissions(path, master.permissions())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn preserve_mtime_and_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempdir::TempDir::new("preservetest").expect("tmp");
+        let master = tmp.path().join("master");
+        let copy = tmp.path().join("copy");
+        fs::write(&master, "hello").expect("write");
+        fs::write(&copy, "hello").expect("write");
+        let old = SystemTime::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+        set_modified(&master, old).expect("mtime");
+        fs::set_permissions(&master, fs::Permissions::from_mode(0o640)).expect("chmod");
+
+        preserve(&master, &copy, &CopyOptions::default()).expect("preserve");
+        assert_ne!(old, fs::metadata(&copy).expect("stat").modified().expect("mtime"));
+
+        preserve(&master, &copy, &CopyOptions { preserve_mtime: true, preserve_permissions: true }).expect("preserve");
+        let metadata = fs::metadata(&copy).expect("stat");
+        assert_eq!(old, metadata.modified().expect("mtime"));
+        assert_eq!(0o640, metadata.permissions().mode() & 0o777);
+    }
+
+    #[test]
+    fn sparse_copy() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let tmp = tempdir::TempDir::new("copytest").expect("tmp");
+        let master = tmp.path().join("master");
+        let copy = tmp.path().join("copy");
+        let mut file = fs::File::create(&master).expect("create");
+        file.seek(SeekFrom::Start(1 << 20)).expect("seek");
+        file.write_all(b"hello").expect("write");
+        file.set_len(2 << 20).expect("len");
+        fs::write(&copy, vec![1; 100]).expect("write");
+
+        replace_with_copy(&master, &copy, &CopyOptions::default(), true).expect("copy");
+        assert_eq!(fs::read(&master).expect("read"), fs::read(&copy).expect("read"));
+        assert!(crate::sparse::allocated_size(&copy, &fs::metadata(&copy).expect("stat")) < 1 << 20);
+        assert_eq!(2, fs::read_dir(tmp.path()).expect("dir").count());
+    }
+
+    #[test]
+    fn changed_after_planning() {
+        let tmp = tempdir::TempDir::new("changedtest").expect("tmp");
+        let master = tmp.path().join("master");
+        let copy = tmp.path().join("copy");
+        fs::write(&master, "hello").expect("write");
+        fs::write(&copy, "hello").expect("write");
+        let mut set = FileSet::new(master.into(), 0);
+        set.push(copy.clone().into());
+        let plan = set.plan(LinkAction::Hardlink).expect("plan");
+
+        // Same size, so only the modification time tells
+        fs::write(&copy, "world").expect("write");
+        set_modified(&copy, SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000)).expect("mtime");
+        let err = plan.execute().expect_err("changed");
+        assert!(err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).expect("file error").changed);
+        assert_eq!(b"world"[..], fs::read(&copy).expect("read")[..]);
+    }
+
+    #[test]
+    fn leftover_backup() {
+        let tmp = tempdir::TempDir::new("leftovertest").expect("tmp");
+        let master = tmp.path().join("master");
+        let copy = tmp.path().join("copy");
+        fs::write(&master, "hello").expect("write");
+        fs::write(&copy, "hello").expect("write");
+        // As if an earlier run had been killed while replacing the copy
+        let leftover = backup_path(&copy);
+        fs::hard_link(&copy, &leftover).expect("link");
+        let mut set = FileSet::new(master.clone().into(), 0);
+        set.push(copy.clone().into());
+
+        let summary = set.plan(LinkAction::Hardlink).expect("plan").execute().expect("execute");
+        assert_eq!(1, summary.links);
+        assert!(Metadata::from_path(&master).expect("master").same_file(&Metadata::from_path(&copy).expect("copy")));
+        assert!(fs::symlink_metadata(&leftover).is_err());
+    }
+}
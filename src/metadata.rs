@@ -1,24 +1,392 @@
+use crate::sparse;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::time::SystemTime;
 
-#[derive(Copy, Clone, Hash, Ord, PartialOrd, PartialEq, Eq, Debug, Default)]
+/// Files are grouped by content only if their metadata is equal. Permissions and modification times aren't
+/// included, so copies restored from different backups are still found to be duplicates.
+///
+/// Metadata is ordered by size, then by device. Nothing else is compared, so metadata of files that may be
+/// duplicates is equal, and then files are ordered by their content. Use `tie_break` for a deterministic order
+/// of such files that doesn't depend on the order in which they were scanned.
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
+#[cfg_attr(feature = "json", serde(default))]
 pub struct Metadata {
     pub dev: u64,
     pub size: u64,
+    /// File ID within the device. It's 0 on filesystems that don't have stable IDs. Not compared.
+    /// It has 128 bits, since IDs of ReFS files do.
+    pub ino: u128,
+    /// Number of hardlinks of the file, including this one. Not compared.
+    pub nlink: u64,
+    /// A junction, symlink or another Windows reparse point. Not compared.
+    pub reparse_point: bool,
+    /// Owner user and group. On Windows they're 0, unless `GroupPolicy::compare_owners` is set, and then
+    /// `FileContent::with_grouping` sets them to numbers that stand for the owner and group SIDs within this process.
+    /// Not compared, unless `GroupPolicy::compare_owners` is set.
+    pub uid: u32,
+    pub gid: u32,
+    /// Not writable. On Unix it means no write permission for anyone. Not compared, unless `GroupPolicy::compare_attributes` is set.
+    pub readonly: bool,
+    /// Windows hidden attribute. Not compared, unless `GroupPolicy::compare_attributes` is set.
+    pub hidden: bool,
+    /// Windows system attribute. Not compared, unless `GroupPolicy::compare_attributes` is set.
+    pub system: bool,
+    /// Stored with NTFS compression. Not compared.
+    pub compressed: bool,
+    /// Has fewer bytes allocated than its length. Not compared.
+    pub sparse: bool,
+    /// Creation time, if the platform and filesystem have it (e.g. Windows and macOS).
+    /// It's informational, e.g. for picking the oldest copy, and isn't compared.
+    pub created: Option<SystemTime>,
+    /// Modification time, e.g. for picking the master of duplicates. Not compared.
+    pub modified: Option<SystemTime>,
 }
 
 impl Metadata {
+    fn key(&self) -> (u64, u64) {
+        (self.size, self.dev)
+    }
+}
+
+impl PartialEq for Metadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Metadata {}
+
+impl Hash for Metadata {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl PartialOrd for Metadata {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Metadata {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Metadata {
+    /// Orders metadata that is equal according to `cmp`: oldest first, files without creation time last,
+    /// then by file ID. It's stable across runs as long as the files don't change.
+    pub fn tie_break(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+            .then_with(|| match (self.created, other.created) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            })
+            .then_with(|| self.ino.cmp(&other.ino))
+    }
+
+    /// Both are hardlinks of the same file. Files without IDs are never known to be the same.
+    pub fn same_file(&self, other: &Self) -> bool {
+        self.ino != 0 && self.ino == other.ino && self.dev == other.dev
+    }
+
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let path = path.as_ref();
         let m = fs::symlink_metadata(path)?;
-        Ok(Self::new(&m))
+        Ok(Self::new(&m).with_index(FileIndex::new(path, &m)?))
+    }
+
+    /// Like `from_path`, but of the file the path leads to, following symlinks
+    pub(crate) fn from_followed_path(path: &Path) -> Result<Self, io::Error> {
+        let m = fs::metadata(path)?;
+        Ok(Self::new(&m).with_index(FileIndex::new(path, &m)?))
     }
 
+    /// Sets the device, file ID and number of links
+    pub(crate) fn with_index(mut self, index: FileIndex) -> Self {
+        self.dev = index.dev;
+        self.ino = index.ino;
+        self.nlink = index.nlink;
+        self
+    }
+
+    /// On Windows `fs::Metadata` doesn't have the device, file ID and number of links,
+    /// so they're left as 0, 0 and 1. `from_path` has them everywhere.
     pub fn new(m: &fs::Metadata) -> Self {
+        let (hidden, system) = hidden_system(m);
+        let (uid, gid) = owner(m);
+        let index = FileIndex::from_metadata(m);
         Metadata {
+            dev: index.dev,
+            size: m.len(),
+            ino: index.ino,
+            nlink: index.nlink,
+            reparse_point: is_reparse_point(m),
+            uid,
+            gid,
+            readonly: m.permissions().readonly(),
+            hidden,
+            system,
+            compressed: is_compressed(m),
+            sparse: sparse::is_sparse(m),
+            created: m.created().ok(),
+            modified: m.modified().ok(),
+        }
+    }
+}
+
+/// Where the file is, and how many links it has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileIndex {
+    pub dev: u64,
+    /// 0 if the filesystem doesn't have file IDs
+    pub ino: u128,
+    pub nlink: u64,
+}
+
+impl FileIndex {
+    /// Only what's in `fs::Metadata`, which on Windows is nothing
+    #[cfg(unix)]
+    pub fn from_metadata(m: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        FileIndex {
             dev: m.dev(),
-            size: m.size(),
+            ino: u128::from(m.ino()),
+            nlink: m.nlink(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_metadata(_: &fs::Metadata) -> Self {
+        FileIndex { dev: 0, ino: 0, nlink: 1 }
+    }
+
+    /// `m` must be the metadata of the path
+    #[cfg(not(windows))]
+    pub fn new(_: &Path, m: &fs::Metadata) -> io::Result<Self> {
+        Ok(Self::from_metadata(m))
+    }
+
+    /// The volume serial number and `nNumberOfLinks` of `GetFileInformationByHandle`, and the 128-bit file ID
+    /// of `FILE_ID_INFO`. The 64-bit file index is used only if the filesystem doesn't have `FILE_ID_INFO`,
+    /// since it's not unique on ReFS. The file is opened without any access to its content.
+    /// If `m` is of a reparse point (from `symlink_metadata`), the reparse point itself is opened, otherwise it's followed.
+    #[cfg(windows)]
+    pub fn new(path: &Path, m: &fs::Metadata) -> io::Result<Self> {
+        use std::mem;
+        use std::os::windows::fs::OpenOptionsExt;
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::Storage::FileSystem::{
+            FileIdInfo, GetFileInformationByHandle, GetFileInformationByHandleEx, BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT, FILE_ID_INFO,
+            FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+        };
+
+        let file = fs::OpenOptions::new()
+            .access_mode(0)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+            // Directories can be opened only with backup semantics
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | if is_reparse_point(m) { FILE_FLAG_OPEN_REPARSE_POINT } else { 0 })
+            .open(crate::lazyfile::open_path(path))?;
+        let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+        if unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut info) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut id: FILE_ID_INFO = unsafe { mem::zeroed() };
+        let has_id = unsafe { GetFileInformationByHandleEx(file.as_raw_handle(), FileIdInfo, &mut id as *mut FILE_ID_INFO as *mut _, mem::size_of::<FILE_ID_INFO>() as u32) } != 0;
+        Ok(FileIndex {
+            dev: u64::from(info.dwVolumeSerialNumber),
+            ino: if has_id { u128::from_le_bytes(id.FileId.Identifier) } else { u128::from(info.nFileIndexHigh) << 32 | u128::from(info.nFileIndexLow) },
+            nlink: u64::from(info.nNumberOfLinks),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn owner(m: &fs::Metadata) -> (u32, u32) {
+    use std::os::unix::fs::MetadataExt;
+    (m.uid(), m.gid())
+}
+
+#[cfg(not(unix))]
+fn owner(_: &fs::Metadata) -> (u32, u32) {
+    (0, 0)
+}
+
+/// Owner and group SIDs of the file, numbered in the order they're first seen by this process. `fs::Metadata`
+/// doesn't have them. If they can't be read, the file gets numbers of its own, so it's never equal to any other.
+#[cfg(windows)]
+pub(crate) fn owner_sids(path: &Path) -> (u32, u32) {
+    use std::iter::once;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::{GetNamedSecurityInfoW, SE_FILE_OBJECT};
+    use windows_sys::Win32::Security::{GetLengthSid, GROUP_SECURITY_INFORMATION, OWNER_SECURITY_INFORMATION, PSID};
+
+    let wide: Vec<u16> = crate::lazyfile::open_path(path).as_os_str().encode_wide().chain(once(0)).collect();
+    let (mut owner, mut group, mut descriptor) = (null_mut(), null_mut(), null_mut());
+    let info = OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION;
+    let err = unsafe { GetNamedSecurityInfoW(wide.as_ptr(), SE_FILE_OBJECT, info, &mut owner, &mut group, null_mut(), null_mut(), &mut descriptor) };
+    if err != 0 {
+        return (sid_number(None), sid_number(None));
+    }
+    // The SIDs point into the descriptor
+    let sid = |sid: PSID| (!sid.is_null()).then(|| unsafe { std::slice::from_raw_parts(sid as *const u8, GetLengthSid(sid) as usize) });
+    let numbers = (sid_number(sid(owner)), sid_number(sid(group)));
+    unsafe { LocalFree(descriptor) };
+    numbers
+}
+
+/// Numbers SIDs, since they don't fit in `uid` and `gid`. `None` gets a new number every time.
+#[cfg(windows)]
+fn sid_number(sid: Option<&[u8]>) -> u32 {
+    use std::sync::Mutex;
+    static SIDS: Mutex<Vec<Box<[u8]>>> = Mutex::new(Vec::new());
+    let mut sids = SIDS.lock().unwrap_or_else(|err| err.into_inner());
+    let known = sid.and_then(|sid| sids.iter().position(|known| **known == *sid));
+    let index = known.unwrap_or_else(|| {
+        sids.push(sid.unwrap_or_default().into());
+        sids.len() - 1
+    });
+    index as u32 + 1
+}
+
+/// `Metadata` of paths, fetched once per path. Paths that are known to have changed have to be invalidated.
+#[derive(Debug, Default)]
+pub struct MetadataCache {
+    paths: HashMap<Box<Path>, Metadata>,
+}
+
+impl MetadataCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Doesn't follow symlinks, like `Metadata::from_path`
+    pub fn get(&mut self, path: &Path) -> Result<Metadata, io::Error> {
+        if let Some(m) = self.paths.get(path) {
+            return Ok(*m);
         }
+        let m = Metadata::from_path(path)?;
+        self.paths.insert(path.into(), m);
+        Ok(m)
+    }
+
+    /// Remember metadata that's already known, e.g. from a directory listing
+    pub fn insert(&mut self, path: &Path, metadata: Metadata) {
+        self.paths.insert(path.into(), metadata);
+    }
+
+    /// The path has been modified or replaced, so it has to be read again
+    pub fn invalidate(&mut self, path: &Path) {
+        self.paths.remove(path);
+    }
+
+    pub fn clear(&mut self) {
+        self.paths.clear();
+    }
+}
+
+/// Junctions, symlinks and other reparse points, which may lead to data that's also reachable elsewhere
+#[cfg(windows)]
+pub fn is_reparse_point(m: &fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+    m.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0
+}
+
+#[cfg(not(windows))]
+pub fn is_reparse_point(_: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(windows)]
+fn hidden_system(m: &fs::Metadata) -> (bool, bool) {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::{FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM};
+    let attributes = m.file_attributes();
+    (attributes & FILE_ATTRIBUTE_HIDDEN != 0, attributes & FILE_ATTRIBUTE_SYSTEM != 0)
+}
+
+#[cfg(not(windows))]
+fn hidden_system(_: &fs::Metadata) -> (bool, bool) {
+    (false, false)
+}
+
+#[cfg(windows)]
+fn is_compressed(m: &fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_COMPRESSED;
+    m.file_attributes() & FILE_ATTRIBUTE_COMPRESSED != 0
+}
+
+/// Transparent compression of other filesystems isn't visible in the metadata
+#[cfg(not(windows))]
+fn is_compressed(_: &fs::Metadata) -> bool {
+    false
+}
+
+/// Identifies the file and its version, regardless of the path it was found under.
+/// Hardlinks of the same file have the same identity.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde_derive::Serialize, serde_derive::Deserialize))]
+pub(crate) struct FileIdentity {
+    dev: u64,
+    ino: u128,
+    mtime: (i64, i64),
+    size: u64,
+}
+
+impl FileIdentity {
+    /// `None` if the filesystem doesn't have file IDs (reports zero), which happens on some network shares.
+    /// The modification time and size are included, since such filesystems may also reuse IDs for different files.
+    pub fn new(index: &FileIndex, m: &fs::Metadata) -> Option<Self> {
+        if index.ino == 0 {
+            return None;
+        }
+        Some(FileIdentity {
+            dev: index.dev,
+            ino: index.ino,
+            mtime: mtime(m),
+            size: m.len(),
+        })
+    }
+
+    /// The same as `new`, from metadata that has been read already
+    pub fn from_metadata(m: &Metadata) -> Option<Self> {
+        if m.ino == 0 {
+            return None;
+        }
+        let mtime = match m.modified.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+            Some(d) => (d.as_secs() as i64, i64::from(d.subsec_nanos())),
+            None => (0, 0),
+        };
+        Some(FileIdentity {
+            dev: m.dev,
+            ino: m.ino,
+            mtime,
+            size: m.size,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn mtime(m: &fs::Metadata) -> (i64, i64) {
+    use std::os::unix::fs::MetadataExt;
+    (m.mtime(), m.mtime_nsec())
+}
+
+#[cfg(not(unix))]
+fn mtime(m: &fs::Metadata) -> (i64, i64) {
+    match m.modified().ok().and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+        Some(d) => (d.as_secs() as i64, i64::from(d.subsec_nanos())),
+        None => (0, 0),
     }
 }
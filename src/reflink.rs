@@ -0,0 +1,127 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Creates a new file at `dest` that shares its data with `source` (copy-on-write), so it takes no extra space
+/// until either one is modified. `dest` must not exist. Fails if the filesystem can't do that.
+#[cfg(target_os = "linux")]
+pub fn clone_file(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source_file = fs::File::open(source)?;
+    let dest_file = fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+    if unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, source_file.as_raw_fd()) } != 0 {
+        let err = io::Error::last_os_error();
+        drop(dest_file);
+        let _ = fs::remove_file(dest);
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn clone_file(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err));
+    let (source, dest) = (c_path(source)?, c_path(dest)?);
+    if unsafe { libc::clonefile(source.as_ptr(), dest.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// ReFS clones whole clusters, so the destination gets the source's size first, and the ranges are rounded up to clusters
+#[cfg(windows)]
+pub fn clone_file(source: &Path, dest: &Path) -> io::Result<()> {
+    let source_file = fs::File::open(source)?;
+    let dest_file = fs::OpenOptions::new().write(true).create_new(true).open(dest)?;
+    let res = clone_extents(source, &source_file, &dest_file);
+    if res.is_err() {
+        drop(dest_file);
+        let _ = fs::remove_file(dest);
+    }
+    res
+}
+
+#[cfg(windows)]
+fn clone_extents(source: &Path, source_file: &fs::File, dest_file: &fs::File) -> io::Result<()> {
+    use std::mem::size_of;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::MetadataExt;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use windows_sys::Win32::Storage::FileSystem::{GetDiskFreeSpaceW, GetVolumePathNameW, FILE_ATTRIBUTE_SPARSE_FILE};
+    use windows_sys::Win32::System::Ioctl::{DUPLICATE_EXTENTS_DATA, FSCTL_DUPLICATE_EXTENTS_TO_FILE};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    // Larger ranges are rejected
+    const MAX_CHUNK: u64 = 1 << 31;
+
+    let wide: Vec<u16> = crate::lazyfile::open_path(source).as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = vec![0u16; wide.len().max(4) + 1];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut sectors_per_cluster, mut bytes_per_sector) = (0, 0);
+    if unsafe { GetDiskFreeSpaceW(root.as_ptr(), &mut sectors_per_cluster, &mut bytes_per_sector, ptr::null_mut(), ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let cluster = u64::from(sectors_per_cluster * bytes_per_sector).max(1);
+
+    let metadata = source_file.metadata()?;
+    if metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0 {
+        // Clones of sparse files must be sparse too
+        crate::sparse::set_sparse(dest_file)?;
+    }
+    let mut returned = 0;
+    let len = metadata.len();
+    dest_file.set_len(len)?;
+    let mut offset = 0;
+    while offset < len {
+        let count = (len - offset).min(MAX_CHUNK);
+        let data = DUPLICATE_EXTENTS_DATA {
+            FileHandle: source_file.as_raw_handle(),
+            SourceFileOffset: offset as i64,
+            TargetFileOffset: offset as i64,
+            ByteCount: ((count + cluster - 1) / cluster * cluster) as i64,
+        };
+        let ok = unsafe {
+            DeviceIoControl(
+                dest_file.as_raw_handle(),
+                FSCTL_DUPLICATE_EXTENTS_TO_FILE,
+                ptr::addr_of!(data).cast(),
+                size_of::<DUPLICATE_EXTENTS_DATA>() as u32,
+                ptr::null_mut(),
+                0,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        offset += count;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn clone_file(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The filesystem or the pair of files can't be cloned, but could still be linked or copied
+pub fn clone_unsupported(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        return code == libc::EOPNOTSUPP || code == libc::ENOTSUP || code == libc::EXDEV || code == libc::EINVAL || code == libc::ENOTTY;
+    }
+    #[cfg(windows)]
+    if let Some(code) = err.raw_os_error() {
+        use windows_sys::Win32::Foundation::{ERROR_INVALID_FUNCTION, ERROR_NOT_SAME_DEVICE, ERROR_NOT_SUPPORTED};
+        return code == ERROR_INVALID_FUNCTION as i32 || code == ERROR_NOT_SUPPORTED as i32 || code == ERROR_NOT_SAME_DEVICE as i32;
+    }
+    err.kind() == io::ErrorKind::Unsupported
+}
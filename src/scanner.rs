@@ -1,5 +1,11 @@
-use crate::file::{FileContent, FileSet};
-use crate::metadata::Metadata;
+use crate::cache::HashCache;
+use crate::file::{FileContent, FileSet, GroupPolicy};
+use crate::hasher::HashSettings;
+use crate::journal::Journal;
+use crate::lazyfile::{symlink_target, FileError};
+use crate::link::{LinkAction, LinkScope, MasterPolicy, PlannedAction, SizeMode};
+use crate::metadata::{is_reparse_point, FileIdentity, FileIndex, Metadata, MetadataCache};
+use crate::sparse;
 use std::cell::RefCell;
 use std::cmp;
 use std::collections::btree_map::Entry as BTreeEntry;
@@ -12,9 +18,10 @@ use std::ffi::OsString;
 use std::fmt::Debug;
 use std::fs;
 use std::io;
-use std::os::unix::fs::MetadataExt;
+use std::mem;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
@@ -28,12 +35,60 @@ pub enum RunMode {
     Hardlink,
 }
 
+/// What to do with Windows reparse points (junctions, mount points and symlinks) found while scanning
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ReparsePoints {
+    /// Count them as skipped, so that data reachable via a junction isn't scanned twice
+    #[default]
+    Skip,
+    /// Skip them, and tell the listener about each one
+    Report,
+    /// Scan their targets as if they were at the reparse point. A directory reached through more than one of them,
+    /// e.g. a junction leading back to its parent, is scanned through the first one only, so loops end.
+    Follow,
+}
+
+/// What to do with zero-size files. They're all identical, but linking them saves nothing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum EmptyFiles {
+    /// Count them as skipped
+    #[default]
+    Skip,
+    /// Skip them, and tell the listener about each one
+    Report,
+    /// Treat them as duplicates of each other, even if `ignore_small` or `min_size` is set
+    Link,
+}
+
 #[derive(Debug)]
 pub struct Settings {
     /// Ignore files smaller than a filesystem block.
     /// Deduping of such files is unlikely to save space.
     pub ignore_small: bool,
+    /// Ignore files smaller than this many bytes, e.g. 4096. They're skipped before they're read or compared.
+    /// Empty files are handled according to `empty_files` regardless.
+    pub min_size: u64,
     pub run_mode: RunMode,
+    /// Content is compared regardless of permissions. If this is set, duplicates with different permissions are
+    /// hardlinked anyway, and they all end up with permissions of one of them, except that read-only files aren't
+    /// linked to writable ones. Otherwise they're only reported.
+    pub ignore_permissions: bool,
+    pub reparse_points: ReparsePoints,
+    pub empty_files: EmptyFiles,
+    /// Tell the listener about duplicates that are stored differently (compressed or sparse),
+    /// since after linking all of them will be stored like the one they're linked to
+    pub report_storage_differences: bool,
+    /// How file content is hashed and compared
+    pub hashing: Arc<HashSettings>,
+    /// What else duplicates must have in common
+    pub grouping: GroupPolicy,
+    /// Which duplicate the others are linked to. With `MasterPolicy::First` it's a file with the most hardlinks,
+    /// since it's less work to link a small group of links to a large one.
+    pub master_policy: MasterPolicy,
+    pub link_scope: LinkScope,
+    /// Free memory of the hashes once a scan is over and its duplicates have been deduped.
+    /// Files scanned later are still compared correctly, but the files they're compared to have to be read again.
+    pub free_hashes: bool,
 
     // If 1, go to flush. If > 1, abort immediately.
     pub break_on: Option<&'static AtomicU32>,
@@ -58,6 +113,14 @@ pub struct Stats {
     pub bytes_deduplicated: usize,
     pub hardlinks: usize,
     pub bytes_saved_by_hardlinks: usize,
+    /// Like `bytes_deduplicated`, but counting space allocated on disk, which differs for sparse and compressed files
+    pub allocated_bytes_deduplicated: usize,
+    /// Like `bytes_saved_by_hardlinks`, but counting space allocated on disk
+    pub allocated_bytes_saved_by_hardlinks: usize,
+    /// Space freed by linking duplicates (see `LinkSummary::bytes_saved`). It's 0 in dry runs.
+    pub bytes_freed: usize,
+    /// Like `bytes_freed`, but counting space allocated on disk
+    pub allocated_bytes_freed: usize,
 }
 
 pub trait ScanListener: Debug {
@@ -65,6 +128,15 @@ pub trait ScanListener: Debug {
     fn scan_over(&self, scanner: &Scanner, stats: &Stats, scan_duration: Duration);
     fn hardlinked(&mut self, src: &Path, dst: &Path);
     fn duplicate_found(&mut self, src: &Path, dst: &Path);
+    /// Reparse point skipped because of `ReparsePoints::Report`
+    fn reparse_point_found(&mut self, _path: &Path) {}
+    /// Zero-size file skipped because of `EmptyFiles::Report`
+    fn empty_file_found(&mut self, _path: &Path) {}
+    /// Duplicates differ in compression or sparseness. Only if `report_storage_differences` is set.
+    fn storage_differs(&mut self, _src: &Path, _dst: &Path) {}
+    /// Files have the same content, but different alternate data streams, so they aren't duplicates.
+    /// Only if `GroupPolicy::compare_streams` is set.
+    fn streams_differ(&mut self, _src: &Path, _dst: &Path) {}
 }
 
 #[derive(Debug)]
@@ -79,12 +151,44 @@ impl ScanListener for SilentListener {
     fn duplicate_found(&mut self, _: &Path, _: &Path) {}
 }
 
+/// The filesystem can't hardlink these files at all, as opposed to failing to link them this time
+fn hardlinks_unsupported(err: &io::Error) -> bool {
+    // Errors of planned actions are wrapped
+    #[cfg(unix)]
+    let code = match err.get_ref().and_then(|e| e.downcast_ref::<FileError>()) {
+        Some(err) => err.raw_os_error,
+        None => err.raw_os_error(),
+    };
+    #[cfg(unix)]
+    if let Some(code) = code {
+        // Linux reports EPERM for FAT, which plans don't link at all (see `FilesystemType::max_hardlinks`),
+        // but also for `fs.protected_hardlinks` and files of other users, which are errors.
+        // EXDEV happens only if files on different devices have been compared.
+        return code == libc::EOPNOTSUPP || code == libc::ENOTSUP || code == libc::EXDEV;
+    }
+    err.kind() == io::ErrorKind::Unsupported
+}
+
+/// Files smaller than a filesystem block are unlikely to take less space when linked
+#[cfg(unix)]
+fn small_size(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // APFS reports 4*MB* block size
+    cmp::min(16 * 1024, metadata.blksize())
+}
+
+/// The usual NTFS cluster size
+#[cfg(not(unix))]
+fn small_size(_: &fs::Metadata) -> u64 {
+    4096
+}
+
 type RcFileSet = Rc<RefCell<FileSet>>;
 
 #[derive(Debug)]
 pub struct Scanner {
     /// All hardlinks of the same inode have to be treated as the same file
-    by_inode: HashMap<(u64, u64), RcFileSet>,
+    by_inode: HashMap<FileIdentity, RcFileSet>,
     /// See Hasher for explanation
     by_content: BTreeMap<FileContent, Vec<RcFileSet>>,
     /// Directories left to scan. Sorted by inode number.
@@ -93,6 +197,12 @@ pub struct Scanner {
     to_scan: BinaryHeap<(u64, Box<Path>)>,
 
     scan_listener: Box<dyn ScanListener>,
+    hash_cache: Option<HashCache>,
+    journal: Option<Journal>,
+    /// Metadata needed again when deduping
+    metadata_cache: MetadataCache,
+    /// Devices and IDs of directories that followed reparse points have led to
+    followed_dirs: HashSet<(u64, u128)>,
     stats: Stats,
     exclude: HashSet<OsString>,
     pub settings: Settings,
@@ -106,13 +216,27 @@ impl Scanner {
         Scanner {
             settings: Settings {
                 ignore_small: true,
+                min_size: 1,
                 run_mode: RunMode::Hardlink,
+                ignore_permissions: true,
+                reparse_points: ReparsePoints::default(),
+                empty_files: EmptyFiles::default(),
+                report_storage_differences: false,
+                hashing: Arc::default(),
+                grouping: GroupPolicy::default(),
+                master_policy: MasterPolicy::default(),
+                link_scope: LinkScope::default(),
+                free_hashes: false,
                 break_on: None,
             },
             by_inode: HashMap::new(),
             by_content: BTreeMap::new(),
             to_scan: BinaryHeap::new(),
             scan_listener: Box::new(SilentListener),
+            hash_cache: None,
+            journal: None,
+            metadata_cache: MetadataCache::new(),
+            followed_dirs: HashSet::new(),
             stats: Stats::default(),
             exclude: HashSet::new(),
             deferred_count: 0,
@@ -130,6 +254,18 @@ impl Scanner {
         self.scan_listener = listener;
     }
 
+    /// Remember hashes in this file between runs, so that unchanged files aren't read again.
+    /// Must be set after `settings.hashing`, since cached hashes are only valid for the same settings.
+    pub fn set_hash_cache(&mut self, path: impl AsRef<Path>) {
+        self.hash_cache = Some(HashCache::open(path.as_ref(), &self.settings.hashing));
+    }
+
+    /// Record hardlinked files in this journal, so that linking can be undone later with `undo`
+    pub fn set_journal(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.journal = Some(Journal::open(path)?);
+        Ok(())
+    }
+
     /// Scan any file or directory for dupes.
     /// Dedupe is done within the path as well as against all previously added paths.
     pub fn scan(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
@@ -151,6 +287,7 @@ impl Scanner {
         while let Some((_, path)) = self.to_scan.pop() {
             if let Err(err) = self.scan_dir(&path) {
                 eprintln!("Error scanning {}: {}", path.display(), err);
+                self.log_error(&path, &err);
                 self.stats.skipped += 1;
             }
             if self.settings.breaks() > 0 {
@@ -159,16 +296,28 @@ impl Scanner {
             }
         }
         self.flush_deferred();
+        self.save_hash_cache();
         let scan_duration = Instant::now().duration_since(start_time);
         self.scan_listener.scan_over(self, &self.stats, scan_duration);
+        if self.settings.free_hashes {
+            for content in self.by_content.keys() {
+                content.clear_hashes();
+            }
+        }
         Ok(())
     }
 
     fn scan_dir(&mut self, path: &Path) -> io::Result<()> {
-        // Errors are ignored here, since it's super common to find permission denied and unreadable symlinks,
-        // and it'd be annoying if that aborted the whole operation.
-        // FIXME: store the errors somehow to report them in a controlled manner
-        for entry in fs::read_dir(path)?.filter_map(|p| p.ok()) {
+        // Errors don't abort the scan, since it's super common to find permission denied and unreadable symlinks,
+        // and it'd be annoying if that aborted the whole operation. They're only logged.
+        for entry in fs::read_dir(path)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    self.log_error(path, &err);
+                    continue;
+                },
+            };
             if self.settings.breaks() > 0 {
                 break;
             }
@@ -180,57 +329,141 @@ impl Scanner {
                     continue;
                 }
             }
-            if let Err(err) = self.add(path.into_boxed_path(), &entry.metadata()?) {
+            // The file may be gone already
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    let err = FileError::metadata(&path, &err).to_io_error();
+                    eprintln!("{}", err);
+                    self.log_error(&path, &err);
+                    self.stats.skipped += 1;
+                    continue;
+                },
+            };
+            if let Err(err) = self.add(path.into_boxed_path(), &metadata) {
                 eprintln!("{}: {}", entry.path().display(), err);
+                self.log_error(&entry.path(), &err);
             }
         }
         Ok(())
     }
 
+    /// Errors of hashing are logged by the comparisons themselves
+    fn log_error(&self, path: &Path, err: &io::Error) {
+        if let Some(log) = &self.settings.hashing.error_log {
+            log.record(FileError::new(path, err));
+        }
+    }
+
     fn add(&mut self, path: Box<Path>, metadata: &fs::Metadata) -> io::Result<()> {
         self.scan_listener.file_scanned(&path, &self.stats);
 
+        // Symlinks compared by their targets don't lead anywhere
+        let followed;
+        let mut metadata = metadata;
+        if is_reparse_point(metadata) && !(metadata.file_type().is_symlink() && self.settings.hashing.compare_symlink_targets) {
+            match self.settings.reparse_points {
+                ReparsePoints::Follow => {
+                    followed = fs::metadata(&path)?;
+                    metadata = &followed;
+                    if followed.is_dir() {
+                        let index = FileIndex::new(&path, &followed).map_err(|err| FileError::metadata(&path, &err).to_io_error())?;
+                        // Directories without IDs can't be told apart
+                        if index.ino != 0 && !self.followed_dirs.insert((index.dev, index.ino)) {
+                            self.stats.skipped += 1;
+                            return Ok(());
+                        }
+                    }
+                },
+                policy => {
+                    if policy == ReparsePoints::Report {
+                        self.scan_listener.reparse_point_found(&path);
+                    }
+                    self.stats.skipped += 1;
+                    return Ok(());
+                },
+            }
+        }
+
         let ty = metadata.file_type();
         if ty.is_dir() {
             // Inode is truncated to group scanning of roughly close inodes together,
             // But still preserve some directory traversal order.
             // Negation to scan from the highest (assuming latest) first.
-            let order_key = !(metadata.ino() >> 8);
+            // It's not worth opening directories for their IDs on Windows, so there it's just the path order.
+            let order_key = !(FileIndex::from_metadata(metadata).ino >> 8) as u64;
             self.to_scan.push((order_key, path));
             return Ok(());
-        } else if ty.is_symlink() || !ty.is_file() {
+        } else if (ty.is_symlink() && !self.settings.hashing.compare_symlink_targets) || !(ty.is_file() || ty.is_symlink()) {
             // Support for traversing symlinks would require preventing loops
             // Deduping /dev/ would be funny
             self.stats.skipped += 1;
             return Ok(());
         }
 
-        // APFS reports 4*MB* block size
-        let small_size = cmp::min(16 * 1024, metadata.blksize());
-        if metadata.size() == 0 || (self.settings.ignore_small && metadata.size() < small_size) {
+        // Windows reports symlinks as empty
+        let size = if ty.is_symlink() {
+            match symlink_target(&path) {
+                Ok(target) => target.map_or(0, |t| t.len() as u64),
+                Err(err) => {
+                    self.stats.skipped += 1;
+                    return Err(err);
+                },
+            }
+        } else {
+            metadata.len()
+        };
+        let small_size = small_size(metadata);
+        if size == 0 {
+            if self.settings.empty_files != EmptyFiles::Link {
+                if self.settings.empty_files == EmptyFiles::Report {
+                    self.scan_listener.empty_file_found(&path);
+                }
+                self.stats.skipped += 1;
+                return Ok(());
+            }
+        } else if size < self.settings.min_size || (self.settings.ignore_small && size < small_size) {
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+
+        let index = FileIndex::new(&path, metadata).map_err(|err| FileError::metadata(&path, &err).to_io_error())?;
+        if self.already_added(&path, &index, metadata) {
             self.stats.skipped += 1;
             return Ok(());
         }
         self.stats.added += 1;
 
-        if let Some(fileset) = self.new_fileset(&path, metadata) {
-            self.dedupe_by_content(fileset, path, metadata)?;
+        if let Some(fileset) = self.new_fileset(&path, &index, metadata) {
+            self.dedupe_by_content(fileset, path, index, metadata)?;
         } else {
             self.stats.hardlinks += 1;
-            self.stats.bytes_saved_by_hardlinks += metadata.size() as usize;
+            self.stats.bytes_saved_by_hardlinks += size as usize;
+            self.stats.allocated_bytes_saved_by_hardlinks += sparse::allocated_size(&path, metadata) as usize;
         }
         Ok(())
     }
 
+    /// The same path has been found before, e.g. via scan roots that differ only in case
+    fn already_added(&self, path: &Path, index: &FileIndex, metadata: &fs::Metadata) -> bool {
+        FileIdentity::new(index, metadata)
+            .and_then(|id| self.by_inode.get(&id))
+            .map_or(false, |set| set.borrow().contains(path))
+    }
+
     /// Creates a new fileset if it's a new file.
     /// Returns None if it's a hardlink of a file already seen.
-    fn new_fileset(&mut self, path: &Path, metadata: &fs::Metadata) -> Option<RcFileSet> {
+    fn new_fileset(&mut self, path: &Path, index: &FileIndex, metadata: &fs::Metadata) -> Option<RcFileSet> {
         let path: Box<Path> = path.into();
-        let device_inode = (metadata.dev(), metadata.ino());
+        // Files without IDs can't be told apart from their hardlinks, so each is a separate file
+        let id = match FileIdentity::new(index, metadata) {
+            Some(id) => id,
+            None => return Some(Rc::new(RefCell::new(FileSet::new(path, 1)))),
+        };
 
-        match self.by_inode.entry(device_inode) {
+        match self.by_inode.entry(id) {
             HashEntry::Vacant(e) => {
-                let fileset = Rc::new(RefCell::new(FileSet::new(path, metadata.nlink())));
+                let fileset = Rc::new(RefCell::new(FileSet::new(path, index.nlink)));
                 e.insert(Rc::clone(&fileset)); // clone just bumps a refcount here
                 Some(fileset)
             },
@@ -245,24 +478,61 @@ impl Scanner {
     }
 
     /// Here's where all the magic happens
-    fn dedupe_by_content(&mut self, fileset: RcFileSet, path: Box<Path>, metadata: &fs::Metadata) -> io::Result<()> {
+    fn dedupe_by_content(&mut self, fileset: RcFileSet, path: Box<Path>, index: FileIndex, metadata: &fs::Metadata) -> io::Result<()> {
         let mut deferred = false;
-        match self.by_content.entry(FileContent::new(path, Metadata::new(metadata))) {
+        let metadata_copy = Metadata::new(metadata).with_index(index);
+        if self.settings.report_storage_differences {
+            // Already known from the directory scan, so dedupe doesn't need to stat it again
+            self.metadata_cache.insert(&path, metadata_copy);
+        }
+        let content = FileContent::with_settings(path, metadata_copy, Arc::clone(&self.settings.hashing)).with_grouping(self.settings.grouping);
+        if let Some(ranges) = self.hash_cache.as_mut().and_then(|cache| cache.lookup(&content.path, metadata)) {
+            content.preload(ranges);
+        }
+        let size = content.metadata().size;
+        // Keys that have failed to be read while they were compared are out of order, so they can't stay in the map.
+        // Once they're gone, the content is looked up again. That reads nothing new, unless it's compared with other keys.
+        let mut found;
+        loop {
+            found = self.by_content.contains_key(&content);
+            if !content.take_failed_comparison() {
+                break;
+            }
+            let before = self.by_content.len();
+            self.by_content.retain(|key, _| key.error().is_none());
+            self.stats.skipped += before - self.by_content.len();
+            if content.error().is_some() {
+                break;
+            }
+        }
+        // The entry is compared with the same keys as the lookup
+        for other in content.take_differing_streams() {
+            self.scan_listener.streams_differ(&content.path, &other);
+        }
+        // Only duplicates need it, and on Windows it's another call per file
+        let allocated = if found { sparse::allocated_size(&content.path, metadata) } else { 0 };
+        match self.by_content.entry(content) {
             BTreeEntry::Vacant(e) => {
+                // Don't keep unreadable files, so that they're never grouped with anything
+                if e.key().error().is_some() {
+                    self.stats.skipped += 1;
+                    return Ok(());
+                }
                 // Seems unique so far
                 e.insert(vec![fileset]);
             },
             BTreeEntry::Occupied(mut e) => {
                 // Found a dupe!
                 self.stats.dupes += 1;
-                self.stats.bytes_deduplicated += metadata.size() as usize;
+                self.stats.bytes_deduplicated += size as usize;
+                self.stats.allocated_bytes_deduplicated += allocated as usize;
                 let filesets = e.get_mut();
                 filesets.push(fileset);
                 // Deduping can either be done immediately or later. Immediate is more cache-friendly and interactive,
                 // but for files that already have hardlinks it can cause unnecessary re-linking. So if there are
                 // hardlinks in the set, wait until the end to dedupe when all hardlinks are known.
                 if filesets.iter().all(|set| set.borrow().links() == 1) {
-                    Self::dedupe(filesets, self.settings.run_mode, &mut *self.scan_listener)?;
+                    Self::dedupe(filesets, &self.settings, &mut self.metadata_cache, self.journal.as_mut(), &mut self.stats, &mut *self.scan_listener)?;
                 } else {
                     deferred = true;
                 }
@@ -283,92 +553,215 @@ impl Scanner {
         Ok(())
     }
 
+    fn save_hash_cache(&mut self) {
+        if let Some(cache) = &mut self.hash_cache {
+            for content in self.by_content.keys() {
+                cache.update(&content.path, content.known_ranges().into_iter());
+            }
+            if let Err(err) = cache.save() {
+                eprintln!("Can't save hash cache: {}", err);
+            }
+        }
+    }
+
     fn flush_deferred(&mut self) {
         for filesets in self.by_content.values_mut() {
             if self.settings.breaks() > 1 {
                 eprintln!("Aborting");
                 break;
             }
-            if let Err(err) = Self::dedupe(filesets, self.settings.run_mode, &mut *self.scan_listener) {
+            if let Err(err) = Self::dedupe(filesets, &self.settings, &mut self.metadata_cache, self.journal.as_mut(), &mut self.stats, &mut *self.scan_listener) {
                 eprintln!("{}", err);
             }
         }
     }
 
-    fn dedupe(filesets: &mut [RcFileSet], run_mode: RunMode, scan_listener: &mut dyn ScanListener) -> io::Result<()> {
-        if run_mode == RunMode::DryRunNoMerging {
+    fn dedupe(filesets: &mut [RcFileSet], settings: &Settings, metadata_cache: &mut MetadataCache, mut journal: Option<&mut Journal>, stats: &mut Stats, scan_listener: &mut dyn ScanListener) -> io::Result<()> {
+        if settings.run_mode == RunMode::DryRunNoMerging {
             return Ok(());
         }
+        if filesets.iter().filter(|set| !set.borrow().paths.is_empty()).count() < 2 {
+            return Ok(()); // Already merged
+        }
 
-        // Find file with the largest number of hardlinks, since it's less work to merge a small group into a large group
-        let mut largest_idx = 0;
-        let mut largest_links = 0;
-        let mut nonempty_filesets = 0;
-        for (idx, fileset) in filesets.iter().enumerate() {
-            let fileset = fileset.borrow();
-            if !fileset.paths.is_empty() {
-                // Only actual paths we can merge matter here
-                nonempty_filesets += 1;
+        // The sets are still going to be in use! So everything has to be updated to make sense for the next call.
+        // Paths that have been dealt with are merged into the set with the most links, which groups sort first.
+        let merged = filesets.iter().enumerate().max_by_key(|&(i, set)| (set.borrow().links(), cmp::Reverse(i))).map_or(0, |(i, _)| i);
+        let mut done = HashSet::new();
+        // Paths of a plan that failed will be "lost", but that's fine, since we don't want to dedupe them if they cause errors
+        let mut lost = Vec::new();
+        let mut res = Ok(());
+        'groups: for mut group in Self::master_groups(filesets, &settings.master_policy, settings.link_scope)? {
+            let master = group.paths[0].clone();
+            // Already links of the master, or dealt with in a dry run. A new master needs all of them linked to it.
+            {
+                let merged = filesets[merged].borrow();
+                if merged.contains(&master) {
+                    let merged: HashSet<&Path> = merged.paths.iter().map(|path| &**path).collect();
+                    group.paths.retain(|path| *path == master || !merged.contains(&**path));
+                }
             }
-            let links = fileset.links();
-            if links > largest_links {
-                largest_idx = idx;
-                largest_links = links;
+            if group.paths.len() < 2 {
+                continue;
             }
+            for path in Self::take_differing_permissions(&mut group, settings.ignore_permissions)? {
+                scan_listener.duplicate_found(&path, &master);
+                done.insert(path);
+            }
+            for plan in group.plans(LinkAction::Hardlink)? {
+                if let Err(err) = Self::link(&plan, settings, metadata_cache, journal.as_deref_mut(), stats, scan_listener) {
+                    lost = plan.paths;
+                    res = Err(err);
+                    break 'groups;
+                }
+                done.insert(plan.master);
+                done.extend(plan.paths);
+            }
+            // Including paths that turned out to be links of the master
+            done.extend(group.paths);
         }
 
-        if nonempty_filesets == 0 {
-            return Ok(()); // Already merged
-        }
-
-        // The set is still going to be in use! So everything has to be updated to make sense for the next call
-        let merged_paths = &mut { filesets[largest_idx].borrow_mut() }.paths;
-        let source_path = merged_paths[0].clone();
+        let mut merged_paths = Vec::new();
         for (i, set) in filesets.iter().enumerate() {
-            // We don't want to merge the set with itself
-            if i == largest_idx {
+            if i == merged {
                 continue;
             }
+            let mut set = set.borrow_mut();
+            merged_paths.extend(set.paths.iter().filter(|path| done.contains(*path)).cloned());
+            set.paths.retain(|path| !done.contains(path) && !lost.contains(path));
+        }
+        filesets[merged].borrow_mut().paths.extend(merged_paths);
+        res
+    }
 
-            let paths = &mut set.borrow_mut().paths;
-            // dest_path will be "lost" on error, but that's fine, since we don't want to dedupe it if it causes errors
-            for dest_path in paths.drain(..) {
-                assert_ne!(&source_path, &dest_path);
-                debug_assert_ne!(fs::symlink_metadata(&source_path)?.ino(), fs::symlink_metadata(&dest_path)?.ino());
-
-                if run_mode == RunMode::DryRun {
-                    scan_listener.duplicate_found(&dest_path, &source_path);
-                    merged_paths.push(dest_path);
-                    continue;
+    /// Links the paths of the plan to its master, or in a dry run only reports them
+    fn link(plan: &PlannedAction, settings: &Settings, metadata_cache: &mut MetadataCache, journal: Option<&mut Journal>, stats: &mut Stats, scan_listener: &mut dyn ScanListener) -> io::Result<()> {
+        if plan.paths.is_empty() {
+            return Ok(());
+        }
+        if settings.report_storage_differences {
+            let master = metadata_cache.get(&plan.master)?;
+            for path in &plan.paths {
+                let metadata = metadata_cache.get(path)?;
+                if (master.compressed, master.sparse) != (metadata.compressed, metadata.sparse) {
+                    scan_listener.storage_differs(path, &plan.master);
                 }
-
-                let temp_path = dest_path.with_file_name(".tmp-dupe-e1iIQcBFn5pC4MUSm-xkcd-221");
-                debug_assert!(!temp_path.exists());
-                debug_assert!(source_path.exists());
-                debug_assert!(dest_path.exists());
-
-                // In posix link guarantees not to overwrite, and mv guarantes to move atomically
-                // so this two-step replacement is pretty robust
-                if let Err(err) = fs::hard_link(&source_path, &temp_path) {
-                    eprintln!("unable to hardlink {} {} due to {}", source_path.display(), temp_path.display(), err);
-                    let _ = fs::remove_file(temp_path);
-                    return Err(err);
+            }
+        }
+        if settings.run_mode == RunMode::DryRun {
+            for path in &plan.paths {
+                scan_listener.duplicate_found(path, &plan.master);
+            }
+            return Ok(());
+        }
+        // Before anything is linked. Paths that won't be linked after all are skipped when undoing.
+        if let Some(journal) = journal {
+            journal.record(&plan.master, &plan.paths)?;
+        }
+        match plan.execute() {
+            Ok(summary) => {
+                stats.bytes_freed += summary.bytes_saved as usize;
+                stats.allocated_bytes_freed += summary.allocated_bytes_saved as usize;
+                for path in &plan.paths {
+                    metadata_cache.invalidate(path);
+                    scan_listener.hardlinked(path, &plan.master);
                 }
-                if let Err(err) = fs::rename(&temp_path, &dest_path) {
-                    eprintln!("unable to rename {} {} due to {}", temp_path.display(), dest_path.display(), err);
-                    let _ = fs::remove_file(temp_path);
-                    return Err(err);
+            },
+            // E.g. over SMB, which may not support hardlinks. They're still duplicates worth reporting.
+            Err(err) if hardlinks_unsupported(&err) => {
+                for path in &plan.paths {
+                    scan_listener.duplicate_found(path, &plan.master);
                 }
-                debug_assert!(!temp_path.exists());
-                debug_assert!(source_path.exists());
-                debug_assert!(dest_path.exists());
-                scan_listener.hardlinked(&dest_path, &source_path);
-                merged_paths.push(dest_path);
-            }
+            },
+            Err(err) => {
+                eprintln!("unable to hardlink files to {} due to {}", plan.master.display(), err);
+                return Err(err);
+            },
         }
         Ok(())
     }
 
+    /// What the action would do with each group of duplicates found so far, e.g. in a dry run, without changing anything.
+    /// The groups are split and get their masters like in a real run (see `Settings::master_policy`), except that
+    /// the policy and scope are given. Groups too large for one master get several plans (see `FileSet::plans`),
+    /// and so does each directory of a group if the scope is `LinkScope::PerDirectory`.
+    /// Duplicates with different permissions are left out like in a real run (see `Settings::ignore_permissions`). `PlannedAction::execute` does it.
+    pub fn plan(&self, action: LinkAction, master: &MasterPolicy, scope: LinkScope) -> io::Result<Vec<PlannedAction>> {
+        let mut plans = Vec::new();
+        for filesets in self.by_content.values() {
+            for mut group in Self::master_groups(filesets, master, scope)? {
+                Self::take_differing_permissions(&mut group, self.settings.ignore_permissions)?;
+                plans.extend(group.plans(action)?);
+            }
+        }
+        Ok(plans)
+    }
+
+    /// The group split according to the scope, with the master of each part first. Parts with only one path are left out.
+    fn master_groups(filesets: &[RcFileSet], master: &MasterPolicy, scope: LinkScope) -> io::Result<Vec<FileSet>> {
+        let groups = match scope {
+            LinkScope::Global => vec![Self::group(filesets)],
+            LinkScope::PerDirectory => Self::group(filesets).split_by_directory(),
+        };
+        let mut with_masters = Vec::new();
+        for mut group in groups {
+            if group.paths.len() < 2 {
+                continue;
+            }
+            group.set_master(master)?;
+            with_masters.push(group);
+        }
+        Ok(with_masters)
+    }
+
+    /// Takes out the paths that linking to the master would silently change permissions of.
+    /// If permissions are ignored, only the ones it would make writable.
+    fn take_differing_permissions(group: &mut FileSet, ignore_permissions: bool) -> io::Result<Vec<Box<Path>>> {
+        let permissions = |path: &Path| fs::symlink_metadata(path).map(|m| m.permissions()).map_err(|err| FileError::metadata(path, &err).to_io_error());
+        let master = permissions(&group.paths[0])?;
+        let mut differing = Vec::new();
+        for (i, path) in mem::take(&mut group.paths).into_iter().enumerate() {
+            let differs = i > 0 && {
+                let permissions = permissions(&path)?;
+                if ignore_permissions { permissions.readonly() && !master.readonly() } else { permissions != master }
+            };
+            if differs {
+                differing.push(path);
+            } else {
+                group.paths.push(path);
+            }
+        }
+        Ok(differing)
+    }
+
+    /// Counts so far, like the ones `ScanListener::scan_over` gets
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Space that linking each group of duplicates found so far would free (see `FileSet::bytes_saved`),
+    /// and the total of all groups. E.g. after a dry run, it's how much less `du` would show after linking.
+    pub fn bytes_saved(&self, size: SizeMode) -> io::Result<(Vec<(FileSet, u64)>, u64)> {
+        let mut groups = Vec::new();
+        let mut total = 0;
+        for group in self.by_content.values().map(|filesets| Self::group(filesets)).filter(|group| group.paths.len() > 1) {
+            let saved = group.bytes_saved(size)?;
+            total += saved;
+            groups.push((group, saved));
+        }
+        Ok((groups, total))
+    }
+
+    /// All paths with the same content in one set, with the files with the most hardlinks first
+    fn group(filesets: &[RcFileSet]) -> FileSet {
+        let mut filesets: Vec<_> = filesets.iter().map(|set| set.borrow()).collect();
+        filesets.sort_by_key(|set| cmp::Reverse(set.links()));
+        FileSet {
+            max_hardlinks: 0,
+            paths: filesets.iter().flat_map(|set| set.paths.iter().cloned()).collect(),
+        }
+    }
+
     pub fn dupes(&self) -> Vec<Vec<FileSet>> {
         self.by_content.values().map(|filesets| {
             filesets.iter().map(|d|{
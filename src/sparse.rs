@@ -0,0 +1,164 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Whether the file has fewer bytes allocated than its length, so it's worth looking for holes
+#[cfg(unix)]
+pub fn is_sparse(m: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    m.blocks().saturating_mul(512) < m.size()
+}
+
+#[cfg(windows)]
+pub fn is_sparse(m: &fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_ATTRIBUTE_SPARSE_FILE;
+    m.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn is_sparse(_: &fs::Metadata) -> bool {
+    false
+}
+
+/// Bytes the file takes on disk, which is less than its length if it's sparse or compressed. It's only for reporting.
+#[cfg(unix)]
+pub fn allocated_size(_: &Path, m: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    m.blocks().saturating_mul(512)
+}
+
+#[cfg(windows)]
+pub fn allocated_size(path: &Path, m: &fs::Metadata) -> u64 {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::{GetLastError, NO_ERROR};
+    use windows_sys::Win32::Storage::FileSystem::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+
+    let wide: Vec<u16> = crate::lazyfile::open_path(path).as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut high = 0;
+    let low = unsafe { GetCompressedFileSizeW(wide.as_ptr(), &mut high) };
+    if low == INVALID_FILE_SIZE && unsafe { GetLastError() } != NO_ERROR {
+        return m.len();
+    }
+    u64::from(high) << 32 | u64::from(low)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn allocated_size(_: &Path, m: &fs::Metadata) -> u64 {
+    m.len()
+}
+
+/// Makes holes in the file possible. Elsewhere than on Windows, every file can have them.
+#[cfg(windows)]
+pub fn set_sparse(file: &fs::File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use windows_sys::Win32::System::Ioctl::FSCTL_SET_SPARSE;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let mut returned = 0;
+    if unsafe { DeviceIoControl(file.as_raw_handle(), FSCTL_SET_SPARSE, ptr::null(), 0, ptr::null_mut(), 0, &mut returned, ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn set_sparse(_: &fs::File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Ranges of `(start, end)` within `start..end` that may contain data. Everything else is a hole, which reads as zeros.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+pub fn data_ranges(file: &fs::File, start: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let data_start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data_start < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data past pos
+                Some(libc::ENXIO) => Ok(ranges),
+                // Filesystem doesn't know about holes
+                Some(libc::EINVAL) => Ok(vec![(start, end)]),
+                _ => Err(err),
+            };
+        }
+        let data_start = data_start as u64;
+        if data_start >= end {
+            break;
+        }
+        let data_end = unsafe { libc::lseek(fd, data_start as libc::off_t, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let data_end = (data_end as u64).min(end);
+        ranges.push((data_start, data_end));
+        pos = data_end;
+    }
+    Ok(ranges)
+}
+
+#[cfg(windows)]
+pub fn data_ranges(file: &fs::File, start: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::mem::size_of;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::ERROR_MORE_DATA;
+    use windows_sys::Win32::System::Ioctl::{FILE_ALLOCATED_RANGE_BUFFER, FSCTL_QUERY_ALLOCATED_RANGES};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let mut ranges = Vec::new();
+    let mut pos = start;
+    let mut out = [FILE_ALLOCATED_RANGE_BUFFER { FileOffset: 0, Length: 0 }; 64];
+    while pos < end {
+        let query = FILE_ALLOCATED_RANGE_BUFFER {
+            FileOffset: pos as i64,
+            Length: (end - pos) as i64,
+        };
+        let mut returned = 0;
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle(),
+                FSCTL_QUERY_ALLOCATED_RANGES,
+                ptr::addr_of!(query).cast(),
+                size_of::<FILE_ALLOCATED_RANGE_BUFFER>() as u32,
+                out.as_mut_ptr().cast(),
+                (out.len() * size_of::<FILE_ALLOCATED_RANGE_BUFFER>()) as u32,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        };
+        let more = if ok != 0 {
+            false
+        } else {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_MORE_DATA as i32) {
+                return Err(err);
+            }
+            true
+        };
+        let count = returned as usize / size_of::<FILE_ALLOCATED_RANGE_BUFFER>();
+        for r in &out[..count] {
+            let data_start = (r.FileOffset as u64).max(pos);
+            let data_end = (r.FileOffset as u64 + r.Length as u64).min(end);
+            if data_start < data_end {
+                ranges.push((data_start, data_end));
+            }
+        }
+        match (more, ranges.last()) {
+            (true, Some(&(_, last_end))) if last_end > pos => pos = last_end,
+            _ => break,
+        }
+    }
+    Ok(ranges)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows)))]
+pub fn data_ranges(_: &fs::File, start: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(start, end)])
+}
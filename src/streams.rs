@@ -0,0 +1,53 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Names and sizes of NTFS alternate data streams of the file, e.g. `:Zone.Identifier:$DATA`.
+/// The main stream isn't included. A stream can be opened by appending its name to the path.
+#[cfg(windows)]
+pub fn named_streams(path: &Path) -> io::Result<Vec<(String, u64)>> {
+    use std::mem;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::{FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard, WIN32_FIND_STREAM_DATA};
+
+    let wide: Vec<u16> = crate::lazyfile::open_path(path).as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut data: WIN32_FIND_STREAM_DATA = unsafe { mem::zeroed() };
+    let handle = unsafe { FindFirstStreamW(wide.as_ptr(), FindStreamInfoStandard, ptr::addr_of_mut!(data).cast(), 0) };
+    if handle == INVALID_HANDLE_VALUE {
+        let err = io::Error::last_os_error();
+        // Directories and other filesystems may have no streams at all
+        return if err.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) { Ok(Vec::new()) } else { Err(err) };
+    }
+    let mut streams = Vec::new();
+    let res = loop {
+        let len = data.cStreamName.iter().position(|&c| c == 0).unwrap_or(data.cStreamName.len());
+        let name = String::from_utf16_lossy(&data.cStreamName[..len]);
+        if name != "::$DATA" {
+            streams.push((name, data.StreamSize as u64));
+        }
+        if unsafe { FindNextStreamW(handle, ptr::addr_of_mut!(data).cast()) } == 0 {
+            let err = io::Error::last_os_error();
+            break if err.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) { Ok(()) } else { Err(err) };
+        }
+    };
+    unsafe {
+        FindClose(handle);
+    }
+    res?;
+    streams.sort_unstable();
+    Ok(streams)
+}
+
+/// Only NTFS has alternate data streams
+#[cfg(not(windows))]
+pub fn named_streams(_: &Path) -> io::Result<Vec<(String, u64)>> {
+    Ok(Vec::new())
+}
+
+/// Path that opens the named stream of the file
+pub fn stream_path(path: &Path, name: &str) -> PathBuf {
+    let mut stream = path.as_os_str().to_owned();
+    stream.push(name);
+    stream.into()
+}
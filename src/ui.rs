@@ -45,8 +45,9 @@ impl ScanListener for UI {
             x @ 5..=59 => format!("{}s", x),
             x => format!("{}m{}s", x / 60, x % 60),
         };
-        println!("Dupes found: {}, wasting {}. Existing hardlinks: {}, saving {}. Scanned: {}. Skipped {}. Total scan duration: {}",
-            stats.dupes, human_size(stats.bytes_deduplicated), stats.hardlinks, human_size(stats.bytes_saved_by_hardlinks),
+        println!("Dupes found: {}, wasting {} ({} on disk). Existing hardlinks: {}, saving {} ({} on disk). Scanned: {}. Skipped {}. Total scan duration: {}",
+            stats.dupes, human_size(stats.bytes_deduplicated), human_size(stats.allocated_bytes_deduplicated),
+            stats.hardlinks, human_size(stats.bytes_saved_by_hardlinks), human_size(stats.allocated_bytes_saved_by_hardlinks),
             stats.added, stats.skipped, nice_duration);
     }
 
@@ -57,10 +58,19 @@ impl ScanListener for UI {
     fn duplicate_found(&mut self, src: &Path, dst: &Path) {
         println!("Found dupe {}", combined_paths(src, dst));
     }
+
+    fn storage_differs(&mut self, src: &Path, dst: &Path) {
+        println!("Stored differently (compressed or sparse) {}", combined_paths(src, dst));
+    }
+
+    fn streams_differ(&mut self, src: &Path, dst: &Path) {
+        println!("Same content, but different alternate data streams {}", combined_paths(src, dst));
+    }
 }
 
 const POWERS_OF_TWO: [&str; 7] = ["", "k", "M", "G", "T", "P", "E"];
-fn human_size(size: usize) -> String {
+/// With decimal prefixes, e.g. `15.63kB`
+pub fn human_size(size: usize) -> String {
     let power_threshold = 1024.;
 
     let mut current_power = 0;
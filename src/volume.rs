@@ -0,0 +1,188 @@
+use crate::metadata::Metadata;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Filesystem of a volume, as far as linking duplicates is concerned
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FilesystemType {
+    Ntfs,
+    Refs,
+    /// FAT, FAT32 and exFAT
+    Fat,
+    Btrfs,
+    Xfs,
+    Apfs,
+    /// ext2, ext3 and ext4
+    Ext,
+    /// Recognized, but with nothing special about it
+    Other(Box<str>),
+    /// The platform can't tell
+    Unknown,
+}
+
+impl FilesystemType {
+    /// Name as reported by the OS, e.g. `NTFS` or `apfs`
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "ntfs" | "ntfs3" => Self::Ntfs,
+            "refs" => Self::Refs,
+            "fat" | "fat12" | "fat16" | "fat32" | "vfat" | "msdos" | "exfat" => Self::Fat,
+            "btrfs" => Self::Btrfs,
+            "xfs" => Self::Xfs,
+            "apfs" => Self::Apfs,
+            "ext2" | "ext3" | "ext4" => Self::Ext,
+            "" => Self::Unknown,
+            other => Self::Other(other.into()),
+        }
+    }
+
+    /// Files can share data while staying separate files (copy-on-write clones)
+    pub fn supports_reflink(&self) -> bool {
+        matches!(self, Self::Refs | Self::Btrfs | Self::Xfs | Self::Apfs)
+    }
+
+    /// Unknown filesystems are assumed to support hardlinks, since that's what is tried by default
+    pub fn supports_hardlinks(&self) -> bool {
+        !matches!(self, Self::Fat)
+    }
+
+    /// Most links a file can have, including its original name, or `None` if it's practically unlimited or unknown.
+    /// These are assumed from the filesystem type, e.g. NTFS allows 1024 names per file, so 1023 hardlinks of it.
+    pub fn max_hardlinks(&self) -> Option<u64> {
+        match self {
+            Self::Ntfs | Self::Refs => Some(1024),
+            Self::Fat => Some(1),
+            Self::Ext => Some(65000),
+            Self::Btrfs => Some(65535),
+            _ => None,
+        }
+    }
+}
+
+/// Type of the filesystem the path is on
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn filesystem_type(path: &Path) -> io::Result<FilesystemType> {
+    let st = statfs(path)?;
+    // The type of the field differs between libcs
+    #[allow(clippy::unnecessary_cast)]
+    let name = match st.f_type as u64 & 0xFFFF_FFFF {
+        0x5346544E | 0x7366746E => "ntfs",
+        0x4d44 => "vfat",
+        0x2011BAB0 => "exfat",
+        0x9123683E => "btrfs",
+        0x58465342 => "xfs",
+        0xEF53 => "ext4",
+        0x01021994 => "tmpfs",
+        0x2FC12FC1 => "zfs",
+        0xF2F52010 => "f2fs",
+        0x794C7630 => "overlayfs",
+        0x6969 => "nfs",
+        0xFF534D42 | 0xFE534D42 => "cifs",
+        0x65735546 => "fuse",
+        _ => "",
+    };
+    Ok(FilesystemType::from_name(name))
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn filesystem_type(path: &Path) -> io::Result<FilesystemType> {
+    use std::ffi::CStr;
+
+    let st = statfs(path)?;
+    let name = unsafe { CStr::from_ptr(st.f_fstypename.as_ptr()) };
+    Ok(FilesystemType::from_name(&name.to_string_lossy()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn statfs(path: &Path) -> io::Result<libc::statfs> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut st: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(st)
+}
+
+#[cfg(windows)]
+pub fn filesystem_type(path: &Path) -> io::Result<FilesystemType> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use windows_sys::Win32::Storage::FileSystem::{GetVolumeInformationW, GetVolumePathNameW};
+
+    let wide: Vec<u16> = crate::lazyfile::open_path(path).as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut root = vec![0u16; wide.len().max(4) + 1];
+    if unsafe { GetVolumePathNameW(wide.as_ptr(), root.as_mut_ptr(), root.len() as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut name = [0u16; 64];
+    let ok = unsafe {
+        GetVolumeInformationW(root.as_ptr(), ptr::null_mut(), 0, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), name.as_mut_ptr(), name.len() as u32)
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    Ok(FilesystemType::from_name(&String::from_utf16_lossy(&name[..len])))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", windows)))]
+pub fn filesystem_type(_: &Path) -> io::Result<FilesystemType> {
+    Ok(FilesystemType::Unknown)
+}
+
+/// Filesystem types looked up once per device
+#[derive(Debug, Default)]
+pub struct FilesystemCache {
+    devices: HashMap<u64, FilesystemType>,
+}
+
+impl FilesystemCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `metadata` must be of the same path. Errors are cached as `Unknown`, so that hardlinks are tried anyway.
+    pub fn get(&mut self, path: &Path, metadata: &Metadata) -> FilesystemType {
+        self.devices
+            .entry(metadata.dev)
+            .or_insert_with(|| filesystem_type(path).unwrap_or(FilesystemType::Unknown))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(FilesystemType::Ntfs, FilesystemType::from_name("NTFS"));
+        assert_eq!(FilesystemType::Fat, FilesystemType::from_name("exFAT"));
+        assert_eq!(FilesystemType::Other("zfs".into()), FilesystemType::from_name("zfs"));
+        assert!(FilesystemType::from_name("ReFS").supports_reflink());
+        assert!(!FilesystemType::Ntfs.supports_reflink());
+        assert!(!FilesystemType::Fat.supports_hardlinks());
+        assert!(FilesystemType::Unknown.supports_hardlinks());
+        assert_eq!(Some(1024), FilesystemType::Ntfs.max_hardlinks());
+        assert_eq!(None, FilesystemType::Unknown.max_hardlinks());
+    }
+
+    #[test]
+    fn cached_per_device() {
+        let tmp = tempdir::TempDir::new("volumetest").expect("tmp");
+        let path = tmp.path().join("a");
+        std::fs::write(&path, "hello").expect("write");
+        let metadata = Metadata::from_path(&path).expect("stat");
+
+        let fs_type = filesystem_type(&path).expect("statfs");
+        let mut cache = FilesystemCache::new();
+        assert_eq!(fs_type, cache.get(&path, &metadata));
+        // Other paths on the same device aren't looked up again
+        assert_eq!(fs_type, cache.get(Path::new("/nonexistent"), &metadata));
+    }
+}
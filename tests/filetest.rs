@@ -1,5 +1,7 @@
 use dupe_krill::*;
+use std::collections::BTreeMap;
 use std::fs;
+use std::sync::Arc;
 use tempdir::TempDir;
 
 #[test]
@@ -16,6 +18,9 @@ fn hardlink_of_same_file() {
     let b = FileContent::from_path(b_path).unwrap();
     assert_eq!(a, b);
     assert_eq!(b, b);
+    // Known to be the same without reading
+    assert_eq!(0, a.bytes_read() + b.bytes_read());
+    assert!(a.metadata().same_file(b.metadata()));
 }
 
 #[test]
@@ -70,6 +75,7 @@ fn same_content() {
 }
 
 #[test]
+#[cfg(unix)]
 fn symlink() {
     let dir = TempDir::new("sametest").unwrap();
     let a_path = dir.path().join("a").into_boxed_path();
@@ -84,3 +90,1078 @@ fn symlink() {
     assert_ne!(a, b);
     assert_eq!(b, b);
 }
+
+#[test]
+fn digest() {
+    let dir = TempDir::new("digesttest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    let c_path = dir.path().join("c").into_boxed_path();
+
+    let mut content = vec![0x55u8; 100_000];
+    fs::write(&a_path, &content).unwrap();
+    fs::write(&b_path, &content).unwrap();
+    content[99_999] = 0;
+    fs::write(&c_path, &content).unwrap();
+
+    assert_eq!(hash_file(&a_path).unwrap(), hash_file(&b_path).unwrap());
+    assert_ne!(hash_file(&a_path).unwrap(), hash_file(&c_path).unwrap());
+
+    let a = FileContent::from_path(a_path.clone()).unwrap();
+    let b = FileContent::from_path(b_path).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(hash_file(&a_path).unwrap(), a.digest().unwrap());
+    assert_eq!(a.digest().unwrap(), b.digest().unwrap());
+
+    // Prefix is the same, but the files differ
+    let c = FileContent::from_path(c_path.clone()).unwrap();
+    assert_eq!(a.quick_hash(4096).unwrap(), c.quick_hash(4096).unwrap());
+    assert_ne!(a.quick_hash(100_000).unwrap(), c.quick_hash(100_000).unwrap());
+    assert_ne!(a, c);
+
+    // The settings' algorithm and digest length are used
+    let metadata = fs::metadata(&a_path).unwrap();
+    let with_settings = |settings: HashSettings| {
+        FileContent::with_memo(a_path.clone(), &metadata, Arc::new(settings), &mut HashMemo::new()).quick_hash(4096).unwrap()
+    };
+    let xxh3 = with_settings(HashSettings { algorithm: HashAlgorithm::Xxh3, ..HashSettings::default() });
+    assert_ne!(a.quick_hash(4096).unwrap(), xxh3);
+    let short = with_settings(HashSettings { digest_len: 8, ..HashSettings::default() });
+    assert_eq!(a.quick_hash(4096).unwrap()[..8], short[..8]);
+    assert_eq!([0; 12], short[8..]);
+
+    a.clear_hashes();
+    assert_eq!(a, b);
+    assert_eq!(hash_file(&a_path).unwrap(), a.digest().unwrap());
+
+    let known = hash_file(&c_path).unwrap();
+    assert_eq!((true, known), FileContent::from_path(c_path).unwrap().matches_digest(&known).unwrap());
+    assert_eq!((false, a.digest().unwrap()), a.matches_digest(&known).unwrap());
+}
+
+#[test]
+#[allow(clippy::mutable_key_type)] // that's how the lazy hashing works
+fn unreadable_file_is_unique() {
+    let dir = TempDir::new("unreadabletest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    let c_path = dir.path().join("c").into_boxed_path();
+    fs::write(&a_path, "hello").unwrap();
+    fs::write(&b_path, "hello").unwrap();
+    fs::write(&c_path, "hello").unwrap();
+
+    let a = FileContent::from_path(a_path).unwrap();
+    let b = FileContent::from_path(b_path).unwrap();
+    let c = FileContent::from_path(c_path.clone()).unwrap();
+    fs::remove_file(&c_path).unwrap();
+
+    let mut groups = BTreeMap::new();
+    for (i, content) in [c, a, b].into_iter().enumerate() {
+        groups.entry(content).or_insert_with(Vec::new).push(i);
+    }
+    assert_eq!(2, groups.len());
+    for (content, members) in &groups {
+        if content.error().is_some() {
+            assert_eq!(&vec![0], members);
+        } else {
+            assert_eq!(&vec![1, 2], members);
+        }
+    }
+}
+
+#[test]
+fn unreadable_files_are_ordered_last() {
+    let dir = TempDir::new("unreadableordertest").unwrap();
+    let path = |name: &str| dir.path().join(name).into_boxed_path();
+    for (name, content) in [("a", "hello"), ("b", "world"), ("c", "hello"), ("d", "hallo"), ("e", "hello")] {
+        fs::write(path(name), content).unwrap();
+    }
+    let names = ["e", "a", "d", "c", "b"];
+    let files: Vec<_> = names.iter().map(|name| FileContent::from_path(path(name)).unwrap()).collect();
+    fs::remove_file(path("c")).unwrap();
+    fs::remove_file(path("e")).unwrap();
+
+    // Whichever order the errors are found in
+    let mut first = None;
+    for start in 0..files.len() {
+        let mut sorted: Vec<_> = files[start..].iter().chain(&files[..start]).collect();
+        sorted.sort();
+        let sorted_names: Vec<_> = sorted.iter().map(|&f| names[files.iter().position(|g| std::ptr::eq(f, g)).unwrap()]).collect();
+        let mut readable = sorted_names[..3].to_vec();
+        readable.sort_unstable();
+        assert_eq!((vec!["a", "b", "d"], &["c", "e"][..]), (readable, &sorted_names[3..]));
+        assert_eq!(sorted_names, *first.get_or_insert_with(|| sorted_names.clone()));
+        for pair in sorted.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn hash_state_round_trip() {
+    let dir = TempDir::new("statetest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+
+    fs::write(&a_path, vec![1; 100_000]).unwrap();
+    fs::write(&b_path, vec![1; 100_000]).unwrap();
+
+    let a = FileContent::from_path(a_path.clone()).unwrap();
+    let b = FileContent::from_path(b_path).unwrap();
+    assert_eq!(a, b);
+    let json = serde_json::to_string(&a.hash_state()).unwrap();
+
+    // Hashes are taken from the state, so the changed content isn't read
+    fs::write(&a_path, vec![2; 100_000]).unwrap();
+    fs::File::options().write(true).open(&a_path).unwrap().set_modified(a.metadata().modified.unwrap()).unwrap();
+    let a2 = FileContent::from_path(a_path.clone()).unwrap();
+    assert!(a2.restore_hash_state(serde_json::from_str(&json).unwrap()));
+    assert_eq!(a2, b);
+    assert_eq!(json, serde_json::to_string(&a2.hash_state()).unwrap());
+
+    // Different content of the same length is detected by the modification time, even if it's within the same second
+    let modified = a.metadata().modified.unwrap();
+    fs::write(&a_path, vec![3; 100_000]).unwrap();
+    fs::File::options().write(true).open(&a_path).unwrap().set_modified(modified + std::time::Duration::from_nanos(1)).unwrap();
+    let a3 = FileContent::from_path(a_path.clone()).unwrap();
+    assert!(!a3.restore_hash_state(serde_json::from_str(&json).unwrap()));
+    assert_ne!(a3, b);
+
+    // Or by the file ID, if it was written to a new file and moved into place
+    let new_path = dir.path().join("new");
+    fs::write(&new_path, vec![3; 100_000]).unwrap();
+    fs::File::options().write(true).open(&new_path).unwrap().set_modified(modified).unwrap();
+    fs::rename(&new_path, &a_path).unwrap();
+    let a4 = FileContent::from_path(a_path.clone()).unwrap();
+    assert!(!a4.restore_hash_state(serde_json::from_str(&json).unwrap()));
+    assert_ne!(a4, b);
+
+    // Different size is detected
+    fs::write(&a_path, vec![1; 100]).unwrap();
+    let a5 = FileContent::from_path(a_path).unwrap();
+    assert!(!a5.restore_hash_state(serde_json::from_str(&json).unwrap()));
+}
+
+#[test]
+fn fingerprint() {
+    let dir = TempDir::new("fingerprinttest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    fs::write(&a_path, "hello").unwrap();
+
+    let fingerprint = fingerprint_file(&a_path).unwrap();
+    assert_eq!(40, fingerprint.len());
+    // Fingerprints are stored by other tools, so this must never change
+    assert_eq!("8ba3657bb992be7676e187852af20ee8c4a6fc3a", fingerprint);
+    assert_eq!(fingerprint, FileContent::from_path(a_path).unwrap().fingerprint().unwrap());
+}
+
+#[test]
+fn group_by_content() {
+    let dir = TempDir::new("grouptest").unwrap();
+    let mut content = vec![3u8; 200_000];
+    let mut paths = Vec::new();
+    let mut write = |name: &str, content: &[u8]| {
+        let path = dir.path().join(name).into_boxed_path();
+        fs::write(&path, content).unwrap();
+        paths.push(path);
+    };
+    write("a", &content);
+    write("b", &content);
+    content[199_999] = 0;
+    write("c", &content);
+    write("d", &content);
+    content[0] = 0;
+    write("e", &content);
+    write("f", &content[..1000]);
+    write("g", &content);
+    write("h", b"");
+    write("i", b"");
+
+    let files: Vec<_> = paths.iter().map(|p| FileContent::from_path(p.clone()).unwrap()).collect();
+    let groups = FileContent::group_by_content(&files);
+    assert_eq!(vec![vec![0, 1], vec![2, 3], vec![4, 6], vec![5], vec![7, 8]], groups);
+
+    // Same as pairwise comparisons
+    let files: Vec<_> = paths.iter().map(|p| FileContent::from_path(p.clone()).unwrap()).collect();
+    for group in &groups {
+        for (i, a) in files.iter().enumerate() {
+            assert_eq!(group.contains(&i), *a == files[group[0]], "{} vs {}", i, group[0]);
+        }
+    }
+}
+
+#[test]
+fn first_chunk_index() {
+    let dir = TempDir::new("firstchunktest").unwrap();
+    let mut content = vec![3u8; 200_000];
+    let mut paths = Vec::new();
+    let mut write = |name: &str, content: &[u8]| {
+        let path = dir.path().join(name).into_boxed_path();
+        fs::write(&path, content).unwrap();
+        paths.push(path);
+    };
+    write("a", &content);
+    content[199_999] = 0;
+    write("b", &content);
+    content[0] = 0;
+    write("c", &content);
+    write("d", &content[..1000]);
+    paths.push(dir.path().join("missing").into_boxed_path());
+
+    let mut files: Vec<_> = paths[..4].iter().map(|p| FileContent::from_path(p.clone()).unwrap()).collect();
+    files.push(FileContent::new(paths[4].clone(), Default::default()));
+    let index = FirstChunkIndex::build(&files);
+    let mut buckets: Vec<_> = index.buckets().map(|b| b.to_vec()).collect();
+    buckets.sort();
+    assert_eq!(vec![vec![0, 1], vec![2], vec![3], vec![4]], buckets);
+
+    // Only the first chunk has been read
+    assert!(files[0].bytes_read() < 200_000);
+    assert!(files[0] != files[1]);
+}
+
+#[test]
+fn hash_memo() {
+    let dir = TempDir::new("memotest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    let c_path = dir.path().join("c").into_boxed_path();
+    fs::write(&a_path, vec![1u8; 100_000]).unwrap();
+    fs::hard_link(&a_path, &b_path).unwrap();
+    fs::write(&c_path, vec![1u8; 100_000]).unwrap();
+
+    let mut memo = HashMemo::new();
+    let settings = Arc::new(HashSettings::default());
+    let open = |path: &std::path::Path, memo: &mut HashMemo| {
+        FileContent::with_memo(path.into(), &fs::metadata(path).unwrap(), Arc::clone(&settings), memo)
+    };
+    let a = open(&a_path, &mut memo);
+    let b = open(&b_path, &mut memo);
+    let c = open(&c_path, &mut memo);
+    assert_eq!(a, b);
+    assert_eq!(0, a.bytes_read());
+
+    // Hashes computed via one path are reused via the other
+    assert_eq!(a, c);
+    assert_eq!(100_000, b.bytes_read());
+    assert_eq!(b.digest().unwrap(), c.digest().unwrap());
+    assert_eq!(100_000, a.bytes_read());
+
+    let files = [a, b, c, open(&a_path, &mut memo)];
+    assert_eq!(vec![vec![0, 1, 2, 3]], FileContent::group_by_content(&files));
+}
+
+#[test]
+fn different_sizes_are_not_opened() {
+    let dir = TempDir::new("lazytest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+    let a = FileContent::from_path(a_path.clone()).unwrap();
+    let b = FileContent::from_path(b_path.clone()).unwrap();
+
+    // Opening them now would fail
+    fs::remove_file(&a_path).unwrap();
+    fs::remove_file(&b_path).unwrap();
+    assert!(a < b);
+    assert!(a.error().is_none());
+    assert!(b.error().is_none());
+}
+
+#[test]
+fn changed_during_scan() {
+    let dir = TempDir::new("changedtest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+    let a = FileContent::from_path(a_path.clone()).unwrap();
+    let b = FileContent::from_path(b_path).unwrap();
+
+    fs::write(&a_path, "hello").unwrap();
+    assert_eq!(None, a.try_cmp(&b));
+    let err = a.error().unwrap();
+    assert!(err.get_ref().unwrap().downcast_ref::<FileError>().unwrap().changed);
+    assert!(b.error().is_none());
+}
+
+/// Windows allows creating symlinks only with a privilege or in developer mode
+#[cfg(windows)]
+fn make_symlink(target: &str, path: &std::path::Path) -> bool {
+    std::os::windows::fs::symlink_file(target, path).is_ok()
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &std::path::Path) -> bool {
+    std::os::unix::fs::symlink(target, path).unwrap();
+    true
+}
+
+#[cfg(any(unix, windows))]
+#[test]
+fn symlink_target_sizes() {
+    let dir = TempDir::new("symlinksizetest").unwrap();
+    let links = dir.path().join("links");
+    fs::create_dir(&links).unwrap();
+    fs::write(dir.path().join("t1"), "hello").unwrap();
+    fs::write(dir.path().join("t2"), "hello").unwrap();
+    let mut paths = Vec::new();
+    for (name, target) in [("a", "../t1"), ("b", "../t1"), ("c", "../t2")] {
+        let path = links.join(name).into_boxed_path();
+        if !make_symlink(target, &path) {
+            return;
+        }
+        paths.push(path);
+    }
+
+    // Symlinks are the same only if their targets are, even where they're reported as empty
+    let settings = Arc::new(HashSettings { compare_symlink_targets: true, ..HashSettings::default() });
+    let files: Vec<_> = paths.iter().map(|path| {
+        FileContent::with_memo(path.clone(), &fs::symlink_metadata(path).unwrap(), Arc::clone(&settings), &mut HashMemo::new())
+    }).collect();
+    assert!(files.iter().all(|f| f.metadata().size == 5));
+    assert_eq!(files[0], files[1]);
+    assert!(files[0] != files[2]);
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.empty_files = EmptyFiles::Link;
+    Arc::make_mut(&mut d.settings.hashing).compare_symlink_targets = true;
+    d.scan(&links).unwrap();
+    let dupes: Vec<_> = d.dupes().into_iter().filter(|sets| sets.len() > 1).collect();
+    assert_eq!(1, dupes.len());
+    let mut linked: Vec<_> = dupes[0].iter().flat_map(|set| set.paths.iter().cloned()).collect();
+    linked.sort();
+    assert_eq!(&paths[..2], &linked[..]);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_targets() {
+    let dir = TempDir::new("symlinktest").unwrap();
+    let link = |name: &str, target: &str| {
+        let path = dir.path().join(name).into_boxed_path();
+        std::os::unix::fs::symlink(target, &path).unwrap();
+        path
+    };
+    fs::write(dir.path().join("t1"), "hello").unwrap();
+    fs::write(dir.path().join("t2"), "hello").unwrap();
+    let paths = [link("a", "t1"), link("b", "t1"), link("c", "t2"), link("d", "missing"), link("e", "missing")];
+
+    let settings = Arc::new(HashSettings { compare_symlink_targets: true, ..HashSettings::default() });
+    let mut memo = HashMemo::new();
+    let files: Vec<_> = paths.iter().map(|path| {
+        FileContent::with_memo(path.clone(), &fs::symlink_metadata(path).unwrap(), Arc::clone(&settings), &mut memo)
+    }).collect();
+    assert_eq!(files[0], files[1]);
+    assert!(files[0] != files[2]);
+    // Broken links are fine
+    assert_eq!(files[3], files[4]);
+    assert!(files.iter().all(|f| f.error().is_none()));
+}
+
+#[test]
+fn error_log() {
+    let dir = TempDir::new("errorlogtest").unwrap();
+    let log = Arc::new(ErrorLog::new());
+    let settings = Arc::new(HashSettings { error_log: Some(Arc::clone(&log)), ..HashSettings::default() });
+    let mut memo = HashMemo::new();
+    let mut files = Vec::new();
+    for name in ["a", "b", "c"] {
+        let path = dir.path().join(name).into_boxed_path();
+        fs::write(&path, "hello world").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        files.push(FileContent::with_memo(path, &metadata, Arc::clone(&settings), &mut memo));
+    }
+    fs::remove_file(dir.path().join("b")).unwrap();
+
+    assert_eq!(None, files[0].try_cmp(&files[1]));
+    assert_eq!(None, files[2].try_cmp(&files[1]));
+    assert_eq!(Some(std::cmp::Ordering::Equal), files[0].try_cmp(&files[2]));
+    // The failed file is logged once
+    let errors = log.errors();
+    assert_eq!(1, errors.len());
+    assert_eq!(&*dir.path().join("b"), &*errors[0].path);
+    assert_eq!(std::io::ErrorKind::NotFound, errors[0].kind);
+    assert_eq!(1, files[1].read_errors());
+    assert_eq!(0, files[0].read_errors());
+}
+
+#[test]
+fn no_files_open_after_comparison() {
+    let dir = TempDir::new("opentest").unwrap();
+    let limit = Arc::new(OpenFileLimit::new(4));
+    let settings = Arc::new(HashSettings { open_files: Some(Arc::clone(&limit)), small_file_threshold: 0, ..HashSettings::default() });
+    let mut memo = HashMemo::new();
+    let mut files = Vec::new();
+    for name in ["a", "b", "c"] {
+        let path = dir.path().join(name).into_boxed_path();
+        fs::write(&path, vec![7u8; 100_000]).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        files.push(FileContent::with_memo(path, &metadata, Arc::clone(&settings), &mut memo));
+    }
+    assert_eq!(vec![vec![0, 1, 2]], FileContent::group_by_content(&files));
+    assert!(limit.peak() > 0);
+    assert_eq!(0, limit.open());
+}
+
+#[test]
+fn different_mtimes_are_grouped() {
+    let dir = TempDir::new("mtimetest").unwrap();
+    let mut files = Vec::new();
+    for (name, age) in [("a", 0), ("b", 1_000_000)] {
+        let path = dir.path().join(name).into_boxed_path();
+        fs::write(&path, "hello world").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(age)).unwrap();
+        files.push(FileContent::from_path(path).unwrap());
+    }
+    assert_eq!(vec![vec![0, 1]], FileContent::group_by_content(&files));
+}
+
+#[test]
+fn creation_time() {
+    let dir = TempDir::new("createdtest").unwrap();
+    let path = dir.path().join("a");
+    fs::write(&path, "hello").unwrap();
+    let metadata = Metadata::from_path(&path).unwrap();
+    #[cfg(any(windows, target_os = "macos"))]
+    assert!(metadata.created.is_some());
+    if let Some(created) = metadata.created {
+        assert!(created <= std::time::SystemTime::now());
+    }
+    // Also when the metadata comes from scanning, which reads file IDs separately on Windows
+    #[cfg(windows)]
+    assert_eq!(metadata.created, FileContent::from_path(path.clone().into_boxed_path()).unwrap().metadata().created);
+
+    // Doesn't affect grouping
+    let older = Metadata { created: Some(std::time::UNIX_EPOCH), ..metadata };
+    assert_eq!(metadata, older);
+    let a = FileContent::new(path.clone().into_boxed_path(), metadata);
+    let b = FileContent::new(path.into_boxed_path(), older);
+    assert_eq!(Some(std::time::UNIX_EPOCH), b.metadata().created);
+    assert_eq!(vec![vec![0, 1]], FileContent::group_by_content(&[a, b]));
+}
+
+#[test]
+fn across_devices() {
+    let dir = TempDir::new("devicetest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+    let a_metadata = Metadata { dev: 1, ..Metadata::from_path(&a_path).unwrap() };
+    let b_metadata = Metadata { dev: 2, ..Metadata::from_path(&b_path).unwrap() };
+
+    for across_devices in [false, true] {
+        let grouping = GroupPolicy { across_devices, ..GroupPolicy::default() };
+        let a = FileContent::with_settings(a_path.clone(), a_metadata, Arc::default()).with_grouping(grouping);
+        let b = FileContent::with_settings(b_path.clone(), b_metadata, Arc::default()).with_grouping(grouping);
+        assert_eq!(across_devices, a == b);
+        let expected = if across_devices { vec![vec![0, 1]] } else { vec![vec![0], vec![1]] };
+        assert_eq!(expected, FileContent::group_by_content(&[a, b]));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn link_count() {
+    let dir = TempDir::new("nlinktest").unwrap();
+    let a_path = dir.path().join("a");
+    let b_path = dir.path().join("b");
+    fs::write(&a_path, "hello").unwrap();
+    assert_eq!(1, Metadata::from_path(&a_path).unwrap().nlink);
+    fs::hard_link(&a_path, &b_path).unwrap();
+    let a = Metadata::from_path(&a_path).unwrap();
+    let b = Metadata::from_path(&b_path).unwrap();
+    assert_eq!(2, a.nlink);
+    assert_eq!((a.dev, a.ino), (b.dev, b.ino));
+    assert_ne!(0, a.ino);
+}
+
+#[test]
+fn alternate_data_streams() {
+    let dir = TempDir::new("streamtest").unwrap();
+    let grouping = GroupPolicy { compare_streams: true, ..GroupPolicy::default() };
+    let settings = Arc::new(HashSettings::default());
+    let mut memo = HashMemo::new();
+    let mut files = Vec::new();
+    for name in ["a", "b", "c"] {
+        let path = dir.path().join(name).into_boxed_path();
+        fs::write(&path, "hello world").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        files.push(FileContent::with_memo(path, &metadata, Arc::clone(&settings), &mut memo).with_grouping(grouping));
+    }
+    // Only NTFS can add streams to a file
+    if cfg!(windows) {
+        fs::write(format!("{}:Zone.Identifier", dir.path().join("c").display()), "[ZoneTransfer]").unwrap();
+        assert_eq!(files[0], files[1]);
+        assert_ne!(files[0], files[2]);
+        assert_eq!(vec![vec![0, 1], vec![2]], FileContent::group_by_content(&files));
+        assert_eq!(vec![dir.path().join("a").into_boxed_path()], files[2].take_differing_streams());
+        assert!(files[0].take_differing_streams().is_empty());
+    } else {
+        assert_eq!(files[0], files[2]);
+        assert_eq!(vec![vec![0, 1, 2]], FileContent::group_by_content(&files));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn owners() {
+    let dir = TempDir::new("ownertest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+    // Only root can give files away
+    if std::os::unix::fs::chown(&b_path, Some(12345), None).is_err() {
+        return;
+    }
+
+    for compare_owners in [false, true] {
+        let grouping = GroupPolicy { compare_owners, ..GroupPolicy::default() };
+        let a = FileContent::new(a_path.clone(), Metadata::from_path(&a_path).unwrap()).with_grouping(grouping);
+        let b = FileContent::new(b_path.clone(), Metadata::from_path(&b_path).unwrap()).with_grouping(grouping);
+        assert_eq!(12345, b.metadata().uid);
+        assert_eq!(!compare_owners, a == b);
+        let groups = FileContent::group_by_content(&[a, b]);
+        assert_eq!(if compare_owners { 2 } else { 1 }, groups.len());
+    }
+}
+
+#[cfg(windows)]
+#[test]
+fn owner_sids() {
+    let dir = TempDir::new("ownertest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+
+    // Both are owned by whoever runs the test, and a file that's gone has an owner of its own
+    let grouping = GroupPolicy { compare_owners: true, ..GroupPolicy::default() };
+    let a = FileContent::new(a_path.clone(), Metadata::from_path(&a_path).unwrap()).with_grouping(grouping);
+    let b = FileContent::new(b_path.clone(), Metadata::from_path(&b_path).unwrap()).with_grouping(grouping);
+    assert_ne!(0, a.metadata().uid);
+    assert_eq!((a.metadata().uid, a.metadata().gid), (b.metadata().uid, b.metadata().gid));
+    assert!(a == b);
+    let gone = dir.path().join("gone").into_boxed_path();
+    let gone = FileContent::new(gone, *b.metadata()).with_grouping(grouping);
+    assert_ne!(b.metadata().uid, gone.metadata().uid);
+}
+
+#[test]
+fn attributes() {
+    let dir = TempDir::new("attributetest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+    let mut permissions = fs::metadata(&b_path).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&b_path, permissions).unwrap();
+
+    for compare_attributes in [false, true] {
+        let grouping = GroupPolicy { compare_attributes, ..GroupPolicy::default() };
+        let a = FileContent::new(a_path.clone(), Metadata::from_path(&a_path).unwrap()).with_grouping(grouping);
+        let b = FileContent::new(b_path.clone(), Metadata::from_path(&b_path).unwrap()).with_grouping(grouping);
+        assert!(!a.metadata().readonly);
+        assert!(b.metadata().readonly);
+        assert_eq!(!compare_attributes, a == b);
+    }
+}
+
+#[test]
+fn metadata_cache() {
+    let dir = TempDir::new("metadatacache").unwrap();
+    let a_path = dir.path().join("a");
+    fs::write(&a_path, "hello").unwrap();
+
+    let mut cache = MetadataCache::new();
+    assert_eq!(5, cache.get(&a_path).unwrap().size);
+
+    // Not read again until invalidated
+    fs::write(&a_path, "hello world").unwrap();
+    assert_eq!(5, cache.get(&a_path).unwrap().size);
+    cache.invalidate(&a_path);
+    assert_eq!(11, cache.get(&a_path).unwrap().size);
+
+    assert!(cache.get(&dir.path().join("missing")).is_err());
+}
+
+#[test]
+fn metadata_order() {
+    use std::cmp::Ordering;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let small = Metadata { size: 1, dev: 2, ..Metadata::default() };
+    let large = Metadata { size: 2, dev: 1, ..Metadata::default() };
+    assert!(small < large);
+    assert!(Metadata { dev: 3, ..small } > small);
+
+    // Only size and device are compared
+    let old = Metadata { ino: 2, created: Some(UNIX_EPOCH), ..small };
+    let new = Metadata { ino: 1, created: Some(UNIX_EPOCH + Duration::from_secs(1)), ..small };
+    let unknown = Metadata { ino: 0, created: None, ..small };
+    assert_eq!(Ordering::Equal, old.cmp(&new));
+    assert_eq!(Ordering::Equal, new.cmp(&unknown));
+
+    let mut sorted = [unknown, new, old, Metadata { ino: 1, ..old }];
+    sorted.sort_by(Metadata::tie_break);
+    assert_eq!(vec![1, 2, 1, 0], sorted.iter().map(|m| m.ino).collect::<Vec<_>>());
+    assert_eq!(Ordering::Less, old.tie_break(&large));
+}
+
+#[test]
+fn file_name() {
+    let dir = TempDir::new("nametest").unwrap();
+    let path = dir.path().join("hello.txt");
+    fs::write(&path, "hello").unwrap();
+
+    assert_eq!("hello.txt", FileContent::from_path(path.into_boxed_path()).unwrap().name());
+}
+
+#[test]
+fn missing_file_metadata() {
+    let dir = TempDir::new("missingtest").unwrap();
+    let path = dir.path().join("missing").into_boxed_path();
+    let err = FileContent::from_path(path.clone()).unwrap_err();
+    assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+    let err = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).unwrap();
+    assert!(err.metadata);
+    assert_eq!(path, err.path);
+}
+
+#[test]
+fn hardlink_file_set() {
+    let dir = TempDir::new("linktest").unwrap();
+    let paths: Vec<_> = ["a", "b", "c"].iter().map(|name| dir.path().join(name).into_boxed_path()).collect();
+    for path in &paths {
+        fs::write(path, "hello world").unwrap();
+    }
+    // Already has a link elsewhere, so replacing it doesn't free any space
+    fs::hard_link(&paths[2], dir.path().join("elsewhere")).unwrap();
+
+    let mut set = FileSet::new(paths[0].clone(), 1);
+    set.push(paths[1].clone());
+    set.push(paths[2].clone());
+    let summary = set.hardlink().unwrap();
+    // Space on disk depends on the filesystem
+    assert_eq!(LinkSummary { links: 2, bytes_saved: 11, allocated_bytes_saved: summary.allocated_bytes_saved, ..LinkSummary::default() }, summary);
+    let ino = Metadata::from_path(&paths[0]).unwrap().ino;
+    assert!(paths.iter().all(|p| Metadata::from_path(p).unwrap().ino == ino));
+    assert_eq!(3, Metadata::from_path(&paths[0]).unwrap().nlink);
+    assert_eq!(b"hello world"[..], fs::read(&paths[1]).unwrap()[..]);
+
+    // A failed step leaves the file as it was
+    let missing = dir.path().join("missing").into_boxed_path();
+    let d_path = dir.path().join("d").into_boxed_path();
+    fs::write(&d_path, "hello world").unwrap();
+    let mut set = FileSet::new(missing, 1);
+    set.push(d_path.clone());
+    assert!(set.hardlink().is_err());
+    assert_eq!(1, Metadata::from_path(&d_path).unwrap().nlink);
+    assert_eq!(5, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn symlink_file_set() {
+    let dir = TempDir::new("symlinktest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+
+    let mut set = FileSet::new(a_path.clone(), 1);
+    set.push(b_path.clone());
+    let summary = set.symlink().unwrap();
+    assert_eq!(LinkSummary { links: 1, bytes_saved: 11, allocated_bytes_saved: summary.allocated_bytes_saved, ..LinkSummary::default() }, summary);
+    assert!(fs::symlink_metadata(&a_path).unwrap().is_file());
+    assert_eq!(&*a_path, fs::read_link(&b_path).unwrap());
+    assert_eq!(b"hello world"[..], fs::read(&b_path).unwrap()[..]);
+    assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn symlink_relative_paths() {
+    // Relative to the current directory, which isn't the directory of the link
+    let dir = TempDir::new_in(".", "relsymlinktest").unwrap();
+    let cwd = std::env::current_dir().unwrap();
+    let rel = dir.path().strip_prefix(&cwd).unwrap_or_else(|_| dir.path());
+    fs::create_dir(rel.join("sub")).unwrap();
+    let a_path = rel.join("a").into_boxed_path();
+    let b_path = rel.join("sub").join("b").into_boxed_path();
+    assert!(a_path.is_relative());
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+
+    let mut set = FileSet::new(a_path.clone(), 1);
+    set.push(b_path.clone());
+    assert_eq!(1, set.symlink().unwrap().links);
+    assert_eq!(cwd.join(&a_path), fs::read_link(&b_path).unwrap());
+    assert_eq!(b"hello world"[..], fs::read(&b_path).unwrap()[..]);
+}
+
+#[test]
+fn delete_duplicates() {
+    let dir = TempDir::new("deletetest").unwrap();
+    let paths: Vec<_> = ["a", "b", "c"].iter().map(|name| dir.path().join(name).into_boxed_path()).collect();
+    for path in &paths {
+        fs::write(path, "hello world").unwrap();
+    }
+    let mut set = FileSet::new(paths[0].clone(), 1);
+    set.push(paths[1].clone());
+    set.push(paths[2].clone());
+
+    let plan = set.plan_deletion();
+    assert_eq!(paths[0], plan.master);
+    assert_eq!(&paths[1..], &plan.delete[..]);
+    assert!(plan.execute(false).is_err());
+    assert_eq!(3, fs::read_dir(dir.path()).unwrap().count());
+
+    // Changed after it was found
+    fs::write(&paths[2], "hello World").unwrap();
+    let summary = plan.execute(true).unwrap();
+    assert_eq!((1, 11), (summary.deleted, summary.bytes_saved));
+    assert_eq!(vec![paths[2].clone()], summary.changed);
+    assert!(paths[0].exists());
+    assert!(!paths[1].exists());
+    assert!(paths[2].exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn delete_master_alias() {
+    let dir = TempDir::new("deletealiastest").unwrap();
+    let master = dir.path().join("real/a").into_boxed_path();
+    let alias = dir.path().join("link/a").into_boxed_path();
+    let copy = dir.path().join("b").into_boxed_path();
+    fs::create_dir(dir.path().join("real")).unwrap();
+    std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("link")).unwrap();
+    fs::write(&master, "hello world").unwrap();
+    fs::write(&copy, "hello world").unwrap();
+
+    // The alias is the same file, not a link of it, so it's only seen to be the master by resolving the path
+    let mut set = FileSet::new(master.clone(), 1);
+    set.push(alias.clone());
+    set.push(copy.clone());
+    let summary = set.plan_deletion().execute(true).unwrap();
+    assert_eq!(1, summary.deleted);
+    assert_eq!(vec![alias], summary.aliases);
+    assert!(master.exists());
+    assert!(!copy.exists());
+}
+
+#[test]
+fn reflink_file_set() {
+    let dir = TempDir::new("reflinktest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    fs::write(&a_path, "hello world").unwrap();
+    fs::write(&b_path, "hello world").unwrap();
+    let mut set = FileSet::new(a_path.clone(), 1);
+    set.push(b_path.clone());
+
+    let summary = set.reflink(ReflinkFallback::Report).unwrap();
+    if summary.reflinks == 1 {
+        // Clones are separate files
+        assert_ne!(Metadata::from_path(&a_path).unwrap().ino, Metadata::from_path(&b_path).unwrap().ino);
+        assert_eq!(b"hello world"[..], fs::read(&b_path).unwrap()[..]);
+        return;
+    }
+    assert!(!filesystem_type(&a_path).unwrap().supports_reflink());
+    assert_eq!(LinkSummary { unsupported: 1, ..LinkSummary::default() }, summary);
+    assert_eq!(1, Metadata::from_path(&b_path).unwrap().nlink);
+
+    let summary = set.reflink(ReflinkFallback::Hardlink).unwrap();
+    assert_eq!(LinkSummary { links: 1, bytes_saved: 11, allocated_bytes_saved: summary.allocated_bytes_saved, ..LinkSummary::default() }, summary);
+    assert_eq!(Metadata::from_path(&a_path).unwrap().ino, Metadata::from_path(&b_path).unwrap().ino);
+    assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn oldest_master() {
+    let dir = TempDir::new("mastertest").unwrap();
+    let now = std::time::SystemTime::now();
+    let mut set: Option<FileSet> = None;
+    // c and a have the same time, so a wins
+    for (name, age) in [("b", 10), ("c", 20), ("a", 20), ("d", 5)] {
+        let path = dir.path().join(name).into_boxed_path();
+        let file = fs::File::create(&path).unwrap();
+        file.set_modified(now - std::time::Duration::from_secs(age)).unwrap();
+        match &mut set {
+            Some(set) => set.push(path),
+            None => set = Some(FileSet::new(path, 1)),
+        }
+    }
+    let mut set = set.unwrap();
+    assert_eq!(0, set.master(&MasterPolicy::First).unwrap());
+    assert_eq!(2, set.master(&MasterPolicy::OldestMtime).unwrap());
+    set.set_master(&MasterPolicy::OldestMtime).unwrap();
+    let names: Vec<_> = set.paths.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+    assert_eq!(["a", "b", "c", "d"], &names[..]);
+}
+
+#[test]
+fn newest_master() {
+    let dir = TempDir::new("newesttest").unwrap();
+    let now = std::time::SystemTime::now();
+    let mut set: Option<FileSet> = None;
+    for (name, age) in [("a", 30), ("b", 10), ("c", 20)] {
+        let path = dir.path().join(name).into_boxed_path();
+        let file = fs::File::create(&path).unwrap();
+        file.set_modified(now - std::time::Duration::from_secs(age)).unwrap();
+        match &mut set {
+            Some(set) => set.push(path),
+            None => set = Some(FileSet::new(path, 1)),
+        }
+    }
+    let set = set.unwrap();
+    assert_eq!(1, set.master(&MasterPolicy::NewestMtime).unwrap());
+    assert_eq!(0, set.master(&MasterPolicy::OldestMtime).unwrap());
+}
+
+#[test]
+fn path_priority_master() {
+    let mut set = FileSet::new(std::path::Path::new("/other/a").into(), 1);
+    set.push(std::path::Path::new("/cache/b").into());
+    set.push(std::path::Path::new("/canonical/z").into());
+    set.push(std::path::Path::new("/canonical/y").into());
+    set.push(std::path::Path::new("/canonicalish/x").into());
+
+    let policy = MasterPolicy::PathPriority(vec!["/canonical".into(), "/cache".into()]);
+    assert_eq!(3, set.master(&policy).unwrap());
+    let policy = MasterPolicy::PathPriority(vec!["/cache".into(), "/canonical".into()]);
+    assert_eq!(1, set.master(&policy).unwrap());
+    // Nothing matches
+    let policy = MasterPolicy::PathPriority(vec!["/elsewhere".into()]);
+    assert_eq!(1, set.master(&policy).unwrap());
+}
+
+#[test]
+fn rollback_failed_group() {
+    let dir = TempDir::new("rollbacktest").unwrap();
+    let paths: Vec<_> = ["a", "b", "c", "d"].iter().map(|name| dir.path().join(name).into_boxed_path()).collect();
+    for path in &paths {
+        fs::write(path, "hello world").unwrap();
+    }
+    let inodes = || paths.iter().map(|p| Metadata::from_path(p).unwrap().ino).collect::<Vec<_>>();
+    let before = inodes();
+    let mut set = FileSet::new(paths[0].clone(), 1);
+    for path in &paths[1..] {
+        set.push(path.clone());
+    }
+    let plan = set.plan(LinkAction::Hardlink).unwrap();
+
+    // The third link fails, because the file has changed since
+    fs::write(&paths[3], "hello").unwrap();
+    let err = plan.execute().unwrap_err();
+    let err = err.get_ref().and_then(|e| e.downcast_ref::<FileError>()).unwrap();
+    assert!(err.rolled_back);
+    assert_eq!(paths[3], err.path);
+    assert_eq!(before, inodes());
+    assert!(paths.iter().all(|p| Metadata::from_path(p).unwrap().nlink == 1));
+    assert_eq!(4, fs::read_dir(dir.path()).unwrap().count());
+
+    // The plan is for files as they were, so it takes a new one
+    fs::write(&paths[3], "hello world").unwrap();
+    let plan = set.plan(LinkAction::Hardlink).unwrap();
+    assert_eq!(3, plan.execute().unwrap().links);
+    assert_eq!(4, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn replaced_path_always_exists() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let dir = TempDir::new("atomictest").unwrap();
+    let a_path = dir.path().join("a").into_boxed_path();
+    let b_path = dir.path().join("b").into_boxed_path();
+    let dest = dir.path().join("dest").into_boxed_path();
+    for path in [&a_path, &b_path, &dest] {
+        fs::write(path, "hello world").unwrap();
+    }
+
+    let (started, done) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+    let watcher = {
+        let (started, done, dest) = (Arc::clone(&started), Arc::clone(&done), dest.clone());
+        std::thread::spawn(move || {
+            let mut checks = 0;
+            while !done.load(Ordering::Relaxed) {
+                assert!(fs::symlink_metadata(&dest).is_ok());
+                started.store(true, Ordering::Relaxed);
+                checks += 1;
+            }
+            checks
+        })
+    };
+    while !started.load(Ordering::Relaxed) {
+        std::thread::yield_now();
+    }
+    for i in 0..200 {
+        let mut set = FileSet::new(if i % 2 == 0 { a_path.clone() } else { b_path.clone() }, 1);
+        set.push(dest.clone());
+        set.hardlink().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    assert!(watcher.join().unwrap() > 0);
+    assert_eq!(3, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn split_by_links() {
+    let dir = TempDir::new("splittest").unwrap();
+    let paths: Vec<_> = ["a", "b", "c", "d", "e"].iter().map(|name| dir.path().join(name).into_boxed_path()).collect();
+    for path in &paths {
+        fs::write(path, "hello world").unwrap();
+    }
+    // Already a link of the master, so it doesn't need another one
+    let linked = dir.path().join("linked").into_boxed_path();
+    fs::hard_link(&paths[0], &linked).unwrap();
+
+    let mut set = FileSet::new(paths[0].clone(), 2);
+    set.push(linked.clone());
+    for path in &paths[1..] {
+        set.push(path.clone());
+    }
+    let sets = set.split_by_links(3).unwrap();
+    assert_eq!(2, sets.len());
+    assert_eq!(&[paths[0].clone(), linked, paths[1].clone()][..], &sets[0].paths[..]);
+    assert_eq!(&paths[2..], &sets[1].paths[..]);
+    assert!(sets.iter().all(|set| set.max_hardlinks == 3));
+
+    let summary = sets[0].hardlink().unwrap();
+    assert_eq!(LinkSummary { links: 1, already_linked: 1, bytes_saved: 11, allocated_bytes_saved: summary.allocated_bytes_saved, ..LinkSummary::default() }, summary);
+    assert_eq!(2, sets[1].hardlink().unwrap().links);
+    assert_eq!(3, Metadata::from_path(&paths[2]).unwrap().nlink);
+    assert_eq!(Metadata::from_path(&paths[2]).unwrap().ino, Metadata::from_path(&paths[4]).unwrap().ino);
+    // Once linked, they are all links of the master
+    let sets = sets[1].split_by_links(3).unwrap();
+    assert_eq!(1, sets.len());
+}
+
+#[test]
+fn differently_cased_files() {
+    let dir = TempDir::new("casetest").unwrap();
+    let lower = dir.path().join("a").into_boxed_path();
+    let upper = dir.path().join("A").into_boxed_path();
+    fs::write(&lower, "hello").unwrap();
+    fs::write(&upper, "world").unwrap();
+    // Only case-sensitive directories can have both
+    if fs::read(&lower).unwrap() != b"hello" {
+        return;
+    }
+    let a = FileContent::from_path(lower.clone()).unwrap();
+    let b = FileContent::from_path(upper.clone()).unwrap();
+    assert_ne!(a, b);
+
+    let mut set = FileSet::new(lower.clone(), 1);
+    set.push(upper.clone());
+    assert_eq!(2, FileSet::new(lower, 1).merge(FileSet::new(upper, 1)).paths.len());
+    assert_eq!(1, set.split_by_directory().len());
+}
+
+#[test]
+fn merge_file_sets() {
+    let path = |name: &str| std::path::Path::new(name).into();
+    let mut a = FileSet::new(path("/a"), 2);
+    a.push(path("/b"));
+    let mut b = FileSet::new(path("/b"), 3);
+    b.push(path("/c"));
+    b.push(path("/a"));
+
+    let merged = a.merge(b);
+    assert_eq!(3, merged.max_hardlinks);
+    assert_eq!(&[path("/a"), path("/b"), path("/c")][..], &merged.paths[..]);
+}
+
+#[test]
+fn already_linked() {
+    let dir = TempDir::new("linkedtest").unwrap();
+    let paths: Vec<_> = ["a", "b", "c"].iter().map(|name| dir.path().join(name).into_boxed_path()).collect();
+    fs::write(&paths[0], "hello world").unwrap();
+    fs::hard_link(&paths[0], &paths[1]).unwrap();
+    fs::write(&paths[2], "hello world").unwrap();
+    let ino = Metadata::from_path(&paths[0]).unwrap().ino;
+
+    let mut set = FileSet::new(paths[0].clone(), 2);
+    set.push(paths[1].clone());
+    set.push(paths[2].clone());
+    for action in [LinkAction::Hardlink, LinkAction::Symlink, LinkAction::Reflink(ReflinkFallback::Hardlink)] {
+        let plan = set.plan(action).unwrap();
+        assert_eq!(&paths[2..], &plan.paths[..]);
+        assert_eq!((1, 11), (plan.already_linked, plan.bytes_saved));
+    }
+
+    let summary = set.hardlink().unwrap();
+    assert_eq!(LinkSummary { links: 1, already_linked: 1, bytes_saved: 11, allocated_bytes_saved: summary.allocated_bytes_saved, ..LinkSummary::default() }, summary);
+    assert!(paths.iter().all(|p| Metadata::from_path(p).unwrap().ino == ino));
+    // Hardlinks of the master aren't replaced with symlinks either
+    assert_eq!(2, set.symlink().unwrap().already_linked);
+    assert!(!fs::symlink_metadata(&paths[1]).unwrap().file_type().is_symlink());
+    assert_eq!(3, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn link_readonly_files() {
+    let dir = TempDir::new("readonlytest").unwrap();
+    let set_readonly = |path: &std::path::Path, readonly: bool| {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(readonly);
+        fs::set_permissions(path, permissions).unwrap();
+    };
+    // Whether the master and the duplicate are read-only. Read-only is an attribute of the file, shared by its hardlinks.
+    for (i, &(master_readonly, readonly)) in [(true, true), (true, false), (false, true)].iter().enumerate() {
+        let master = dir.path().join(format!("{}master", i)).into_boxed_path();
+        let path = dir.path().join(format!("{}dupe", i)).into_boxed_path();
+        fs::write(&master, "hello world").unwrap();
+        fs::write(&path, "hello world").unwrap();
+        set_readonly(&master, master_readonly);
+        set_readonly(&path, readonly);
+
+        let mut set = FileSet::new(master.clone(), 1);
+        set.push(path.clone());
+        let summary = set.hardlink().unwrap();
+        // It would become writable
+        let kept = readonly && !master_readonly;
+        assert_eq!((!kept as usize, kept as usize), (summary.links, summary.kept_readonly));
+        assert_eq!(!kept, Metadata::from_path(&master).unwrap().ino == Metadata::from_path(&path).unwrap().ino);
+        assert_eq!(master_readonly, fs::metadata(&master).unwrap().permissions().readonly());
+        assert_eq!(master_readonly || kept, fs::metadata(&path).unwrap().permissions().readonly());
+        // So that the directory can be removed on Windows
+        set_readonly(&master, false);
+        set_readonly(&path, false);
+    }
+    assert_eq!(6, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn file_set_bytes_saved() {
+    let dir = TempDir::new("savedtest").unwrap();
+    let path = |name: &str| dir.path().join(name).into_boxed_path();
+    for name in ["master", "pair", "linked_elsewhere"] {
+        fs::write(path(name), "hello world").unwrap();
+    }
+    fs::hard_link(path("master"), path("master_link")).unwrap();
+    fs::hard_link(path("pair"), path("pair_link")).unwrap();
+    fs::hard_link(path("linked_elsewhere"), path("elsewhere")).unwrap();
+
+    let mut set = FileSet::new(path("master"), 2);
+    for name in ["master_link", "pair", "pair_link", "linked_elsewhere"] {
+        set.push(path(name));
+    }
+    // Only the pair is freed, and only once
+    assert_eq!(11, set.bytes_saved(SizeMode::Logical).unwrap());
+    let allocated = set.bytes_saved(SizeMode::Physical).unwrap();
+    assert!(allocated > 0);
+    assert_eq!(11, set.plan(LinkAction::Hardlink).unwrap().bytes_saved);
+
+    let summary = set.hardlink().unwrap();
+    assert_eq!((11, allocated), (summary.bytes_saved, summary.allocated_bytes_saved));
+    assert_eq!(0, set.bytes_saved(SizeMode::Logical).unwrap());
+}
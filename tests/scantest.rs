@@ -1,6 +1,9 @@
 
 use dupe_krill::*;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use tempdir::TempDir;
 
@@ -30,6 +33,27 @@ fn test_exclude() {
     assert_eq!(dupes[0][0].paths.len(), 1);
 }
 
+#[test]
+fn free_hashes() {
+    let dir = TempDir::new("freehashestest").unwrap();
+    let other = TempDir::new("freehashestest2").unwrap();
+    fs::write(dir.path().join("a"), "dupe").unwrap();
+    fs::write(dir.path().join("b"), "dupe").unwrap();
+    fs::write(dir.path().join("c"), "else").unwrap();
+    fs::write(other.path().join("d"), "dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.free_hashes = true;
+    d.scan(dir.path()).unwrap();
+    // Compared again after the hashes are gone
+    d.scan(other.path()).unwrap();
+    let mut lens: Vec<_> = d.dupes().iter().map(|group| group.len()).collect();
+    lens.sort_unstable();
+    assert_eq!(vec![1, 3], lens);
+}
+
 #[test]
 fn scan_hardlink() {
     let dir = TempDir::new("hardlinktest2").unwrap();
@@ -55,3 +79,541 @@ fn scan_hardlink() {
     assert_eq!(dupes.len(), 1);
     assert_eq!(dupes[0][0].paths.len(), 2);
 }
+
+#[test]
+fn scan_with_hash_cache() {
+    let dir = TempDir::new("cachetest").unwrap();
+    let cache_path = dir.path().join("cache");
+    let files = dir.path().join("files");
+    fs::create_dir(&files).unwrap();
+    fs::write(files.join("a"), b"dupe").unwrap();
+    fs::write(files.join("b"), b"dupe").unwrap();
+
+    for _ in 0..2 {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.run_mode = RunMode::DryRun;
+        d.set_hash_cache(&cache_path);
+        d.scan(&files).unwrap();
+        let dupes = d.dupes();
+        assert_eq!(dupes.len(), 1);
+        assert_eq!(dupes[0][0].paths.len(), 2);
+        assert!(cache_path.exists());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn keep_permissions() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let dir = TempDir::new("permissionstest").unwrap();
+    let a_path = dir.path().join("a");
+    let b_path = dir.path().join("b");
+    fs::write(&a_path, b"dupe").unwrap();
+    fs::write(&b_path, b"dupe").unwrap();
+    fs::set_permissions(&b_path, fs::Permissions::from_mode(0o600)).unwrap();
+
+    // Still found as duplicates, but not linked
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.ignore_permissions = false;
+    d.scan(dir.path()).unwrap();
+    let dupes = d.dupes();
+    assert_eq!(dupes.len(), 1);
+    assert_eq!(dupes[0][0].paths.len(), 2);
+    assert_ne!(fs::metadata(&a_path).unwrap().ino(), fs::metadata(&b_path).unwrap().ino());
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(fs::metadata(&a_path).unwrap().ino(), fs::metadata(&b_path).unwrap().ino());
+}
+
+#[test]
+fn readonly_stays_readonly() {
+    let dir = TempDir::new("readonlyscantest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::write(root.join("a"), b"dupe").unwrap();
+    fs::write(root.join("b"), b"dupe").unwrap();
+    let set_readonly = |readonly: bool| {
+        let mut permissions = fs::metadata(root.join("b")).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(readonly);
+        fs::set_permissions(root.join("b"), permissions).unwrap();
+    };
+    set_readonly(true);
+
+    // Permissions are ignored, but linking would make it writable
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.master_policy = MasterPolicy::PathPriority(vec![root.join("a")]);
+    d.scan(&root).unwrap();
+    assert_eq!(1, d.dupes().len());
+    assert_eq!(1, Metadata::from_path(root.join("b")).unwrap().nlink);
+    assert!(fs::metadata(root.join("b")).unwrap().permissions().readonly());
+    set_readonly(false);
+}
+
+#[cfg(windows)]
+#[test]
+fn junctions_are_not_followed() {
+    let dir = TempDir::new("junctiontest").unwrap();
+    let data = dir.path().join("data");
+    fs::create_dir(&data).unwrap();
+    fs::write(data.join("a"), b"dupe").unwrap();
+    let status = std::process::Command::new("cmd").arg("/C").arg("mklink").arg("/J").arg(dir.path().join("link")).arg(&data).status().unwrap();
+    assert!(status.success());
+
+    for (reparse_points, paths) in [(ReparsePoints::Skip, 1), (ReparsePoints::Follow, 2)] {
+        let mut d = Scanner::new();
+        d.settings.ignore_small = false;
+        d.settings.run_mode = RunMode::DryRunNoMerging;
+        d.settings.reparse_points = reparse_points;
+        d.scan(dir.path()).unwrap();
+        let dupes = d.dupes();
+        assert_eq!(dupes.len(), 1);
+        assert_eq!(dupes[0].iter().map(|set| set.paths.len()).sum::<usize>(), paths);
+    }
+}
+
+#[cfg(windows)]
+#[test]
+fn junction_loops_end() {
+    let dir = TempDir::new("junctionlooptest").unwrap();
+    let data = dir.path().join("data");
+    fs::create_dir(&data).unwrap();
+    fs::write(data.join("a"), b"dupe").unwrap();
+    let status = std::process::Command::new("cmd").arg("/C").arg("mklink").arg("/J").arg(data.join("loop")).arg(dir.path()).status().unwrap();
+    assert!(status.success());
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.settings.reparse_points = ReparsePoints::Follow;
+    d.scan(dir.path()).unwrap();
+    // Once directly, and once through the junction, which isn't followed again from there
+    let dupes = d.dupes();
+    assert_eq!(dupes[0].iter().map(|set| set.paths.len()).sum::<usize>(), 2);
+}
+
+#[test]
+fn same_path_twice() {
+    let dir = TempDir::new("samepathtest").unwrap();
+    fs::write(dir.path().join("a"), b"dupe").unwrap();
+    fs::write(dir.path().join("b"), b"dupe").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.enqueue(dir.path()).unwrap();
+    d.enqueue(dir.path().join("a")).unwrap();
+    // Differently cased paths are the same file only on case-insensitive systems
+    #[cfg(any(windows, target_os = "macos"))]
+    d.enqueue(dir.path().join("A")).unwrap();
+    d.flush().unwrap();
+    let dupes = d.dupes();
+    assert_eq!(dupes.len(), 1);
+    assert_eq!(dupes[0].len(), 2);
+    assert!(dupes[0].iter().all(|set| set.paths.len() == 1));
+}
+
+/// What the scanner has told a `RecordingListener`
+#[derive(Debug, Default)]
+struct Recorded {
+    empty_files: Vec<PathBuf>,
+    storage_differences: Vec<PathBuf>,
+    /// As of the end of the scan
+    stats: Stats,
+}
+
+/// Records what it's told, for the test to check after the scan
+#[derive(Debug, Default)]
+struct RecordingListener {
+    recorded: Arc<Mutex<Recorded>>,
+    /// Called with every path that is scanned
+    on_file_scanned: Option<fn(&Path)>,
+}
+
+impl ScanListener for RecordingListener {
+    fn file_scanned(&mut self, path: &Path, _: &Stats) {
+        if let Some(on_file_scanned) = self.on_file_scanned {
+            on_file_scanned(path);
+        }
+    }
+
+    fn scan_over(&self, _: &Scanner, stats: &Stats, _: Duration) {
+        self.recorded.lock().unwrap().stats = *stats;
+    }
+
+    fn hardlinked(&mut self, _: &Path, _: &Path) {}
+
+    fn duplicate_found(&mut self, _: &Path, _: &Path) {}
+
+    fn empty_file_found(&mut self, path: &Path) {
+        self.recorded.lock().unwrap().empty_files.push(path.into());
+    }
+
+    fn storage_differs(&mut self, src: &Path, _: &Path) {
+        self.recorded.lock().unwrap().storage_differences.push(src.into());
+    }
+}
+
+#[test]
+fn storage_differences() {
+    let dir = TempDir::new("storagetest").unwrap();
+    let size = 1 << 20;
+    fs::write(dir.path().join("dense"), vec![0u8; size]).unwrap();
+    fs::File::create(dir.path().join("sparse")).unwrap().set_len(size as u64).unwrap();
+    if !Metadata::from_path(dir.path().join("sparse")).unwrap().sparse {
+        // The filesystem doesn't support holes
+        return;
+    }
+
+    let listener = RecordingListener::default();
+    let recorded = Arc::clone(&listener.recorded);
+    let mut d = Scanner::new();
+    d.settings.run_mode = RunMode::DryRun;
+    d.settings.report_storage_differences = true;
+    d.set_listener(Box::new(listener));
+    d.scan(dir.path()).unwrap();
+    assert_eq!(1, recorded.lock().unwrap().storage_differences.len());
+}
+
+#[test]
+fn empty_files() {
+    let dir = TempDir::new("emptytest").unwrap();
+    let paths: Vec<_> = (0..12).map(|i| dir.path().join(i.to_string())).collect();
+    for path in &paths {
+        fs::write(path, b"").unwrap();
+    }
+    let inodes = || paths.iter().map(|p| Metadata::from_path(p).unwrap().ino).collect::<std::collections::HashSet<_>>();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert!(d.dupes().is_empty());
+    assert_eq!(12, inodes().len());
+
+    let listener = RecordingListener::default();
+    let recorded = Arc::clone(&listener.recorded);
+    let mut d = Scanner::new();
+    d.settings.empty_files = EmptyFiles::Report;
+    d.set_listener(Box::new(listener));
+    d.scan(dir.path()).unwrap();
+    assert_eq!(12, recorded.lock().unwrap().empty_files.len());
+    assert_eq!(12, inodes().len());
+
+    let mut d = Scanner::new();
+    d.settings.empty_files = EmptyFiles::Link;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(1, inodes().len());
+}
+
+#[test]
+fn allocated_size_saved() {
+    let dir = TempDir::new("allocatedtest").unwrap();
+    let size = 1 << 20;
+    for name in ["a", "b"] {
+        fs::File::create(dir.path().join(name)).unwrap().set_len(size as u64).unwrap();
+    }
+    // Unless the filesystem doesn't support holes
+    let sparse = Metadata::from_path(dir.path().join("a")).unwrap().sparse;
+    let allocated = allocated_size(&dir.path().join("b"), &fs::metadata(dir.path().join("b")).unwrap()) as usize;
+
+    let listener = RecordingListener::default();
+    let recorded = Arc::clone(&listener.recorded);
+    let mut d = Scanner::new();
+    d.settings.run_mode = RunMode::DryRun;
+    d.set_listener(Box::new(listener));
+    d.scan(dir.path()).unwrap();
+    // Grouped by length, but holes take no space
+    let stats = recorded.lock().unwrap().stats;
+    assert_eq!(1, stats.dupes);
+    assert_eq!(size, stats.bytes_deduplicated);
+    assert_eq!(allocated, stats.allocated_bytes_deduplicated);
+    assert_eq!(sparse, allocated < size);
+}
+
+/// Deletes the other files of the directory as soon as the first one is found.
+/// The files deleted aren't found, so it runs only once.
+fn delete_others(path: &Path) {
+    if !path.is_file() {
+        return;
+    }
+    for entry in fs::read_dir(path.parent().unwrap()).unwrap() {
+        let other = entry.unwrap().path();
+        if other != path {
+            fs::remove_file(other).unwrap();
+        }
+    }
+}
+
+#[test]
+fn deleted_during_scan() {
+    let dir = TempDir::new("deletedtest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(root.join(name), "hello").unwrap();
+    }
+
+    let log = Arc::new(ErrorLog::new());
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.hashing = Arc::new(HashSettings { error_log: Some(Arc::clone(&log)), ..HashSettings::default() });
+    d.set_listener(Box::new(RecordingListener { on_file_scanned: Some(delete_others), ..RecordingListener::default() }));
+    d.scan(&root).unwrap();
+
+    // The directory is listed in one go, so the deleted files are still seen
+    let errors = log.errors();
+    assert_eq!(2, errors.len());
+    for err in errors {
+        assert!(err.metadata);
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind);
+    }
+    assert_eq!((1, 2), (d.stats().added, d.stats().skipped));
+    // Only the file that was found first is left
+    let dupes = d.dupes();
+    assert_eq!(1, dupes.len());
+    assert_eq!(1, dupes[0].len());
+}
+
+#[test]
+fn key_fails_to_be_read() {
+    let dir = TempDir::new("failedkeytest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::write(root.join("a"), "aaaa").unwrap();
+    fs::write(root.join("b"), "bbbb").unwrap();
+    fs::write(root.join("c"), "bbbb").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(root.join("a")).unwrap();
+    // It's read only when it's compared with the next file
+    fs::remove_file(root.join("a")).unwrap();
+    d.scan(root.join("b")).unwrap();
+    d.scan(root.join("c")).unwrap();
+
+    let dupes = d.dupes();
+    assert_eq!(1, dupes.len());
+    assert!(dupes[0].iter().all(|set| set.paths.iter().all(|path| !path.ends_with("a"))));
+    assert_eq!(2, Metadata::from_path(root.join("c")).unwrap().nlink);
+}
+
+#[test]
+fn plan() {
+    let dir = TempDir::new("plantest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::write(root.join("a"), "dupe").unwrap();
+    fs::write(root.join("b"), "dupe").unwrap();
+    fs::write(root.join("c"), "unique").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.scan(&root).unwrap();
+    let plan = d.plan(LinkAction::Hardlink, &MasterPolicy::PathPriority(vec![root.join("b")]), LinkScope::Global).unwrap();
+    assert_eq!(1, plan.len());
+    assert_eq!((LinkAction::Hardlink, root.join("b").as_path()), (plan[0].action, &*plan[0].master));
+    assert_eq!(&[root.join("a").into_boxed_path()][..], &plan[0].paths[..]);
+    assert_eq!((4, 0), (plan[0].bytes_saved, plan[0].already_linked));
+    assert_eq!(1, Metadata::from_path(root.join("a")).unwrap().nlink);
+
+    let summary = plan[0].execute().unwrap();
+    assert_eq!((1, 4), (summary.links, summary.bytes_saved));
+    assert_eq!(2, Metadata::from_path(root.join("a")).unwrap().nlink);
+}
+
+#[test]
+fn plan_links_to_most_links() {
+    let dir = TempDir::new("planlinkstest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    fs::write(root.join("a"), "dupe").unwrap();
+    fs::write(root.join("b"), "dupe").unwrap();
+    fs::hard_link(root.join("b"), root.join("c")).unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRunNoMerging;
+    d.scan(&root).unwrap();
+    let plan = d.plan(LinkAction::Hardlink, &MasterPolicy::First, LinkScope::Global).unwrap();
+    assert_eq!(1, plan.len());
+    assert_eq!(Some("b"), plan[0].master.file_name().and_then(|name| name.to_str()));
+    assert_eq!((&[root.join("a").into_boxed_path()][..], 1), (&plan[0].paths[..], plan[0].already_linked));
+}
+
+#[test]
+fn master_policy() {
+    let dir = TempDir::new("masterpolicytest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(root.join(name), "dupe").unwrap();
+    }
+    let ino = |name: &str| Metadata::from_path(root.join(name)).unwrap().ino;
+    let master = ino("b");
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.master_policy = MasterPolicy::PathPriority(vec![root.join("b")]);
+    d.scan(&root).unwrap();
+    for name in ["a", "b", "c"] {
+        assert_eq!(master, ino(name));
+    }
+    assert_eq!(1, d.dupes()[0].iter().filter(|set| !set.paths.is_empty()).count());
+}
+
+#[test]
+fn undo_journal() {
+    let dir = TempDir::new("undotest").unwrap();
+    let journal = dir.path().join("journal");
+    let root = dir.path().join("files");
+    fs::create_dir(&root).unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(root.join(name), "same").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.set_journal(&journal).unwrap();
+    d.scan(&root).unwrap();
+    assert_eq!(3, Metadata::from_path(root.join("a")).unwrap().nlink);
+    // Files are linked as they're found
+    let entries = Journal::read(&journal).unwrap();
+    assert_eq!(2, entries.iter().map(|entry| entry.paths.len()).sum::<usize>());
+
+    // No longer a link, so it's left alone
+    let changed = entries.last().unwrap().paths[0].clone();
+    fs::remove_file(&changed).unwrap();
+    fs::write(&changed, "changed").unwrap();
+
+    let summary = undo(&journal).unwrap();
+    assert_eq!(1, summary.copied);
+    assert_eq!(&[changed.clone().into_boxed_path()][..], &summary.skipped[..]);
+    for name in ["a", "b", "c"] {
+        assert_eq!(1, Metadata::from_path(root.join(name)).unwrap().nlink);
+    }
+    assert_eq!(b"changed"[..], fs::read(&changed).unwrap()[..]);
+    assert_eq!(0, undo(&journal).unwrap().copied);
+}
+
+#[test]
+fn undo_hardlink_groups() {
+    let dir = TempDir::new("undogrouptest").unwrap();
+    let journal = dir.path().join("journal");
+    let path = |name: &str| dir.path().join(name);
+    fs::write(path("a"), "same").unwrap();
+    fs::write(path("b"), "same").unwrap();
+    fs::hard_link(path("b"), path("c")).unwrap();
+    let mut set = FileSet::new(path("a").into(), 1);
+    set.push(path("b").into());
+    set.push(path("c").into());
+    Journal::open(&journal).unwrap().record(&path("a"), &[path("b"), path("c")]).unwrap();
+    set.hardlink().unwrap();
+    assert_eq!(3, Metadata::from_path(path("a")).unwrap().nlink);
+
+    // They were links of each other, but not of the master
+    assert_eq!(2, undo(&journal).unwrap().copied);
+    let m = |name: &str| Metadata::from_path(path(name)).unwrap();
+    assert_eq!(1, m("a").nlink);
+    assert!(m("b").same_file(&m("c")));
+    assert_eq!(2, m("b").nlink);
+}
+
+#[test]
+#[cfg(unix)]
+fn undo_symlinks() {
+    let dir = TempDir::new("undosymlinktest").unwrap();
+    let journal = dir.path().join("journal");
+    let path = |name: &str| dir.path().join(name);
+    fs::write(path("a"), "same").unwrap();
+    fs::write(path("other"), "same").unwrap();
+    std::os::unix::fs::symlink(path("a"), path("b")).unwrap();
+    std::os::unix::fs::symlink(path("other"), path("c")).unwrap();
+    Journal::open(&journal).unwrap().record(&path("a"), &[path("b"), path("c")]).unwrap();
+
+    let summary = undo(&journal).unwrap();
+    assert_eq!(1, summary.copied);
+    assert_eq!(&[path("c").into_boxed_path()][..], &summary.skipped[..]);
+    // The link is replaced, and its target is left as it was
+    assert!(!fs::symlink_metadata(path("b")).unwrap().file_type().is_symlink());
+    assert_eq!(b"same"[..], fs::read(path("b")).unwrap()[..]);
+    assert!(!Metadata::from_path(path("a")).unwrap().same_file(&Metadata::from_path(path("b")).unwrap()));
+    assert!(fs::symlink_metadata(path("c")).unwrap().file_type().is_symlink());
+}
+
+#[test]
+fn min_size() {
+    let dir = TempDir::new("minsizetest").unwrap();
+    for name in ["a", "b"] {
+        fs::write(dir.path().join(name), "tiny").unwrap();
+    }
+    for name in ["c", "d"] {
+        fs::write(dir.path().join(name), "large enough").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.min_size = 5;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(1, Metadata::from_path(dir.path().join("a")).unwrap().nlink);
+    assert_eq!(2, Metadata::from_path(dir.path().join("c")).unwrap().nlink);
+    assert_eq!(1, d.dupes().len());
+}
+
+#[test]
+fn plan_per_directory() {
+    let dir = TempDir::new("dirplantest").unwrap();
+    let root = fs::canonicalize(dir.path()).unwrap();
+    for name in ["x", "y", "z"] {
+        fs::create_dir(root.join(name)).unwrap();
+    }
+    for path in ["x/a", "x/b", "y/a", "y/b", "y/c", "z/a"] {
+        fs::write(root.join(path), "dupe").unwrap();
+    }
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.scan(&root).unwrap();
+    let mut plans = d.plan(LinkAction::Hardlink, &MasterPolicy::PathPriority(vec![root.join("y/c")]), LinkScope::PerDirectory).unwrap();
+    // No plan for the only copy in z
+    assert_eq!(2, plans.len());
+    plans.sort_by(|a, b| a.master.cmp(&b.master));
+    assert_eq!(root.join("y/c"), &*plans[1].master);
+    for plan in &plans {
+        assert_eq!(plan.master.parent(), plan.paths[0].parent());
+        plan.execute().unwrap();
+    }
+    let ino = |path: &str| Metadata::from_path(root.join(path)).unwrap().ino;
+    assert_eq!(ino("x/a"), ino("x/b"));
+    assert_eq!(ino("y/a"), ino("y/c"));
+    assert_eq!(ino("y/b"), ino("y/c"));
+    assert_ne!(ino("x/a"), ino("y/a"));
+    assert_eq!(1, Metadata::from_path(root.join("z/a")).unwrap().nlink);
+}
+
+#[test]
+fn scanner_bytes_saved() {
+    let dir = TempDir::new("scansavedtest").unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(dir.path().join(name), "dupe").unwrap();
+    }
+    fs::write(dir.path().join("d"), "unique").unwrap();
+
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.settings.run_mode = RunMode::DryRun;
+    d.scan(dir.path()).unwrap();
+    let (groups, total) = d.bytes_saved(SizeMode::Logical).unwrap();
+    assert_eq!(1, groups.len());
+    assert_eq!((3, 8), (groups[0].0.paths.len(), groups[0].1));
+    assert_eq!(8, total);
+    assert_eq!(0, d.stats().bytes_freed);
+
+    // A real run frees what the dry run said it would
+    let mut d = Scanner::new();
+    d.settings.ignore_small = false;
+    d.scan(dir.path()).unwrap();
+    assert_eq!(8, d.stats().bytes_freed);
+    assert_eq!(0, d.bytes_saved(SizeMode::Logical).unwrap().1);
+}